@@ -0,0 +1,48 @@
+//! Compares the latency of appends in each write mode, run with
+//! `cargo bench --bench write_modes`.
+
+use api::ledger_api_server::LedgerApi;
+use api::{AppendRequest, CreateLedgerRequest};
+use service::WriteMode;
+use std::path::PathBuf;
+use std::time::Instant;
+use tonic::Request;
+
+const APPENDS: u32 = 2000;
+const ENTRY_SIZE: usize = 100;
+
+async fn bench(mode: WriteMode) -> Result<(), tonic::Status> {
+    let path = PathBuf::from("target/bench_ledgers").join(format!("{:?}", mode));
+    let service = service::new(path, 1 << 30).with_write_mode(mode);
+    let request = Request::new(CreateLedgerRequest::default());
+    let ledger_id = service.create(request).await?.into_inner().ledger_id;
+
+    let start = Instant::now();
+    for _ in 0..APPENDS {
+        let request = AppendRequest {
+            ledger_id: ledger_id.clone(),
+            entries: vec![vec![0; ENTRY_SIZE]],
+            ..AppendRequest::default()
+        };
+        service.append(Request::new(request)).await?;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:?}: {} appends of {} bytes in {:?}, {:?} per append",
+        mode,
+        APPENDS,
+        ENTRY_SIZE,
+        elapsed,
+        elapsed / APPENDS
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    for mode in &[WriteMode::Buffered, WriteMode::Sync, WriteMode::Direct] {
+        if let Err(status) = bench(*mode).await {
+            println!("{:?}: failed with {}", mode, status.message());
+        }
+    }
+}
@@ -1,6 +1,9 @@
 pub mod store;
 mod types;
-use api::{ledger_api_client::LedgerApiClient, CreateLedgerRequest};
+use api::ledger_api_client::LedgerApiClient;
+use api::{AppendRequest, CreateLedgerRequest, RegisterProducerRequest, TransactionRequest};
+use std::collections::HashMap;
+use std::ops::Range;
 use store::*;
 use tonic::transport::channel::Channel;
 use types::*;
@@ -27,6 +30,76 @@ impl Ledger {
     }
 }
 
+const APPEND_ATTEMPTS: usize = 3;
+
+/// Appends batches to ledgers with increasing sequence numbers per ledger, so
+/// that the server drops the duplicates written by retries. Between `begin` and
+/// `commit` or `abort` the batches belong to a transaction across the ledgers.
+pub struct Producer {
+    pub id: u64,
+    sequences: HashMap<String, u64>,
+    in_transaction: bool,
+    client: LedgerApiClient<Channel>,
+}
+
+impl Producer {
+    pub async fn append(
+        &mut self,
+        ledger: &Ledger,
+        segment_id: u64,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        let sequence = self.sequences.get(&ledger.id).cloned().unwrap_or(0);
+        let mut attempts = 0;
+        let response = loop {
+            let request = tonic::Request::new(AppendRequest {
+                ledger_id: ledger.id.clone(),
+                segment_id,
+                entries: entries.clone(),
+                producer_id: self.id,
+                sequence,
+                transactional: self.in_transaction,
+            });
+            attempts += 1;
+            match self.client.append(request).await {
+                Ok(response) => break response.into_inner(),
+                Err(status)
+                    if status.code() == tonic::Code::Unavailable && attempts < APPEND_ATTEMPTS => {}
+                Err(status) => return Err(status.into()),
+            }
+        };
+        self.sequences.insert(ledger.id.clone(), sequence + 1);
+        Ok(response.base_offset..response.base_offset + response.count as u64)
+    }
+
+    pub async fn begin(&mut self) -> Result<()> {
+        let request = tonic::Request::new(TransactionRequest {
+            producer_id: self.id,
+        });
+        self.client.begin_transaction(request).await?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    pub async fn commit(&mut self) -> Result<()> {
+        let request = tonic::Request::new(TransactionRequest {
+            producer_id: self.id,
+        });
+        self.client.commit_transaction(request).await?;
+        self.in_transaction = false;
+        Ok(())
+    }
+
+    pub async fn abort(&mut self) -> Result<()> {
+        let request = tonic::Request::new(TransactionRequest {
+            producer_id: self.id,
+        });
+        self.client.abort_transaction(request).await?;
+        self.in_transaction = false;
+        Ok(())
+    }
+}
+
 impl Penman {
     pub async fn new(etcd: Vec<String>) -> Result<Penman> {
         let mut nodes = vec![];
@@ -42,11 +115,25 @@ impl Penman {
 
     pub async fn create_ledger(&mut self) -> Result<Ledger> {
         let node = self.nodes.get_mut(0).unwrap();
-        let request = tonic::Request::new(CreateLedgerRequest {});
+        let request = tonic::Request::new(CreateLedgerRequest::default());
         let response = node.client.create(request).await?;
         let ledger_id = response.into_inner().ledger_id;
         Ledger::new(ledger_id, &node.endpoint, &self.store).await
     }
+
+    pub async fn register_producer(&mut self, ledger: &Ledger) -> Result<Producer> {
+        let node = self.nodes.get_mut(0).unwrap();
+        let request = tonic::Request::new(RegisterProducerRequest {
+            ledger_id: ledger.id.clone(),
+        });
+        let response = node.client.register_producer(request).await?;
+        Ok(Producer {
+            id: response.into_inner().producer_id,
+            sequences: HashMap::new(),
+            in_transaction: false,
+            client: node.client.clone(),
+        })
+    }
 }
 
 pub async fn new(etcd: Vec<String>) -> Result<Penman> {
@@ -18,3 +18,39 @@ async fn create_new_ledger() {
         .contains("http://127.0.0.1:"));
     tx.send(()).unwrap();
 }
+
+#[tokio::test]
+async fn append_from_producer() {
+    let tx = utils::start_server().await;
+    let etcd = vec!["http://localhost:2379".to_owned()];
+    let mut penman = penman::new(etcd).await.unwrap();
+    let ledger = penman.create_ledger().await.unwrap();
+
+    let mut producer = penman.register_producer(&ledger).await.unwrap();
+
+    assert_ne!(producer.id, 0);
+    let first = producer.append(&ledger, 0, vec![vec![1, 2], vec![3]]).await;
+    let second = producer.append(&ledger, 0, vec![vec![4]]).await;
+
+    assert_eq!(first.unwrap(), 0..2);
+    assert_eq!(second.unwrap(), 2..3);
+    tx.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn commit_transaction() {
+    let tx = utils::start_server().await;
+    let etcd = vec!["http://localhost:2379".to_owned()];
+    let mut penman = penman::new(etcd).await.unwrap();
+    let source = penman.create_ledger().await.unwrap();
+    let sink = penman.create_ledger().await.unwrap();
+    let mut producer = penman.register_producer(&source).await.unwrap();
+
+    producer.begin().await.unwrap();
+    producer.append(&source, 0, vec![vec![1]]).await.unwrap();
+    producer.append(&sink, 0, vec![vec![2]]).await.unwrap();
+
+    producer.commit().await.unwrap();
+    assert!(producer.commit().await.is_err());
+    tx.send(()).unwrap();
+}
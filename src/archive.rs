@@ -0,0 +1,189 @@
+#![cfg_attr(not(test), allow(dead_code))] // only the tool exports and imports archives
+
+use crate::types::{Error, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Read, Write};
+use std::ops::Range;
+
+const MAGIC: &[u8; 8] = b"LEDGERS\x01";
+const RECORD_HEADER_SIZE: usize = 8 + 4 + 4; // offset + entry size + crc
+const END: u64 = u64::MAX;
+
+/// What an archive holds: the ledger it was exported from and the offsets asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub ledger_id: String,
+    pub offsets: Range<u64>,
+}
+
+impl Manifest {
+    fn encode(&self) -> String {
+        format!(
+            "ledger: {}\nfirst-offset: {}\nnext-offset: {}\n",
+            self.ledger_id, self.offsets.start, self.offsets.end
+        )
+    }
+
+    fn decode(text: &str) -> Result<Manifest> {
+        let field = |name: &str| {
+            text.lines()
+                .filter_map(|line| line.split_once(": "))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_owned())
+                .ok_or_else(|| corrupt(format!("manifest has no {}", name)))
+        };
+        let offset = |name: &str| {
+            field(name)?
+                .parse::<u64>()
+                .map_err(|_| corrupt(format!("{} is not an offset", name)))
+        };
+        Ok(Manifest {
+            ledger_id: field("ledger")?,
+            offsets: offset("first-offset")?..offset("next-offset")?,
+        })
+    }
+}
+
+fn corrupt(reason: String) -> Error {
+    Error::CorruptArchive(reason)
+}
+
+/// Writes the entries of a ledger to a file that does not depend on how segments
+/// are stored, laid out as
+/// magic | manifest size (u32) | manifest | records | end (u64::MAX) | record count (u64)
+///
+/// The manifest is made of `key: value` lines. Records are
+/// offset (u64) | entry size (u32) | crc (u32) | entry, in offset order.
+pub struct ArchiveWriter<W: Write> {
+    out: W,
+    records: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut out: W, manifest: &Manifest) -> Result<ArchiveWriter<W>> {
+        let manifest = manifest.encode();
+        out.write_all(MAGIC)?;
+        out.write_all(&(manifest.len() as u32).to_be_bytes())?;
+        out.write_all(manifest.as_bytes())?;
+        Ok(ArchiveWriter { out, records: 0 })
+    }
+
+    pub fn add(&mut self, offset: u64, entry: &[u8]) -> Result<()> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        BigEndian::write_u64(&mut header, offset);
+        BigEndian::write_u32(&mut header[8..], entry.len() as u32);
+        BigEndian::write_u32(&mut header[12..], crc::crc32::checksum_ieee(entry));
+        self.out.write_all(&header)?;
+        self.out.write_all(entry)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Marks the end of the archive, so a cut off copy is told apart from a whole one.
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(&END.to_be_bytes())?;
+        self.out.write_all(&self.records.to_be_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Reads back the records of an archive, checking every one of them.
+pub struct ArchiveReader<R: Read> {
+    input: R,
+    pub manifest: Manifest,
+    records: u64,
+    last_offset: Option<u64>,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn open(mut input: R) -> Result<ArchiveReader<R>> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(corrupt("not a ledgers archive".to_owned()));
+        }
+        let mut size = [0; 4];
+        input.read_exact(&mut size)?;
+        let mut manifest = vec![0; BigEndian::read_u32(&size) as usize];
+        input.read_exact(&mut manifest)?;
+        let manifest = Manifest::decode(&String::from_utf8_lossy(&manifest))?;
+        Ok(ArchiveReader {
+            input,
+            manifest,
+            records: 0,
+            last_offset: None,
+        })
+    }
+
+    /// The next record, or none once the end of the archive is reached.
+    pub fn next(&mut self) -> Result<Option<(u64, Vec<u8>)>> {
+        let mut offset = [0; 8];
+        self.read(&mut offset)?;
+        let offset = BigEndian::read_u64(&offset);
+        if offset == END {
+            let mut count = [0; 8];
+            self.read(&mut count)?;
+            if BigEndian::read_u64(&count) != self.records {
+                return Err(corrupt("archive is missing records".to_owned()));
+            }
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE - 8];
+        self.read(&mut header)?;
+        let mut entry = vec![0; BigEndian::read_u32(&header) as usize];
+        self.read(&mut entry)?;
+        if crc::crc32::checksum_ieee(&entry) != BigEndian::read_u32(&header[4..]) {
+            return Err(corrupt(format!("record at offset {} is corrupt", offset)));
+        }
+        let in_order = self.last_offset.is_none_or(|last| offset > last);
+        if !in_order || !self.manifest.offsets.contains(&offset) {
+            return Err(corrupt(format!(
+                "record at offset {} is out of order",
+                offset
+            )));
+        }
+        self.records += 1;
+        self.last_offset = Some(offset);
+        Ok(Some((offset, entry)))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.input.read_exact(buf).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => corrupt("archive is cut off".to_owned()),
+            _ => e.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read_archive() {
+        let manifest = Manifest {
+            ledger_id: "ledger".to_owned(),
+            offsets: 3..9,
+        };
+        let mut archive = ArchiveWriter::new(Vec::new(), &manifest).unwrap();
+        archive.add(3, &[1, 2]).unwrap();
+        archive.add(5, &[]).unwrap();
+        let bytes = archive.finish().unwrap();
+
+        let mut reader = ArchiveReader::open(&bytes[..]).unwrap();
+        assert_eq!(reader.manifest, manifest);
+        assert_eq!(reader.next().unwrap(), Some((3, vec![1, 2])));
+        assert_eq!(reader.next().unwrap(), Some((5, vec![])));
+        assert_eq!(reader.next().unwrap(), None);
+        let mut reader = ArchiveReader::open(&bytes[..bytes.len() - 1]).unwrap();
+        reader.next().unwrap();
+        reader.next().unwrap();
+        assert!(reader.next().unwrap_err().is_corrupt_archive());
+        let mut bytes = bytes;
+        let first_entry = bytes.len() - 16 - 16 - 2; // before the second record and the end
+        bytes[first_entry] ^= 1;
+        let mut reader = ArchiveReader::open(&bytes[..]).unwrap();
+        assert!(reader.next().unwrap_err().is_corrupt_archive());
+    }
+}
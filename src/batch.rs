@@ -0,0 +1,258 @@
+use crate::producer::Producer;
+use crate::types::{Error, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+pub const HEADER_SIZE: usize = 8 + 4; // base offset + batch size
+const BATCH_OVERHEAD: usize = 4 + 1 + 4; // crc + attributes + count
+const PRODUCER_SIZE: usize = 8 + 8; // producer id + sequence
+
+const CODEC_MASK: u8 = 0b0111;
+const ENCRYPTED: u8 = 0b1000;
+const HAS_PRODUCER: u8 = 0b1_0000;
+pub const TRANSACTIONAL: u8 = 0b10_0000;
+pub const CONTROL: u8 = 0b100_0000;
+
+/// A batch of entries with consecutive offsets, stored as a single record:
+/// base offset (u64) | batch size (u32) | crc (u32) | attributes (u8) | count (u32) | [producer] | payload
+///
+/// The crc covers everything after itself. The producer id (u64) and sequence (u64)
+/// are only there if the producer bit is set. Batches written in a transaction
+/// have the transactional bit set, and its markers are control batches. The payload holds the packed entries,
+/// compressed with the codec in the low bits of the attributes and then
+/// encrypted if the encrypted bit is set, bound to the base offset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    pub base_offset: u64,
+    pub attributes: u8,
+    pub count: u32,
+    pub producer: Option<Producer>,
+    pub payload: Vec<u8>,
+}
+
+impl Batch {
+    pub fn last_offset(&self) -> u64 {
+        self.base_offset + self.count as u64 - 1
+    }
+
+    pub fn codec_id(&self) -> u8 {
+        self.attributes & CODEC_MASK
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.attributes & ENCRYPTED != 0
+    }
+
+    pub fn is_transactional(&self) -> bool {
+        self.attributes & TRANSACTIONAL != 0
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.attributes & CONTROL != 0
+    }
+
+    pub fn set_encrypted(&mut self, encrypted: bool) {
+        if encrypted {
+            self.attributes |= ENCRYPTED;
+        } else {
+            self.attributes &= !ENCRYPTED;
+        }
+    }
+
+    /// Fails for a batch whose size no longer fits the u32 it is encoded in, which
+    /// compression or encryption can push a batch within the limits to.
+    pub fn check_size(&self) -> Result<()> {
+        if self.body_size() > u32::MAX as usize {
+            return Err(Error::BatchTooLarge(self.size(), u32::MAX));
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.body_size()
+    }
+
+    fn body_size(&self) -> usize {
+        let producer = self.producer.map_or(0, |_| PRODUCER_SIZE);
+        BATCH_OVERHEAD + producer + self.payload.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend(self.base_offset.to_be_bytes().iter());
+        bytes.extend((self.body_size() as u32).to_be_bytes().iter());
+        bytes.extend([0; 4].iter());
+        match self.producer {
+            Some(producer) => {
+                bytes.push(self.attributes | HAS_PRODUCER);
+                bytes.extend(self.count.to_be_bytes().iter());
+                bytes.extend(producer.id.to_be_bytes().iter());
+                bytes.extend(producer.sequence.to_be_bytes().iter());
+            }
+            None => {
+                bytes.push(self.attributes);
+                bytes.extend(self.count.to_be_bytes().iter());
+            }
+        }
+        bytes.extend(self.payload.iter());
+        let crc = crc::crc32::checksum_ieee(&bytes[HEADER_SIZE + 4..]);
+        BigEndian::write_u32(&mut bytes[HEADER_SIZE..], crc);
+        bytes
+    }
+
+    /// Decodes the whole batches in `buf`, ignoring a trailing partial one.
+    pub fn decode(buf: &[u8]) -> Result<Vec<Batch>> {
+        let mut batches = Vec::new();
+        let mut cursor = 0;
+        while cursor + HEADER_SIZE <= buf.len() {
+            let base_offset = BigEndian::read_u64(&buf[cursor..]);
+            let batch_size = BigEndian::read_u32(&buf[cursor + 8..]) as usize;
+            let start = cursor + HEADER_SIZE;
+            if start + batch_size > buf.len() {
+                break;
+            }
+            let body = &buf[start..start + batch_size];
+            if batch_size < BATCH_OVERHEAD
+                || crc::crc32::checksum_ieee(&body[4..]) != BigEndian::read_u32(body)
+            {
+                return Err(Error::CorruptBatch(base_offset));
+            }
+            let attributes = body[4];
+            let (producer, payload) = if attributes & HAS_PRODUCER == 0 {
+                (None, &body[BATCH_OVERHEAD..])
+            } else if batch_size >= BATCH_OVERHEAD + PRODUCER_SIZE {
+                let producer = Producer {
+                    id: BigEndian::read_u64(&body[BATCH_OVERHEAD..]),
+                    sequence: BigEndian::read_u64(&body[BATCH_OVERHEAD + 8..]),
+                };
+                (Some(producer), &body[BATCH_OVERHEAD + PRODUCER_SIZE..])
+            } else {
+                return Err(Error::CorruptBatch(base_offset));
+            };
+            batches.push(Batch {
+                base_offset,
+                attributes: attributes & !HAS_PRODUCER,
+                count: BigEndian::read_u32(&body[5..]),
+                producer,
+                payload: payload.to_vec(),
+            });
+            cursor = start + batch_size;
+        }
+        Ok(batches)
+    }
+}
+
+pub fn attributes(codec_id: u8) -> u8 {
+    codec_id & CODEC_MASK
+}
+
+/// Packs entries as entry size (u32) | entry, one after the other.
+pub fn pack(entries: &[Vec<u8>]) -> Vec<u8> {
+    let size = entries.iter().map(|e| 4 + e.len()).sum();
+    let mut packed = Vec::with_capacity(size);
+    for entry in entries {
+        packed.extend((entry.len() as u32).to_be_bytes().iter());
+        packed.extend(entry.iter());
+    }
+    packed
+}
+
+pub fn unpack(base_offset: u64, count: u32, packed: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut cursor = 0;
+    for _ in 0..count {
+        let start = cursor + 4;
+        let end = packed
+            .get(cursor..start)
+            .map(|size| start + BigEndian::read_u32(size) as usize)
+            .filter(|end| *end <= packed.len())
+            .ok_or(Error::CorruptBatch(base_offset))?;
+        entries.push(packed[start..end].to_vec());
+        cursor = end;
+    }
+    Ok(entries)
+}
+
+/// An entry as sent to readers: offset (u64) | entry size (u32) | entry
+pub fn entry_record(offset: u64, entry: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + entry.len());
+    bytes.extend(offset.to_be_bytes().iter());
+    bytes.extend((entry.len() as u32).to_be_bytes().iter());
+    bytes.extend(entry.iter());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch() -> Batch {
+        Batch {
+            base_offset: 10,
+            attributes: attributes(1),
+            count: 2,
+            producer: None,
+            payload: pack(&[vec![1, 2], vec![3]]),
+        }
+    }
+
+    #[test]
+    fn encode_batch() {
+        let bytes = batch().encode();
+
+        assert_eq!(bytes.len(), batch().size());
+        assert_eq!(&bytes[..12], &[0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 20]);
+        assert_eq!(&bytes[16..21], &[1, 0, 0, 0, 2]);
+        assert_eq!(&bytes[21..], &[0, 0, 0, 2, 1, 2, 0, 0, 0, 1, 3]);
+    }
+
+    #[test]
+    fn decode_batches() {
+        let mut bytes = batch().encode();
+        bytes.extend(batch().encode());
+        bytes.extend(&batch().encode()[..10]);
+
+        let batches = Batch::decode(&bytes).unwrap();
+
+        assert_eq!(batches, vec![batch(), batch()]);
+        assert_eq!(batches[0].last_offset(), 11);
+        assert_eq!(
+            unpack(10, 2, &batches[0].payload).unwrap(),
+            vec![vec![1, 2], vec![3]]
+        );
+    }
+
+    #[test]
+    fn detect_corrupt_batch() {
+        let mut bytes = batch().encode();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        assert!(Batch::decode(&bytes).err().unwrap().is_corrupt_batch());
+    }
+
+    #[test]
+    fn encode_producer() {
+        let producer = Producer { id: 3, sequence: 9 };
+        let produced = Batch {
+            producer: Some(producer),
+            ..batch()
+        };
+
+        let bytes = produced.encode();
+
+        assert_eq!(bytes.len(), batch().size() + 16);
+        assert_eq!(bytes[16], 0b1_0001);
+        assert_eq!(Batch::decode(&bytes).unwrap(), vec![produced]);
+    }
+
+    #[test]
+    fn encrypted_attribute() {
+        let mut batch = batch();
+
+        batch.set_encrypted(true);
+        assert!(batch.is_encrypted());
+        assert_eq!(batch.codec_id(), 1);
+        batch.set_encrypted(false);
+        assert_eq!(batch.attributes, 1);
+    }
+}
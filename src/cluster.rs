@@ -0,0 +1,26 @@
+use crate::settings::ClusterSettings;
+use crate::types::{Error, Result};
+use etcd_rs::{Client, ClientConfig, PutRequest};
+
+const NODES: &str = "/ledgers/nodes/";
+
+/// Registers the server with etcd under `/ledgers/nodes/<node id>`, where
+/// clients look for the servers of the cluster. Does nothing without endpoints.
+pub async fn register(settings: &ClusterSettings) -> Result<()> {
+    let advertise = match &settings.advertise {
+        Some(advertise) if !settings.etcd_endpoints.is_empty() => advertise,
+        _ => return Ok(()),
+    };
+    let node_id = settings.node_id.as_ref().unwrap_or(advertise);
+    let config = ClientConfig {
+        endpoints: settings.etcd_endpoints.clone(),
+        auth: None,
+    };
+    let unavailable =
+        |e: Box<dyn std::error::Error + Send + Sync>| Error::ClusterUnavailable(e.to_string());
+    let client = Client::connect(config).await.map_err(unavailable)?;
+    let request = PutRequest::new(format!("{}{}", NODES, node_id), advertise.as_str());
+    client.kv().put(request).await.map_err(unavailable)?;
+    println!("registered as node {} at {}", node_id, advertise);
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use crate::compression::Codec;
+use crate::encryption::Cipher;
+use crate::files;
+use crate::files::*;
+use crate::handle::*;
+use crate::ledger::LedgerRepository;
+use crate::producer::Producer;
+use crate::segment::*;
+use crate::transaction::Marker;
+use crate::types::Result;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+const STAGING: &str = "compaction";
+
+/// Keyed entries are encoded as key length (u32) | key | value, an entry with
+/// an empty value is a tombstone for its key.
+pub fn keyed_entry(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(4 + key.len() + value.len());
+    entry.extend((key.len() as u32).to_be_bytes().iter());
+    entry.extend(key.iter());
+    entry.extend(value.iter());
+    entry
+}
+
+pub fn entry_key(entry: &[u8]) -> Option<&[u8]> {
+    if entry.len() < 4 {
+        return None;
+    }
+    let key_size = BigEndian::read_u32(entry) as usize;
+    entry.get(4..4 + key_size)
+}
+
+fn is_tombstone(entry: &[u8]) -> bool {
+    match entry_key(entry) {
+        Some(key) => entry.len() == 4 + key.len(),
+        None => false,
+    }
+}
+
+/// Compacts every sealed segment, i.e. all but the latest one, down to the
+/// latest entry of each key. Tombstones are kept in the newest sealed segment
+/// so that readers catching up still see the delete, and dropped from older ones.
+/// Quarantined segments are left out.
+pub async fn compact(segments: &mut Segments) -> Result<()> {
+    let ids: Vec<u64> = segments
+        .ids()
+        .into_iter()
+        .filter(|id| segments.get(*id).unwrap().quarantined().is_none())
+        .collect();
+    let mut latest: HashMap<Vec<u8>, u64> = HashMap::new();
+    for id in &ids {
+        for (offset, entry) in segments.entries(*id).await? {
+            if let Some(key) = entry_key(&entry) {
+                latest.insert(key.to_vec(), offset);
+            }
+        }
+    }
+
+    let sealed = &ids[..ids.len().saturating_sub(1)];
+    for (i, id) in sealed.iter().enumerate() {
+        let drop_tombstones = i + 1 < sealed.len();
+        let retain = |offset: u64, entry: &[u8]| match entry_key(entry) {
+            Some(key) => latest[key] == offset && !(drop_tombstones && is_tombstone(entry)),
+            None => true,
+        };
+        let transactions = segments.transactions(*id).await?;
+        let segment = segments.get_mut(*id).unwrap();
+        if !segment.compact(retain, &transactions).await? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `entries` into a fresh log and index next to the segment and swaps
+/// them in, keeping the original offsets. Runs of consecutive offsets are
+/// appended together so that compressed ledgers keep their batches, split where
+/// the producer changes so that producer batches keep their sequence. A `marker`,
+/// given as offset, producer id and marker, is written after them at the offset it
+/// ends the segment at. The new
+/// files are synced and marked complete before the swap, so that `recover`
+/// can tell a swap to finish from a rewrite to drop after a crash.
+pub async fn rewrite(
+    location: &Path,
+    id: u64,
+    base_offset: u64,
+    codec: Codec,
+    cipher: Option<Cipher>,
+    entries: Vec<(Option<Producer>, u64, Vec<u8>)>,
+    marker: Option<(u64, u64, Marker)>,
+) -> Result<()> {
+    let staging = location.join(STAGING);
+    if staging.exists() {
+        files::remove_dir(&staging)?;
+    }
+    let mode = WriteMode::Buffered;
+    let mut handle = Handle::new(&staging, id, base_offset, codec, cipher, mode).await?;
+    let mut run: Vec<Vec<u8>> = Vec::new();
+    let (mut run_offset, mut run_producer) = (0, None);
+    for (producer, offset, entry) in entries {
+        if offset != run_offset + run.len() as u64 || producer != run_producer {
+            handle.append(run_offset, run_producer, run).await?;
+            run = Vec::new();
+            run_offset = offset;
+            run_producer = producer;
+        }
+        run.push(entry);
+    }
+    handle.append(run_offset, run_producer, run).await?;
+    if let Some((offset, producer_id, marker)) = marker {
+        handle.add_marker_at(offset, producer_id, marker).await?;
+    }
+    handle.sync().await?;
+    drop(handle);
+    files::write_atomic(&staging.join(id.to_string()).with_extension("swap"), &[])?;
+    swap(location, id)
+}
+
+/// Moves the staged log and index of segment `id` over the ones in use, skipping
+/// the ones already moved, and drops the staging directory.
+fn swap(location: &Path, id: u64) -> Result<()> {
+    let staging = location.join(STAGING);
+    for extension in &["log", "index"] {
+        let name = id.to_string();
+        let staged = staging.join(&name).with_extension(extension);
+        if staged.exists() {
+            files::rename(&staged, &location.join(&name).with_extension(extension))?;
+        }
+    }
+    files::sync_dir(location)?;
+    files::remove_dir(&staging)?;
+    Ok(())
+}
+
+/// Finishes a rewrite that a crash cut short in the ledger directory `location`:
+/// a marked rewrite is swapped in, an unmarked one was incomplete and is dropped.
+pub fn recover(location: &Path) -> Result<()> {
+    let staging = location.join(STAGING);
+    if !staging.exists() {
+        return Ok(());
+    }
+    match files::list_files_as_u64(&staging, "swap")?.first() {
+        Some(id) => swap(location, *id),
+        None => Ok(files::remove_dir(&staging)?),
+    }
+}
+
+/// Compacts the compacted ledgers one at a time, so that only the ledger being
+/// compacted waits for it. A ledger that fails is tried again on the next pass.
+pub async fn run(repository: LedgerRepository) {
+    let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        for id in repository.compacted_ledgers().await {
+            if let Err(e) = repository.compact(&id).await {
+                println!("compaction of ledger {} failed: {}", id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util as test;
+    use crate::transaction::Marker;
+
+    #[test]
+    fn keyed_entries() {
+        let entry = keyed_entry(b"k", &[1, 2]);
+
+        assert_eq!(entry, vec![0, 0, 0, 1, b'k', 1, 2]);
+        assert_eq!(entry_key(&entry), Some(&b"k"[..]));
+        assert!(!is_tombstone(&entry));
+        assert!(is_tombstone(&keyed_entry(b"k", &[])));
+        assert_eq!(entry_key(&[0, 0, 0, 9, 1]), None);
+    }
+
+    #[tokio::test]
+    async fn keep_latest_entry_per_key() {
+        let mut segments =
+            Segments::open(test::create_a_test_directory(), Codec::None, None).unwrap();
+        let segment = segments.create(0).await.unwrap();
+        segment
+            .add(vec![
+                keyed_entry(b"a", &[1]),
+                keyed_entry(b"b", &[2]),
+                keyed_entry(b"a", &[3]),
+                keyed_entry(b"c", &[4]),
+            ])
+            .await
+            .unwrap();
+        let segment = segments.create(4).await.unwrap();
+        segment.add(vec![keyed_entry(b"c", &[5])]).await.unwrap();
+
+        compact(&mut segments).await.unwrap();
+
+        let entries = segments.entries(0).await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (1, keyed_entry(b"b", &[2])),
+                (2, keyed_entry(b"a", &[3])),
+                (3, keyed_entry(b"c", &[4])),
+            ]
+        );
+        let mut buf = Vec::new();
+        let segment = segments.get_mut(0).unwrap();
+        segment.stream(0, 8 + 4 + 6, &mut buf).await.unwrap();
+        assert_eq!(&buf[..8], &[0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn compact_compressed_segments() {
+        let location = test::create_a_test_directory();
+        let mut segments = Segments::open(location, Codec::Snappy, None).unwrap();
+        let segment = segments.create(0).await.unwrap();
+        let entries = (0..6).map(|i| keyed_entry(&[i % 3], &[i])).collect();
+        segment.add(entries).await.unwrap();
+        segments.create(6).await.unwrap();
+
+        compact(&mut segments).await.unwrap();
+
+        let entries = segments.entries(0).await.unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.0).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+        let mut buf = Vec::new();
+        let segment = segments.get_mut(0).unwrap();
+        segment.stream_raw(0, 16000, &mut buf).await.unwrap();
+        assert_eq!(&buf[..8], &[0, 0, 0, 0, 0, 0, 0, 3]);
+    }
+
+    #[tokio::test]
+    async fn drop_tombstones_from_older_segments() {
+        let mut segments =
+            Segments::open(test::create_a_test_directory(), Codec::None, None).unwrap();
+        let segment = segments.create(0).await.unwrap();
+        segment
+            .add(vec![keyed_entry(b"a", &[1]), keyed_entry(b"b", &[2])])
+            .await
+            .unwrap();
+        let segment = segments.create(2).await.unwrap();
+        segment
+            .add(vec![keyed_entry(b"a", &[]), keyed_entry(b"b", &[3])])
+            .await
+            .unwrap();
+        segments.create(4).await.unwrap();
+
+        compact(&mut segments).await.unwrap();
+        let entries = segments.entries(2).await.unwrap();
+        assert_eq!(
+            entries,
+            vec![(2, keyed_entry(b"a", &[])), (3, keyed_entry(b"b", &[3]))]
+        );
+
+        let segment = segments.create(6).await.unwrap();
+        segment.add(vec![keyed_entry(b"c", &[4])]).await.unwrap();
+        compact(&mut segments).await.unwrap();
+
+        let entries = segments.entries(2).await.unwrap();
+        assert_eq!(entries, vec![(3, keyed_entry(b"b", &[3]))]);
+    }
+
+    #[tokio::test]
+    async fn compact_settled_transactions() {
+        let mut segments =
+            Segments::open(test::create_a_test_directory(), Codec::None, None).unwrap();
+        let segment = segments.create(0).await.unwrap();
+        let producer = Producer { id: 1, sequence: 0 };
+        segment.add_marker(1, Marker::Begin).await.unwrap();
+        let entries = vec![keyed_entry(b"a", &[1]), keyed_entry(b"a", &[2])];
+        segment.add_transactional(producer, entries).await.unwrap();
+        segment.add_marker(1, Marker::Commit).await.unwrap();
+        segments.create(4).await.unwrap();
+
+        compact(&mut segments).await.unwrap();
+
+        let entries = segments.entries(0).await.unwrap();
+        assert_eq!(entries, vec![(2, keyed_entry(b"a", &[2]))]);
+        let segment = segments.get_mut(0).unwrap();
+        assert_eq!(segment.next_offset().await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn compact_transactions_ended_in_later_segments() {
+        let mut segments =
+            Segments::open(test::create_a_test_directory(), Codec::None, None).unwrap();
+        let segment = segments.create(0).await.unwrap();
+        let producer = Producer { id: 1, sequence: 0 };
+        segment.add_marker(1, Marker::Begin).await.unwrap();
+        let entries = vec![keyed_entry(b"a", &[1]), keyed_entry(b"a", &[2])];
+        segment.add_transactional(producer, entries).await.unwrap();
+        let segment = segments.create(3).await.unwrap();
+        segment.add(vec![keyed_entry(b"b", &[3])]).await.unwrap();
+        segments.create(4).await.unwrap();
+
+        compact(&mut segments).await.unwrap();
+        assert!(segments.entries(0).await.unwrap().is_empty());
+        assert_eq!(segments.entries(3).await.unwrap().len(), 1);
+
+        let segment = segments.get_mut(4).unwrap();
+        segment.add_marker(1, Marker::Commit).await.unwrap();
+        segments.create(5).await.unwrap();
+        compact(&mut segments).await.unwrap();
+        let entries = segments.entries(0).await.unwrap();
+        assert_eq!(entries, vec![(2, keyed_entry(b"a", &[2]))]);
+    }
+
+    #[tokio::test]
+    async fn recover_interrupted_rewrite() {
+        let location = test::create_a_test_directory();
+        let mut segments = Segments::open(location.clone(), Codec::None, None).unwrap();
+        let segment = segments.create(0).await.unwrap();
+        segment.add(vec![keyed_entry(b"a", &[1])]).await.unwrap();
+        drop(segments);
+        let staging = location.join(STAGING);
+        let stage = || async {
+            let mode = WriteMode::Buffered;
+            let mut handle = Handle::new(&staging, 0, 0, Codec::None, None, mode)
+                .await
+                .unwrap();
+            handle.add(vec![keyed_entry(b"a", &[2])]).await.unwrap();
+        };
+
+        stage().await;
+        let mut segments = Segments::open(location.clone(), Codec::None, None).unwrap();
+        assert!(!staging.exists());
+        let entries = segments.entries(0).await.unwrap();
+        assert_eq!(entries, vec![(0, keyed_entry(b"a", &[1]))]);
+        drop(segments);
+
+        stage().await;
+        files::write(&staging.join("0.swap"), &[]).unwrap();
+        files::rename(&staging.join("0.log"), &location.join("0.log")).unwrap();
+        let mut segments = Segments::open(location, Codec::None, None).unwrap();
+        assert!(!staging.exists());
+        let entries = segments.entries(0).await.unwrap();
+        assert_eq!(entries, vec![(0, keyed_entry(b"a", &[2]))]);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::types::Result;
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
+impl Codec {
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+            Codec::Snappy => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Codec> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Lz4),
+            2 => Ok(Codec::Zstd),
+            3 => Ok(Codec::Snappy),
+            _ => Err(invalid_data(format!("unknown codec {}", id))),
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            Codec::Zstd => Ok(zstd::encode_all(bytes, 0)?),
+            Codec::Snappy => Ok(snap::raw::Encoder::new()
+                .compress_vec(bytes)
+                .map_err(io::Error::from)?),
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            Codec::Lz4 => {
+                lz4_flex::decompress_size_prepended(bytes).map_err(|e| invalid_data(e.to_string()))
+            }
+            Codec::Zstd => Ok(zstd::decode_all(bytes)?),
+            Codec::Snappy => Ok(snap::raw::Decoder::new()
+                .decompress_vec(bytes)
+                .map_err(io::Error::from)?),
+        }
+    }
+}
+
+fn invalid_data(message: String) -> crate::types::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_and_decompress_with_every_codec() {
+        let bytes = [vec![1; 100], vec![2, 3]].concat();
+        for codec in &[Codec::None, Codec::Lz4, Codec::Zstd, Codec::Snappy] {
+            let compressed = codec.compress(&bytes).unwrap();
+
+            assert_eq!(Codec::from_id(codec.id()).unwrap(), *codec);
+            assert_eq!(codec.decompress(&compressed).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn compress_repetitive_entries() {
+        let bytes = b"{\"name\":\"ledgers\"}".repeat(50);
+
+        let compressed = Codec::Lz4.compress(&bytes).unwrap();
+
+        assert!(compressed.len() * 5 < bytes.len());
+    }
+
+    #[test]
+    fn reject_unknown_codec() {
+        assert!(Codec::from_id(7).is_err());
+    }
+}
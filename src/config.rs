@@ -0,0 +1,206 @@
+use crate::compression::Codec;
+use crate::files;
+use crate::files::WriteMode;
+use crate::types::{Error, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+pub const DEFAULT_MAX_ENTRY_SIZE: u32 = 1024 * 1024;
+pub const DEFAULT_MAX_BATCH_SIZE: u32 = 4 * 1024 * 1024;
+/// The file in a ledger directory its settings are kept in.
+const CONFIG_FILE: &str = "config";
+
+/// How much of a ledger to keep: its size in `bytes`, or the `age` of its sealed
+/// segments. It is kept with the rest of the config, nothing deletes segments by it yet.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Retention {
+    pub bytes: Option<u64>,
+    pub age: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LedgerConfig {
+    pub segment_size: u64,
+    pub compacted: bool,
+    pub compression: Codec,
+    pub max_entry_size: u32,
+    pub max_batch_size: u32,
+    pub preallocate: bool,
+    pub write_mode: WriteMode,
+    pub retention: Retention,
+    /// The copies of the ledger to keep across the cluster. A single server keeps
+    /// one, the factor is recorded for placing the ledger on other nodes.
+    pub replication_factor: u32,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// The settings of a ledger that can change after it is created. Unset fields
+/// stay as they are, and a label with an empty value is removed.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigUpdate {
+    pub segment_size: Option<u64>,
+    pub max_entry_size: Option<u32>,
+    pub max_batch_size: Option<u32>,
+    pub retention: Option<Retention>,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl LedgerConfig {
+    pub fn new(segment_size: u64) -> LedgerConfig {
+        LedgerConfig {
+            segment_size,
+            compacted: false,
+            compression: Codec::None,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            preallocate: false,
+            write_mode: WriteMode::Buffered,
+            retention: Retention::default(),
+            replication_factor: 1,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// Checks the settings a ledger is created or updated with.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(Error::InvalidConfig(reason));
+        if self.segment_size == 0 {
+            return invalid("segment size must be above 0".to_owned());
+        }
+        if self.max_entry_size > self.max_batch_size {
+            return invalid(format!(
+                "max entry size {} is above the max batch size {}",
+                self.max_entry_size, self.max_batch_size
+            ));
+        }
+        if self.replication_factor == 0 {
+            return invalid("replication factor must be above 0".to_owned());
+        }
+        for (key, value) in &self.labels {
+            let valid_key =
+                !key.is_empty() && !key.contains(|c: char| c == ':' || c.is_whitespace());
+            if !valid_key || value.contains('\n') {
+                return invalid(format!("label {:?}: {:?} is not allowed", key, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `update`, leaving the config as it was if the result is invalid.
+    pub fn update(&mut self, update: ConfigUpdate) -> Result<()> {
+        let mut updated = self.clone();
+        updated.segment_size = update.segment_size.unwrap_or(self.segment_size);
+        updated.max_entry_size = update.max_entry_size.unwrap_or(self.max_entry_size);
+        updated.max_batch_size = update.max_batch_size.unwrap_or(self.max_batch_size);
+        updated.retention = update.retention.unwrap_or_else(|| self.retention.clone());
+        for (key, value) in update.labels {
+            match value.as_str() {
+                "" => updated.labels.remove(&key),
+                _ => updated.labels.insert(key, value),
+            };
+        }
+        updated.validate()?;
+        *self = updated;
+        Ok(())
+    }
+
+    /// Keeps the settings of the ledger stored at `location` next to its segments,
+    /// as `key: value` lines. How segments are written stays a server setting. The
+    /// file is replaced whole, as a ledger cannot be opened without it.
+    pub fn save(&self, location: &Path) -> Result<()> {
+        let mut lines = vec![
+            format!("segment-size: {}", self.segment_size),
+            format!("compacted: {}", self.compacted),
+            format!("compression: {}", self.compression.id()),
+            format!("max-entry-size: {}", self.max_entry_size),
+            format!("max-batch-size: {}", self.max_batch_size),
+            format!("replication-factor: {}", self.replication_factor),
+        ];
+        if let Some(bytes) = self.retention.bytes {
+            lines.push(format!("retention-bytes: {}", bytes));
+        }
+        if let Some(age) = self.retention.age {
+            lines.push(format!("retention-age: {}", age.as_secs()));
+        }
+        for (key, value) in &self.labels {
+            lines.push(format!("label.{}: {}", key, value));
+        }
+        let text = lines.join("\n") + "\n";
+        files::write_atomic(&location.join(CONFIG_FILE), text.as_bytes())?;
+        Ok(())
+    }
+
+    /// The settings kept with the ledger at `location` over `defaults`. Ledgers
+    /// created before settings were kept have none and take the defaults. Values
+    /// out of range or settings that do not hold together make the file corrupt.
+    pub fn load(location: &Path, defaults: LedgerConfig) -> Result<LedgerConfig> {
+        let path = location.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(defaults);
+        }
+        let mut config = LedgerConfig {
+            retention: Retention::default(),
+            ..defaults
+        };
+        let text = String::from_utf8_lossy(&files::read(&path)?).into_owned();
+        for line in text.lines() {
+            let corrupt = || Error::InvalidConfig(format!("bad line {:?} in {:?}", line, path));
+            let (key, value) = line.split_once(": ").ok_or_else(corrupt)?;
+            let number = || value.parse::<u64>().map_err(|_| corrupt());
+            let small = || value.parse::<u32>().map_err(|_| corrupt());
+            match key {
+                "segment-size" => config.segment_size = number()?,
+                "compacted" => {
+                    config.compacted = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(corrupt()),
+                    }
+                }
+                "compression" => {
+                    let id = value.parse().map_err(|_| corrupt())?;
+                    config.compression = Codec::from_id(id)?;
+                }
+                "max-entry-size" => config.max_entry_size = small()?,
+                "max-batch-size" => config.max_batch_size = small()?,
+                "replication-factor" => config.replication_factor = small()?,
+                "retention-bytes" => config.retention.bytes = Some(number()?),
+                "retention-age" => config.retention.age = Some(Duration::from_secs(number()?)),
+                _ => match key.strip_prefix("label.") {
+                    Some(label) => {
+                        config.labels.insert(label.to_owned(), value.to_owned());
+                    }
+                    None => return Err(corrupt()),
+                },
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The bytes to reserve for every new segment, none unless preallocation is on.
+    pub fn preallocation(&self) -> u64 {
+        if self.preallocate {
+            self.segment_size
+        } else {
+            0
+        }
+    }
+
+    /// Checks the entries of an add call against the size limits. The batch size
+    /// counts every entry with its size prefix, as packed before compression.
+    pub fn check_limits(&self, entries: &[Vec<u8>]) -> Result<()> {
+        let mut batch_size = 0usize;
+        for entry in entries {
+            if entry.len() > self.max_entry_size as usize {
+                return Err(Error::EntryTooLarge(entry.len(), self.max_entry_size));
+            }
+            batch_size += 4 + entry.len();
+        }
+        if batch_size > self.max_batch_size as usize {
+            return Err(Error::BatchTooLarge(batch_size, self.max_batch_size));
+        }
+        Ok(())
+    }
+}
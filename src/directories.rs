@@ -0,0 +1,331 @@
+use crate::files;
+use crate::files::*;
+use crate::types::{Error, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_HIGH_WATER_MARK: usize = 95;
+pub const DEFAULT_LOW_WATER_MARK: usize = 90;
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// How long after its last append a ledger still counts as a writer of its directory.
+const ACTIVE_WRITER: Duration = Duration::from_secs(60);
+
+/// How a data directory is picked for a new ledger.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    FreeSpace,
+    FewestWriters,
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(placement: &str) -> std::result::Result<Self, Self::Err> {
+        match placement {
+            "free-space" => Ok(Placement::FreeSpace),
+            "fewest-writers" => Ok(Placement::FewestWriters),
+            _ => Err(format!("unknown placement {}", placement)),
+        }
+    }
+}
+
+struct DataDir {
+    path: PathBuf,
+    available: AtomicBool,
+    full: AtomicBool,
+}
+
+/// The directories ledgers are stored in, usually one per disk. A directory
+/// that fails is left out from then on while the others keep serving. A
+/// directory whose disk fills past the high water mark takes no appends, but
+/// still serves reads, until it drops below the low water mark.
+pub struct DataDirs {
+    dirs: Vec<DataDir>,
+    placement: Placement,
+    locations: RwLock<HashMap<String, usize>>,
+    /// When each ledger was last appended to.
+    writes: Mutex<HashMap<String, Instant>>,
+    high_water_mark: AtomicUsize,
+    low_water_mark: AtomicUsize,
+}
+
+impl DataDirs {
+    pub fn new(paths: Vec<PathBuf>, placement: Placement) -> DataDirs {
+        let dirs = paths
+            .into_iter()
+            .map(|path| DataDir {
+                available: AtomicBool::new(files::create_dir(&path).is_ok()),
+                path,
+                full: AtomicBool::new(false),
+            })
+            .collect();
+        DataDirs {
+            dirs,
+            placement,
+            locations: RwLock::new(HashMap::new()),
+            writes: Mutex::new(HashMap::new()),
+            high_water_mark: AtomicUsize::new(DEFAULT_HIGH_WATER_MARK),
+            low_water_mark: AtomicUsize::new(DEFAULT_LOW_WATER_MARK),
+        }
+    }
+
+    /// Sets the disk usage, in percent, at which appends stop and resume.
+    pub fn set_water_marks(&self, high: usize, low: usize) -> Result<()> {
+        if high > 100 || low >= high {
+            return Err(Error::InvalidConfig(format!(
+                "low water mark ({}) must be below high water mark ({}), which is at most 100",
+                low, high
+            )));
+        }
+        self.high_water_mark.store(high, Ordering::SeqCst);
+        self.low_water_mark.store(low, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// The directory for a new ledger, which counts as a writer of it once it is
+    /// created and added with `add_ledger`.
+    pub fn place(&self) -> Result<PathBuf> {
+        let writers = self.writers();
+        let available = self
+            .dirs
+            .iter()
+            .enumerate()
+            .filter(|(_, dir)| is_available(dir) && !dir.full.load(Ordering::SeqCst));
+        let dir = match self.placement {
+            Placement::FreeSpace => available.max_by_key(|(_, dir)| free_space(&dir.path)),
+            Placement::FewestWriters => available.min_by_key(|(i, _)| writers[*i]),
+        };
+        let (_, dir) = dir.ok_or(Error::NoDataDirectory)?;
+        Ok(dir.path.clone())
+    }
+
+    /// The number of ledgers appended to lately in each directory.
+    fn writers(&self) -> Vec<usize> {
+        let mut writers = vec![0; self.dirs.len()];
+        let mut writes = self.writes.lock().unwrap();
+        writes.retain(|_, written| written.elapsed() < ACTIVE_WRITER);
+        let locations = self.locations.read().unwrap();
+        for i in writes.keys().filter_map(|id| locations.get(id)) {
+            writers[*i] += 1;
+        }
+        writers
+    }
+
+    /// Counts the ledger as a writer of its directory for a while.
+    pub fn written(&self, id: &str) {
+        let mut writes = self.writes.lock().unwrap();
+        writes.insert(id.to_owned(), Instant::now());
+    }
+
+    /// The ids of the ledgers in every available directory, along with where they are.
+    pub fn ledgers(&self) -> Vec<(PathBuf, String)> {
+        let mut ledgers = Vec::new();
+        for dir in self.dirs.iter().filter(|dir| is_available(dir)) {
+            match files::list_dirs(&dir.path) {
+                Ok(ids) => ledgers.extend(ids.into_iter().map(|id| (dir.path.clone(), id))),
+                Err(e) => self.fail(&dir.path, &e.into()),
+            }
+        }
+        ledgers
+    }
+
+    /// The directory holding the ledger, if it is in an available one.
+    pub fn find(&self, id: &str) -> Option<PathBuf> {
+        self.dirs
+            .iter()
+            .filter(|dir| is_available(dir))
+            .map(|dir| dir.path.clone())
+            .find(|path| path.join(id).exists())
+    }
+
+    /// Remembers the directory the ledger is in.
+    pub fn add_ledger(&self, id: &str, path: &Path) {
+        if let Some(i) = self.dirs.iter().position(|dir| dir.path == path) {
+            self.locations.write().unwrap().insert(id.to_owned(), i);
+        }
+    }
+
+    /// Whether every directory is still in use.
+    pub fn all_available(&self) -> bool {
+        self.dirs.iter().all(is_available)
+    }
+
+    /// Fails with `NoDataDirectory` if the directory of the ledger failed.
+    pub fn check_available(&self, id: &str) -> Result<()> {
+        match self.dir(id) {
+            Some(dir) if !is_available(dir) => Err(Error::NoDataDirectory),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `DiskFull` if the directory of the ledger takes no appends.
+    pub fn check_writable(&self, id: &str) -> Result<()> {
+        self.check_available(id)?;
+        match self.dir(id) {
+            Some(dir) if dir.full.load(Ordering::SeqCst) => {
+                Err(Error::DiskFull(dir.path.to_string_lossy().into_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn dir(&self, id: &str) -> Option<&DataDir> {
+        let locations = self.locations.read().unwrap();
+        locations.get(id).map(|i| &self.dirs[*i])
+    }
+
+    /// Stops appends to the directory of the ledger, after its disk ran out of space.
+    pub fn disk_full(&self, id: &str) {
+        if let Some(i) = self.locations.read().unwrap().get(id) {
+            self.dirs[*i].full.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Checks the disk usage of every available directory against the water marks.
+    pub fn refresh(&self) {
+        self.refresh_with(disk_usage)
+    }
+
+    fn refresh_with(&self, disk_usage: impl Fn(&Path) -> Option<usize>) {
+        let high = self.high_water_mark.load(Ordering::SeqCst);
+        let low = self.low_water_mark.load(Ordering::SeqCst);
+        for dir in self.dirs.iter().filter(|dir| is_available(dir)) {
+            let usage = match disk_usage(&dir.path) {
+                Some(usage) => usage,
+                None => continue,
+            };
+            let was_full = dir.full.load(Ordering::SeqCst);
+            let full = if was_full { usage > low } else { usage >= high };
+            if full != was_full {
+                let state = if full { "full" } else { "writable" };
+                println!("data directory {:?} is {} at {}%", dir.path, state, usage);
+                dir.full.store(full, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Takes the directory of the ledger out of use after an I/O error in it.
+    pub fn ledger_failed(&self, id: &str, error: &Error) {
+        if let Some(dir) = self.dir(id) {
+            self.fail(&dir.path, error);
+        }
+    }
+
+    /// Takes the directory out of use after `error`.
+    pub fn fail(&self, path: &Path, error: &Error) {
+        for dir in self.dirs.iter().filter(|dir| dir.path == path) {
+            if dir.available.swap(false, Ordering::SeqCst) {
+                println!("data directory {:?} is unavailable: {}", path, error);
+            }
+        }
+    }
+}
+
+fn is_available(dir: &DataDir) -> bool {
+    dir.available.load(Ordering::SeqCst)
+}
+
+/// Refreshes the disk usage of the directories for as long as they are in use.
+pub async fn watch(dirs: Weak<DataDirs>) {
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match dirs.upgrade() {
+            Some(dirs) => dirs.refresh(),
+            None => return,
+        }
+    }
+}
+
+fn free_space(path: &Path) -> u64 {
+    statvfs(path).map_or(0, |(_, available)| available)
+}
+
+/// The share of the disk in use, in percent.
+fn disk_usage(path: &Path) -> Option<usize> {
+    match statvfs(path)? {
+        (0, _) => None,
+        (total, available) => Some((100 - available * 100 / total) as usize),
+    }
+}
+
+/// The total and available bytes of the file system holding `path`.
+#[cfg(unix)]
+fn statvfs(path: &Path) -> Option<(u64, u64)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        0 => {
+            let block_size = stat.f_frsize as u64;
+            Some((
+                stat.f_blocks as u64 * block_size,
+                stat.f_bavail as u64 * block_size,
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn statvfs(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util as test;
+
+    #[test]
+    fn place_ledgers() {
+        let location = test::create_a_test_directory();
+        let (a, b) = (location.join("a"), location.join("b"));
+        let dirs = DataDirs::new(vec![a.clone(), b.clone()], Placement::FewestWriters);
+
+        assert_eq!(dirs.place().unwrap(), a);
+        assert_eq!(dirs.place().unwrap(), a);
+        files::create_dir(&a.join("ledger")).unwrap();
+        assert_eq!(dirs.ledgers(), vec![(a.clone(), "ledger".to_owned())]);
+        assert_eq!(dirs.find("ledger"), Some(a.clone()));
+        dirs.add_ledger("ledger", &a);
+        assert_eq!(dirs.place().unwrap(), a);
+        dirs.written("ledger");
+        assert_eq!(dirs.place().unwrap(), b);
+        assert!(dirs.check_writable("ledger").is_ok());
+        dirs.ledger_failed("ledger", &Error::NoDataDirectory);
+        assert!(!dirs.all_available());
+        assert!(dirs.check_writable("ledger").is_err());
+        assert_eq!(dirs.place().unwrap(), b);
+        dirs.fail(&b, &Error::NoDataDirectory);
+        assert!(dirs.place().is_err());
+        assert!(free_space(&location) > 0);
+    }
+
+    #[test]
+    fn stop_appends_past_high_water_mark() {
+        let location = test::create_a_test_directory();
+        let dirs = DataDirs::new(vec![location.clone()], Placement::FreeSpace);
+        dirs.add_ledger("ledger", &location);
+        dirs.set_water_marks(90, 80).unwrap();
+
+        dirs.refresh_with(|_| Some(90));
+        let error = dirs.check_writable("ledger").unwrap_err();
+        assert!(error.is_disk_full());
+        assert!(dirs.place().is_err());
+        dirs.refresh_with(|_| Some(85));
+        assert!(dirs.check_writable("ledger").is_err());
+        dirs.refresh_with(|_| Some(80));
+        assert!(dirs.check_writable("ledger").is_ok());
+        dirs.refresh_with(|_| Some(85));
+        assert!(dirs.check_writable("ledger").is_ok());
+        dirs.disk_full("ledger");
+        assert!(dirs.check_writable("ledger").is_err());
+        assert!(dirs.set_water_marks(80, 90).is_err());
+        assert!(dirs.set_water_marks(101, 90).is_err());
+    }
+}
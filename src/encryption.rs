@@ -0,0 +1,218 @@
+use crate::files;
+use crate::files::*;
+use crate::types::{Error, Result};
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use byteorder::{BigEndian, ByteOrder};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Clone)]
+pub struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    fn new(key: &[u8]) -> Result<Cipher> {
+        let cipher = Aes256Gcm::new_varkey(key).map_err(|_| Error::EncryptionFailed)?;
+        Ok(Cipher(cipher))
+    }
+
+    /// Encrypts with a random nonce: nonce (12 bytes) | ciphertext | tag. The
+    /// `aad` is authenticated along, such as the offset the ciphertext belongs at,
+    /// so that it cannot be moved elsewhere without failing to decrypt.
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = self
+            .0
+            .encrypt(&nonce.into(), payload)
+            .map_err(|_| Error::EncryptionFailed)?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn decrypt(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(Error::EncryptionFailed);
+        }
+        let (nonce, msg) = sealed.split_at(NONCE_SIZE);
+        let nonce: [u8; NONCE_SIZE] = nonce.try_into().unwrap();
+        self.0
+            .decrypt(&nonce.into(), Payload { msg, aad })
+            .map_err(|_| Error::EncryptionFailed)
+    }
+}
+
+/// Master keys read from a key file holding one `<id> <hex encoded 256 bit key>`
+/// per line. The last key wraps new data keys, the older ones still unwrap theirs.
+pub struct Keyring {
+    keys: Vec<(u32, Cipher)>,
+}
+
+impl Keyring {
+    pub fn load(path: &Path) -> Result<Keyring> {
+        let contents = String::from_utf8(files::read(path)?).map_err(|_| Error::InvalidKeyFile)?;
+        let mut keys = Vec::new();
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let mut fields = line.split_whitespace();
+            let id = fields.next().and_then(|id| id.parse::<u32>().ok());
+            let key = fields.next().and_then(decode_hex);
+            match (id, key) {
+                (Some(id), Some(key)) if key.len() == KEY_SIZE => {
+                    keys.push((id, Cipher::new(&key)?))
+                }
+                _ => return Err(Error::InvalidKeyFile),
+            }
+        }
+        if keys.is_empty() {
+            return Err(Error::InvalidKeyFile);
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// Wraps a data key with the current master key: master key id (u32) | sealed key
+    fn wrap_key(&self, data_key: &[u8]) -> Result<Vec<u8>> {
+        let (id, master) = self.keys.last().unwrap();
+        let mut wrapped = id.to_be_bytes().to_vec();
+        wrapped.extend(master.encrypt(data_key, &[])?);
+        Ok(wrapped)
+    }
+
+    fn unwrap_key(&self, wrapped: &[u8]) -> Result<Cipher> {
+        if wrapped.len() < 4 {
+            return Err(Error::EncryptionFailed);
+        }
+        let id = BigEndian::read_u32(wrapped);
+        let (_, master) = self
+            .keys
+            .iter()
+            .find(|(key_id, _)| *key_id == id)
+            .ok_or(Error::UnknownMasterKey(id))?;
+        let data_key = master.decrypt(&wrapped[4..], &[])?;
+        Cipher::new(&data_key)
+    }
+}
+
+/// The wrapped data key of a ledger, kept in `ledger.key`. Every segment copies
+/// the data key current at its creation into `<id>.key`, so rotating the ledger
+/// key only applies to new segments. Both are written atomically, as a torn key
+/// file would leave the segments it belongs to unreadable.
+#[derive(Clone)]
+pub struct LedgerKeys {
+    keyring: Arc<Keyring>,
+    data_key: Vec<u8>,
+}
+
+impl LedgerKeys {
+    pub fn create(location: &Path, keyring: Arc<Keyring>) -> Result<LedgerKeys> {
+        let data_key: [u8; KEY_SIZE] = rand::random();
+        let data_key = keyring.wrap_key(&data_key)?;
+        files::write_atomic(&location.join("ledger.key"), &data_key)?;
+        Ok(LedgerKeys { keyring, data_key })
+    }
+
+    pub fn open(location: &Path, keyring: Arc<Keyring>) -> Result<Option<LedgerKeys>> {
+        let path = location.join("ledger.key");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data_key = files::read(&path)?;
+        Ok(Some(LedgerKeys { keyring, data_key }))
+    }
+
+    pub fn rotate(&self, location: &Path) -> Result<LedgerKeys> {
+        LedgerKeys::create(location, self.keyring.clone())
+    }
+
+    /// The cipher of the segment, like `segment_cipher` but without writing the
+    /// key of a segment that has none yet.
+    pub fn segment_cipher_read_only(&self, location: &Path, id: u64) -> Result<Cipher> {
+        let path = location.join(id.to_string()).with_extension("key");
+        if path.exists() {
+            self.keyring.unwrap_key(&files::read(&path)?)
+        } else {
+            self.keyring.unwrap_key(&self.data_key)
+        }
+    }
+
+    pub fn segment_cipher(&self, location: &Path, id: u64) -> Result<Cipher> {
+        let path = location.join(id.to_string()).with_extension("key");
+        if path.exists() {
+            self.keyring.unwrap_key(&files::read(&path)?)
+        } else {
+            files::write_atomic(&path, &self.data_key)?;
+            self.keyring.unwrap_key(&self.data_key)
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util as test;
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let cipher = Cipher::new(&[7; KEY_SIZE]).unwrap();
+
+        let sealed = cipher.encrypt(&[1, 2, 3], &[4]).unwrap();
+
+        assert_eq!(sealed.len(), NONCE_SIZE + 3 + 16);
+        assert_eq!(cipher.decrypt(&sealed, &[4]).unwrap(), vec![1, 2, 3]);
+        assert!(cipher.decrypt(&sealed, &[5]).is_err());
+        let other = Cipher::new(&[8; KEY_SIZE]).unwrap();
+        assert!(other.decrypt(&sealed, &[4]).is_err());
+    }
+
+    #[test]
+    fn load_key_file() {
+        let location = test::create_a_test_directory();
+        let path = location.join("master.keys");
+        files::write(&path, format!("1 {}\n", "ab".repeat(KEY_SIZE)).as_bytes()).unwrap();
+
+        assert!(Keyring::load(&path).is_ok());
+
+        files::write(&path, b"1 abcd\n").unwrap();
+        assert!(Keyring::load(&path).err().unwrap().is_invalid_key_file());
+    }
+
+    #[test]
+    fn unwrap_keys_of_rotated_master_keys() {
+        let location = test::create_a_test_directory();
+        let path = location.join("master.keys");
+        files::write(&path, format!("1 {}\n", "01".repeat(KEY_SIZE)).as_bytes()).unwrap();
+        let keys = LedgerKeys::create(&location, Arc::new(Keyring::load(&path).unwrap())).unwrap();
+        let sealed = keys
+            .segment_cipher(&location, 0)
+            .unwrap()
+            .encrypt(&[1], &[])
+            .unwrap();
+
+        let rotated = format!("1 {}\n2 {}\n", "01".repeat(KEY_SIZE), "02".repeat(KEY_SIZE));
+        files::write(&path, rotated.as_bytes()).unwrap();
+        let keyring = Arc::new(Keyring::load(&path).unwrap());
+        let keys = LedgerKeys::open(&location, keyring).unwrap().unwrap();
+
+        let cipher = keys.segment_cipher(&location, 0).unwrap();
+        assert_eq!(cipher.decrypt(&sealed, &[]).unwrap(), vec![1]);
+    }
+}
@@ -1,35 +1,258 @@
 use std::fs;
 use std::io::Result;
+use std::str::FromStr;
+use std::sync::Arc;
 pub use tokio::prelude::*;
 
 pub type File = tokio::fs::File;
 pub type Path = std::path::Path;
 pub type PathBuf = std::path::PathBuf;
 
-pub fn create(path: &PathBuf) -> Result<File> {
+pub const BLOCK_SIZE: usize = 4096;
+
+/// How segment files are written. Buffered writes land in the page cache, sync
+/// writes are on disk once they return and so are direct writes, which also
+/// bypass the cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WriteMode {
+    #[default]
+    Buffered,
+    Sync,
+    Direct,
+}
+
+impl FromStr for WriteMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode {
+            "buffered" => Ok(WriteMode::Buffered),
+            "sync" => Ok(WriteMode::Sync),
+            "direct" => Ok(WriteMode::Direct),
+            _ => Err(format!("unknown write mode {}", mode)),
+        }
+    }
+}
+
+/// Creates the file for reading and appending. Files written in direct mode are
+/// still read through the page cache, their writes go through a `DirectWriter`.
+pub fn create(path: &PathBuf, mode: WriteMode) -> Result<File> {
     let dir_path = path.parent().unwrap();
     if !dir_path.exists() {
         create_dir(dir_path).unwrap();
     }
-    std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .read(true)
-        .open(path)
-        .map(|f| File::from_std(f))
+    options(mode).create(true).open(path).map(File::from_std)
+}
+
+pub fn open(path: &PathBuf, mode: WriteMode) -> Result<File> {
+    options(mode).open(path).map(File::from_std)
+}
+
+/// Opens the file for reading only, for looking into files without changing them.
+pub fn open_read_only(path: &Path) -> Result<File> {
+    fs::File::open(path).map(File::from_std)
+}
+
+fn options(mode: WriteMode) -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options.append(true).read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if mode == WriteMode::Sync {
+            options.custom_flags(libc::O_DSYNC);
+        }
+    }
+    options
+}
+
+/// The writer for files in direct mode, the other modes write through the file itself.
+pub fn direct_writer(path: &PathBuf, mode: WriteMode) -> Result<Option<DirectWriter>> {
+    match mode {
+        WriteMode::Direct => Ok(Some(DirectWriter::open(path)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Appends to a file opened with `O_DIRECT | O_DSYNC`, which only writes whole
+/// blocks from aligned buffers. The partial block at the end is kept to be written
+/// again in front of the next bytes. The zero padding after them stays on disk
+/// until the file is trimmed, on sync or once the writer is dropped, and is cut
+/// off when the segment is opened if a crash left it. Writes run on the blocking
+/// pool, so they never stall the runtime.
+pub struct DirectWriter {
+    path: PathBuf,
+    file: Arc<fs::File>,
+    len: u64,
+    tail: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectWriter {
+    pub fn open(path: &PathBuf) -> Result<DirectWriter> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_DIRECT | libc::O_DSYNC)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        let mut writer = DirectWriter {
+            path: path.to_owned(),
+            file: Arc::new(file),
+            len: 0,
+            tail: vec![],
+        };
+        writer.rewind(len)?;
+        Ok(writer)
+    }
+
+    pub async fn append(&mut self, bytes: &[u8]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        let start = self.len - self.tail.len() as u64;
+        let size = self.tail.len() + bytes.len();
+        let blocks = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        let mut buf = vec![0; blocks + BLOCK_SIZE];
+        let offset = buf.as_ptr().align_offset(BLOCK_SIZE);
+        let aligned = &mut buf[offset..offset + blocks];
+        aligned[..self.tail.len()].copy_from_slice(&self.tail);
+        aligned[self.tail.len()..size].copy_from_slice(bytes);
+        let tail = aligned[size - size % BLOCK_SIZE..size].to_vec();
+        let file = self.file.clone();
+        let write = move || file.write_all_at(&buf[offset..offset + blocks], start);
+        tokio::task::spawn_blocking(write)
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))??;
+        self.len += bytes.len() as u64;
+        self.tail = tail;
+        Ok(())
+    }
+
+    /// Picks up the end of the file after it was cut back to `len`.
+    pub fn rewind(&mut self, len: u64) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        let start = len - len % BLOCK_SIZE as u64;
+        let mut tail = vec![0; (len - start) as usize];
+        fs::File::open(&self.path)?.read_exact_at(&mut tail, start)?;
+        self.len = len;
+        self.tail = tail;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl DirectWriter {
+    pub fn open(_path: &PathBuf) -> Result<DirectWriter> {
+        Err(std::io::Error::other(
+            "direct writes are only supported on linux",
+        ))
+    }
+
+    pub async fn append(&mut self, _bytes: &[u8]) -> Result<()> {
+        unreachable!("direct writers are only opened on linux")
+    }
+
+    pub fn rewind(&mut self, _len: u64) -> Result<()> {
+        unreachable!("direct writers are only opened on linux")
+    }
 }
 
-pub fn open(path: &PathBuf) -> Result<File> {
-    std::fs::OpenOptions::new()
-        .append(true)
-        .open(path)
-        .map(|f| File::from_std(f))
+impl DirectWriter {
+    /// Cuts the padding of the last block off the file.
+    pub fn trim(&self) -> Result<()> {
+        self.file.set_len(self.len)
+    }
+}
+
+impl Drop for DirectWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.trim() {
+            println!("trimming {} failed: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Reserves `len` bytes of disk for the file without changing its length, so the
+/// end of the file stays where the last write left it.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let result = unsafe { libc::fallocate(fd, libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t) };
+    match result {
+        0 => Ok(()),
+        _ => match std::io::Error::last_os_error() {
+            // not every file system can preallocate, the file just grows as it is written then
+            e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            e => Err(e),
+        },
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &File, _len: u64) -> Result<()> {
+    Ok(())
 }
 
 pub fn create_dir(path: &Path) -> Result<()> {
     fs::create_dir_all(path)
 }
 
+pub fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path)
+}
+
+#[cfg(test)]
+pub fn write(path: &Path, bytes: &[u8]) -> Result<()> {
+    fs::write(path, bytes)
+}
+
+/// Replaces the file at `path` with `bytes` as a whole or not at all, even
+/// across a crash: the bytes go to a temporary file that is synced and renamed over it.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = fs::File::create(&temporary)?;
+    std::io::Write::write_all(&mut file, bytes)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => sync_dir(Path::new(".")),
+    }
+}
+
+/// Syncs the entries of a directory, so that files created, renamed or removed in it stay that way.
+pub fn sync_dir(path: &Path) -> Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// The length of the file at `path`, zero if it does not exist yet.
+pub fn size(path: &Path) -> Result<u64> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn rename(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)
+}
+
+pub fn remove_dir(path: &Path) -> Result<()> {
+    fs::remove_dir_all(path)
+}
+
+pub fn remove_file(path: &Path) -> Result<()> {
+    fs::remove_file(path)
+}
+
+/// When the file at `path` was last written to.
+pub fn modified(path: &Path) -> Result<std::time::SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
 pub fn stem_as_u64(path: PathBuf) -> u64 {
     path.file_stem()
         .map(|name| name.to_str().unwrap())
@@ -39,11 +262,25 @@ pub fn stem_as_u64(path: PathBuf) -> u64 {
         .unwrap()
 }
 
+/// The names of the directories in `path`, leaving out hidden ones.
+pub fn list_dirs(path: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
 pub fn list_files_as_u64(path: &Path, extension: &'static str) -> Result<Vec<u64>> {
     let mut entries: Vec<u64> = fs::read_dir(path)?
         .map(|entry| entry.unwrap().path())
-        .filter(|p| p.extension().unwrap() == extension)
-        .map(|p| stem_as_u64(p))
+        .filter(|p| p.extension().is_some_and(|e| e == extension))
+        .map(stem_as_u64)
         .collect();
     entries.sort();
     Ok(entries)
@@ -61,9 +298,9 @@ mod tests {
         let dir_name = Uuid::new_v4().to_string();
         let path = location.join(dir_name).with_extension("txt");
 
-        files::create(&path).unwrap();
+        files::create(&path, WriteMode::Buffered).unwrap();
 
-        let file = files::open(&path).unwrap();
+        let file = files::open(&path, WriteMode::Buffered).unwrap();
         assert_eq!(file.metadata().await.unwrap().len(), 0);
     }
 
@@ -71,8 +308,9 @@ mod tests {
     async fn list_files_as_u64() {
         let location = Path::new("target/test_files").join(Uuid::new_v4().to_string());
         for i in 9..15 {
-            files::create(&location.join(i.to_string()).with_extension("log")).unwrap();
-            files::create(&location.join(i.to_string()).with_extension("index")).unwrap();
+            let path = location.join(i.to_string());
+            files::create(&path.with_extension("log"), WriteMode::Buffered).unwrap();
+            files::create(&path.with_extension("index"), WriteMode::Buffered).unwrap();
         }
 
         let dirs: Vec<u64> = files::list_files_as_u64(&location, "index").unwrap();
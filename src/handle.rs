@@ -1,59 +1,501 @@
+use crate::batch::{self, Batch};
+use crate::compression::Codec;
+use crate::encryption::Cipher;
 use crate::files;
 use crate::files::*;
 use crate::index::*;
+use crate::journal::SegmentJournal;
 use crate::log::*;
-use crate::types::Result;
+use crate::producer::Producer;
+use crate::tiering::ColdStore;
+use crate::transaction::{Marker, TransactionIndex};
+use crate::types::{Error, Result};
+use std::ops::Range;
 use tokio::io::AsyncWrite;
 
 pub struct Handle {
+    codec: Codec,
+    cipher: Option<Cipher>,
     log: Log,
     index: Index,
+    transactions: Option<TransactionIndex>,
+    journal: Option<SegmentJournal>,
 }
 
 impl Handle {
-    pub async fn new(location: &PathBuf, id: u64) -> Result<Handle> {
-        let log = Log::new(location, id).await?;
-        let index = Index::new(location, id).await?;
-        Ok(Handle { log, index })
+    pub async fn new(
+        location: &PathBuf,
+        id: u64,
+        base_offset: u64,
+        codec: Codec,
+        cipher: Option<Cipher>,
+        mode: WriteMode,
+    ) -> Result<Handle> {
+        let log = Log::new(location, id, mode).await?;
+        let index = Index::new(location, id, base_offset, mode).await?;
+        Ok(Handle {
+            codec,
+            cipher,
+            log,
+            index,
+            transactions: None,
+            journal: None,
+        })
     }
 
-    pub async fn open(location: &PathBuf, id: u64) -> Result<Handle> {
-        let log = Log::open(location, id).await?;
-        let index = Index::open(location, id).await?;
-        Ok(Handle { log, index })
+    pub async fn open(
+        location: &PathBuf,
+        id: u64,
+        base_offset: u64,
+        codec: Codec,
+        cipher: Option<Cipher>,
+        mode: WriteMode,
+    ) -> Result<Handle> {
+        let mut log = Log::open(location, id, mode).await?;
+        let mut index = Index::open(location, id, base_offset, mode).await?;
+        if let Some(position) = index.last_position().await? {
+            if let Some(end) = log.batch_end(position).await? {
+                log.trim_padding(end).await?;
+            }
+        }
+        Ok(Handle {
+            codec,
+            cipher,
+            log,
+            index,
+            transactions: None,
+            journal: None,
+        })
+    }
+
+    /// Opens the files of the segment for reading only, as they are on disk, for
+    /// looking into a data directory without changing it.
+    pub async fn open_read_only(
+        location: &Path,
+        id: u64,
+        base_offset: u64,
+        codec: Codec,
+        cipher: Option<Cipher>,
+    ) -> Result<Handle> {
+        let mut log = Log::open_read_only(location, id).await?;
+        let mut index = Index::open_read_only(location, id, base_offset).await?;
+        if let Some(position) = index.last_position().await? {
+            if let Some(end) = log.batch_end(position).await? {
+                log.skip_padding(end).await?;
+            }
+        }
+        Ok(Handle {
+            codec,
+            cipher,
+            log,
+            index,
+            transactions: None,
+            journal: None,
+        })
+    }
+
+    /// Journals every batch before acknowledging it.
+    pub fn set_journal(&mut self, journal: SegmentJournal) {
+        self.journal = Some(journal);
+    }
+
+    /// Reserves disk space for a log of `bytes` and its index.
+    pub fn preallocate(&mut self, bytes: u64) -> Result<()> {
+        self.log.preallocate(bytes)?;
+        self.index.preallocate(bytes)
+    }
+
+    pub fn exists(location: &Path, id: u64) -> bool {
+        location
+            .join(id.to_string())
+            .with_extension("index")
+            .exists()
+    }
+
+    /// Copies the log and index of a segment to `store`, under `prefix`.
+    pub fn upload(location: &Path, id: u64, store: &dyn ColdStore, prefix: &str) -> Result<()> {
+        for extension in &["log", "index"] {
+            let name = format!("{}.{}", id, extension);
+            store.upload(&format!("{}/{}", prefix, name), &location.join(&name))?;
+        }
+        Ok(())
+    }
+
+    /// Copies the log and index of a segment back from `store`, the index last
+    /// as it tells that the segment is there.
+    pub fn fetch(location: &Path, id: u64, store: &dyn ColdStore, prefix: &str) -> Result<()> {
+        for extension in &["log", "index"] {
+            let name = format!("{}.{}", id, extension);
+            store.download(&format!("{}/{}", prefix, name), &location.join(&name))?;
+        }
+        Ok(())
     }
 
-    pub async fn stream<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<()>
+    /// Removes the log and index of a segment, the index first.
+    pub fn remove(location: &Path, id: u64) -> Result<()> {
+        for extension in &["index", "log"] {
+            let path = location.join(id.to_string()).with_extension(extension);
+            files::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Streams entries from `offset` onwards, decrypting and decompressing them if needed.
+    /// Returns the offset to continue reading from.
+    pub async fn stream<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<u64>
     where
         T: AsyncWrite + Unpin + ?Sized,
     {
         let position = self.index.find_entry(offset).await?;
-        self.log.stream_entries(position, bytes, target).await?;
-        Ok(())
+        let batches = self.log.read_batches(position, bytes).await?;
+        let next = next_offset(&batches, offset);
+        let batches = batches.into_iter().filter(|batch| !batch.is_control());
+        let stopped = self.write_entries(batches, offset, bytes, target).await?;
+        Ok(stopped.unwrap_or(next))
+    }
+
+    /// Streams the committed entries from `offset` onwards. It skips the batches of
+    /// aborted transactions and stops at the first transaction still open, as told
+    /// by `transactions` from this segment on.
+    pub async fn stream_committed<T>(
+        &mut self,
+        offset: u64,
+        bytes: usize,
+        target: &mut T,
+        transactions: &TransactionIndex,
+    ) -> Result<u64>
+    where
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        let position = self.index.find_entry(offset).await?;
+        let batches = self.log.read_batches(position, bytes).await?;
+        let next = next_offset(&batches, offset);
+        let stable_offset = transactions.stable_offset();
+        let batches = committed(batches, transactions);
+        let stopped = self
+            .write_entries(batches.into_iter(), offset, bytes, target)
+            .await?;
+        let next = stopped.unwrap_or(next);
+        Ok(stable_offset.map_or(next, |stable| next.min(stable.max(offset))))
+    }
+
+    /// Writes the entries from `offset` on, a batch at a time while they fit in
+    /// `bytes` once unpacked, but at least the first batch. Returns the offset of
+    /// the first batch left out for the limit.
+    async fn write_entries<I, T>(
+        &self,
+        batches: I,
+        offset: u64,
+        bytes: usize,
+        target: &mut T,
+    ) -> Result<Option<u64>>
+    where
+        I: Iterator<Item = Batch>,
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = Vec::new();
+        let mut stopped = None;
+        for batch in batches {
+            let base_offset = batch.base_offset;
+            let mut records = Vec::new();
+            for (entry_offset, entry) in self.unpack(batch)? {
+                if entry_offset >= offset {
+                    records.extend(batch::entry_record(entry_offset, &entry));
+                }
+            }
+            if !buf.is_empty() && buf.len() + records.len() > bytes {
+                stopped = Some(base_offset.max(offset));
+                break;
+            }
+            buf.extend(records);
+        }
+        target.write_all(&buf).await?;
+        Ok(stopped)
+    }
+
+    /// Streams the whole batches as they are stored, passing compressed batches through.
+    /// Encrypted batches are decrypted, so they never leave the server sealed.
+    pub async fn stream_raw<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<u64>
+    where
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        let position = self.index.find_entry(offset).await?;
+        let batches = self.log.read_batches(position, bytes).await?;
+        let next = next_offset(&batches, offset);
+        if self.cipher.is_none() {
+            let bytes = batches.iter().map(Batch::size).sum();
+            self.log.stream_entries(position, bytes, target).await?;
+            return Ok(next);
+        }
+        let mut buf = Vec::new();
+        for batch in batches {
+            buf.extend(self.decrypt(batch)?.encode());
+        }
+        target.write_all(&buf).await?;
+        Ok(next)
+    }
+
+    pub async fn add(&mut self, entries: Vec<Vec<u8>>) -> Result<Range<u64>> {
+        let offset = self.index.next_offset;
+        self.append(offset, None, entries).await
+    }
+
+    pub async fn add_from_producer(
+        &mut self,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        let offset = self.index.next_offset;
+        self.append(offset, Some(producer), entries).await
+    }
+
+    pub async fn add_transactional(
+        &mut self,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        let offset = self.index.next_offset;
+        let flags = batch::TRANSACTIONAL;
+        self.append_batch(offset, Some(producer), flags, entries)
+            .await
+    }
+
+    pub async fn add_marker(&mut self, producer_id: u64, marker: Marker) -> Result<()> {
+        let offset = self.index.next_offset;
+        self.add_marker_at(offset, producer_id, marker).await
     }
 
-    pub async fn add(&mut self, entries: Vec<Vec<u8>>) -> Result<()> {
-        for entry in entries {
-            let log_position = self.log.position;
-            let offset = self.index.next_offset;
-            self.log.add_entry(offset, entry).await?;
-            self.index.add_entry(log_position).await?;
+    /// Writes a transaction marker at `offset`, which may leave a gap after the
+    /// last offset as compaction does.
+    pub async fn add_marker_at(
+        &mut self,
+        offset: u64,
+        producer_id: u64,
+        marker: Marker,
+    ) -> Result<()> {
+        let producer = Producer {
+            id: producer_id,
+            sequence: 0,
+        };
+        let flags = batch::TRANSACTIONAL | batch::CONTROL;
+        self.append_batch(offset, Some(producer), flags, vec![marker.entry()])
+            .await?;
+        if let Some(transactions) = self.transactions.as_mut() {
+            transactions.add(producer_id, marker, offset);
         }
         Ok(())
     }
 
-    pub async fn log_size(&self) -> u64 {
-        self.log.size().await
+    /// Appends `entries` with consecutive offsets starting at `base_offset` as a
+    /// single batch, indexed by its last offset, and returns their offsets.
+    pub async fn append(
+        &mut self,
+        base_offset: u64,
+        producer: Option<Producer>,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        self.append_batch(base_offset, producer, 0, entries).await
+    }
+
+    async fn append_batch(
+        &mut self,
+        base_offset: u64,
+        producer: Option<Producer>,
+        flags: u8,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        if entries.is_empty() {
+            return Ok(base_offset..base_offset);
+        }
+        let mut batch = Batch {
+            base_offset,
+            attributes: batch::attributes(self.codec.id()) | flags,
+            count: entries.len() as u32,
+            producer,
+            payload: self.codec.compress(&batch::pack(&entries))?,
+        };
+        if let Some(cipher) = &self.cipher {
+            let offset = base_offset.to_be_bytes();
+            batch.payload = cipher.encrypt(&batch.payload, &offset)?;
+            batch.set_encrypted(true);
+        }
+        batch.check_size()?;
+        self.write(&batch).await?;
+        Ok(base_offset..batch.last_offset() + 1)
+    }
+
+    /// Writes a batch, journaling it first, and rolls back what made it to disk if
+    /// it fails. The error of the write is the one returned, a failed rollback is
+    /// only reported.
+    async fn write(&mut self, batch: &Batch) -> Result<()> {
+        let checkpoint = (self.log.position, self.index.entries);
+        let written = self.write_batch(batch, checkpoint.0).await;
+        if written.is_err() {
+            let journal = self
+                .journal
+                .as_ref()
+                .map(|j| j.roll_back(batch.base_offset));
+            if let Err(e) = journal.unwrap_or(Ok(())) {
+                println!(
+                    "journaling the rollback at offset {} failed: {}",
+                    batch.base_offset, e
+                );
+            }
+            if let Err(e) = self.rollback(checkpoint).await {
+                let offset = batch.base_offset;
+                println!("rolling back the batch at offset {} failed: {}", offset, e);
+            }
+        }
+        written
+    }
+
+    async fn write_batch(&mut self, batch: &Batch, log_position: u64) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            journal.append(batch)?;
+        }
+        self.log.add_batch(batch).await?;
+        self.index
+            .add_entry(batch.last_offset(), log_position)
+            .await
+    }
+
+    /// Writes a batch replayed from the journal, unless the segment already has it.
+    pub async fn restore(&mut self, batch: Batch) -> Result<bool> {
+        if batch.base_offset < self.index.next_offset {
+            return Ok(false);
+        }
+        self.write(&batch).await?;
+        Ok(true)
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
+        self.log.sync().await?;
+        self.index.sync().await
+    }
+
+    /// Undoes a partially written batch, so a failed add leaves no trace and can be retried.
+    async fn rollback(&mut self, (log_position, index_entries): (u64, u64)) -> Result<()> {
+        self.log.truncate(log_position).await?;
+        self.index.truncate(index_entries).await
+    }
+
+    /// The producer of every batch written by one with the batch offsets, in log order.
+    pub async fn producers(&mut self) -> Result<Vec<(Producer, Range<u64>)>> {
+        let batches = self.log.batches().await?;
+        let batches = batches.iter().filter(|batch| !batch.is_control());
+        Ok(batches
+            .filter_map(|b| b.producer.map(|p| (p, b.base_offset..b.last_offset() + 1)))
+            .collect())
+    }
+
+    /// The committed entries of the segment, see `stream_committed`.
+    pub async fn entries(
+        &mut self,
+        transactions: &TransactionIndex,
+    ) -> Result<Vec<(u64, Vec<u8>)>> {
+        let entries = self.producer_entries(transactions).await?.into_iter();
+        Ok(entries.map(|(_, offset, entry)| (offset, entry)).collect())
+    }
+
+    /// The committed entries of the segment, each with the producer of its batch.
+    pub async fn producer_entries(
+        &mut self,
+        transactions: &TransactionIndex,
+    ) -> Result<Vec<(Option<Producer>, u64, Vec<u8>)>> {
+        let batches = self.log.batches().await?;
+        let mut entries = Vec::new();
+        for batch in committed(batches, transactions) {
+            let producer = batch.producer;
+            let unpacked = self.unpack(batch)?.into_iter();
+            entries.extend(unpacked.map(|(offset, entry)| (producer, offset, entry)));
+        }
+        Ok(entries)
+    }
+
+    /// The batch holding the last offset of the segment.
+    pub async fn last_batch(&mut self) -> Result<Option<Batch>> {
+        match self.index.last_position().await? {
+            Some(position) => Ok(self.log.read_batches(position, 0).await?.pop()),
+            None => Ok(None),
+        }
+    }
+
+    /// The transaction markers of the segment, read from its log the first time.
+    pub async fn transactions(&mut self) -> Result<&TransactionIndex> {
+        if self.transactions.is_none() {
+            let mut transactions = TransactionIndex::default();
+            for batch in self.log.batches().await? {
+                if let (true, Some(producer)) = (batch.is_control(), batch.producer) {
+                    let offset = batch.base_offset;
+                    let marker = self
+                        .unpack(batch)?
+                        .pop()
+                        .and_then(|(_, e)| Marker::parse(&e));
+                    let marker = marker.ok_or(Error::CorruptBatch(offset))?;
+                    transactions.add(producer.id, marker, offset);
+                }
+            }
+            self.transactions = Some(transactions);
+        }
+        Ok(self.transactions.as_ref().unwrap())
+    }
+
+    fn decrypt(&self, mut batch: Batch) -> Result<Batch> {
+        if batch.is_encrypted() {
+            let cipher = self.cipher.as_ref().ok_or(Error::KeyringMissing)?;
+            let offset = batch.base_offset.to_be_bytes();
+            batch.payload = cipher.decrypt(&batch.payload, &offset)?;
+            batch.set_encrypted(false);
+        }
+        Ok(batch)
+    }
+
+    fn unpack(&self, batch: Batch) -> Result<Vec<(u64, Vec<u8>)>> {
+        let batch = self.decrypt(batch)?;
+        let packed = Codec::from_id(batch.codec_id())?.decompress(&batch.payload)?;
+        let entries = batch::unpack(batch.base_offset, batch.count, &packed)?;
+        Ok((batch.base_offset..).zip(entries).collect())
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.index.next_offset
     }
 
-    pub async fn index_size(&self) -> u64 {
-        self.index.size().await
+    pub fn log_size(&self) -> u64 {
+        self.log.size()
     }
+
+    pub fn index_size(&self) -> u64 {
+        self.index.size()
+    }
+}
+
+fn next_offset(batches: &[Batch], offset: u64) -> u64 {
+    batches
+        .last()
+        .map_or(offset, |batch| batch.last_offset() + 1)
+}
+
+/// The batches of committed transactions and outside of them, up to the first open transaction.
+fn committed(batches: Vec<Batch>, transactions: &TransactionIndex) -> Vec<Batch> {
+    let stable_offset = transactions.stable_offset().unwrap_or(u64::MAX);
+    let aborted = |batch: &Batch| match batch.producer {
+        Some(producer) if batch.is_transactional() => {
+            transactions.is_aborted(producer.id, batch.base_offset)
+        }
+        _ => false,
+    };
+    batches
+        .into_iter()
+        .take_while(|batch| batch.base_offset < stable_offset)
+        .filter(|batch| !batch.is_control() && !aborted(batch))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::files;
     use crate::test_util as test;
 
     #[tokio::test]
@@ -61,7 +503,9 @@ mod tests {
         let id = 123;
         let location = test::create_a_test_directory();
 
-        Handle::new(&location, id).await.unwrap();
+        Handle::new(&location, id, id, Codec::None, None, WriteMode::Buffered)
+            .await
+            .unwrap();
 
         assert!(location
             .join(id.to_string())
@@ -74,20 +518,92 @@ mod tests {
     async fn open_existing_handle() {
         let id = 123;
         let location = test::create_a_test_directory();
-        let mut handle = Handle::new(&location, id).await.unwrap();
+        let mut handle = Handle::new(&location, id, id, Codec::None, None, WriteMode::Buffered)
+            .await
+            .unwrap();
         handle.add(vec![vec![1, 2], vec![5, 6]]).await.unwrap();
 
-        let handle = Handle::open(&location, id).await.unwrap();
+        let handle = Handle::open(&location, id, id, Codec::None, None, WriteMode::Buffered)
+            .await
+            .unwrap();
+
+        assert_eq!(handle.log_size(), 33);
+        assert_eq!(handle.index_size(), 16);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn roll_back_failed_batch() {
+        let id = 123;
+        let location = test::create_a_test_directory();
+        let index = location.join(id.to_string()).with_extension("index");
+        std::os::unix::fs::symlink("/dev/full", &index).unwrap();
+        let mut handle = Handle::new(&location, id, id, Codec::None, None, WriteMode::Buffered)
+            .await
+            .unwrap();
+
+        let error = handle.add(vec![vec![1, 2]]).await.unwrap_err();
+
+        assert!(error.is_disk_full());
+        assert_eq!(handle.log_size(), 0);
+        assert_eq!(files::size(&location.join("123.log")).unwrap(), 0);
+        assert_eq!(handle.next_offset(), id);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn trim_padding_left_by_a_crash() {
+        let id = 123;
+        let location = test::create_a_test_directory();
+        let mut handle = Handle::new(&location, id, id, Codec::None, None, WriteMode::Direct)
+            .await
+            .unwrap();
+        handle.add(vec![vec![1, 2]]).await.unwrap();
+        handle.add(vec![vec![0; 3]]).await.unwrap();
+        let log_size = handle.log_size();
+        std::mem::forget(handle);
+        let log = location.join("123.log");
+        assert_eq!(files::size(&log).unwrap(), BLOCK_SIZE as u64);
+        let mut handle = Handle::open_read_only(&location, id, id, Codec::None, None)
+            .await
+            .unwrap();
+        assert_eq!((handle.log_size(), handle.next_offset()), (log_size, 125));
+        assert_eq!(
+            handle
+                .entries(&TransactionIndex::default())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(files::size(&log).unwrap(), BLOCK_SIZE as u64);
+        drop(handle);
+
+        let mut handle = Handle::open(&location, id, id, Codec::None, None, WriteMode::Direct)
+            .await
+            .unwrap();
 
-        assert_eq!(handle.log_size().await, 28);
-        assert_eq!(handle.index_size().await, 16);
+        assert_eq!(handle.log_size(), log_size);
+        assert_eq!(files::size(&log).unwrap(), log_size);
+        assert_eq!(handle.next_offset(), 125);
+        assert_eq!(handle.add(vec![vec![3]]).await.unwrap(), 125..126);
+        assert_eq!(
+            handle
+                .entries(&TransactionIndex::default())
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
     }
 
     #[tokio::test]
     async fn read_entries_from_active_handle() {
         let id = 123;
         let location = test::create_a_test_directory();
-        let mut handle = Handle::new(&location, id).await.unwrap();
+        let mut handle = Handle::new(&location, id, id, Codec::None, None, WriteMode::Buffered)
+            .await
+            .unwrap();
         handle.add(vec![vec![1, 2], vec![3, 4]]).await.unwrap();
 
         let mut buf: Vec<u8> = Vec::new();
@@ -105,4 +621,129 @@ mod tests {
             ]
         );
     }
+
+    #[tokio::test]
+    async fn compress_batches() {
+        let id = 123;
+        let location = test::create_a_test_directory();
+        let mut handle = Handle::new(&location, id, id, Codec::Zstd, None, WriteMode::Buffered)
+            .await
+            .unwrap();
+        handle.add(vec![vec![7; 100], vec![8; 100]]).await.unwrap();
+        handle.add(vec![vec![9]]).await.unwrap();
+
+        let mut handle = Handle::open(&location, id, id, Codec::Zstd, None, WriteMode::Buffered)
+            .await
+            .unwrap();
+
+        assert!(handle.log_size() < 200);
+        assert_eq!(handle.index_size(), 32);
+        let mut buf: Vec<u8> = Vec::new();
+        handle.stream(124, 16000, &mut buf).await.unwrap();
+        let mut expected = batch::entry_record(124, &[8; 100]);
+        expected.extend(batch::entry_record(125, &[9]));
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn limit_decompressed_entries() {
+        let id = 123;
+        let location = test::create_a_test_directory();
+        let mut handle = Handle::new(&location, id, id, Codec::Zstd, None, WriteMode::Buffered)
+            .await
+            .unwrap();
+        handle.add(vec![vec![7; 100]]).await.unwrap();
+        handle.add(vec![vec![8; 100]]).await.unwrap();
+        handle.add(vec![vec![9; 100]]).await.unwrap();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let next = handle.stream(123, 250, &mut buf).await.unwrap();
+
+        assert_eq!(next, 125);
+        let mut expected = batch::entry_record(123, &[7; 100]);
+        expected.extend(batch::entry_record(124, &[8; 100]));
+        assert_eq!(buf, expected);
+        let mut buf: Vec<u8> = Vec::new();
+        assert_eq!(handle.stream(125, 1, &mut buf).await.unwrap(), 126);
+        assert_eq!(buf, batch::entry_record(125, &[9; 100]));
+    }
+
+    #[tokio::test]
+    async fn pass_compressed_batches_through() {
+        let id = 123;
+        let location = test::create_a_test_directory();
+        let mut handle = Handle::new(&location, id, id, Codec::Lz4, None, WriteMode::Buffered)
+            .await
+            .unwrap();
+        handle.add(vec![vec![1, 2], vec![3, 4]]).await.unwrap();
+
+        let mut buf: Vec<u8> = Vec::new();
+        handle.stream_raw(124, 16000, &mut buf).await.unwrap();
+
+        let batch = Batch::decode(&buf).unwrap().pop().unwrap();
+        assert_eq!(batch.base_offset, 123);
+        assert_eq!(batch.codec_id(), Codec::Lz4.id());
+        assert_eq!(
+            Codec::Lz4.decompress(&batch.payload).unwrap(),
+            batch::pack(&[vec![1, 2], vec![3, 4]])
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypt_records() {
+        let id = 123;
+        let location = test::create_a_test_directory();
+        let cipher = test::cipher(&location);
+        let mut handle = Handle::new(
+            &location,
+            id,
+            id,
+            Codec::None,
+            Some(cipher.clone()),
+            WriteMode::Buffered,
+        )
+        .await
+        .unwrap();
+        handle.add(vec![b"secret".to_vec()]).await.unwrap();
+
+        let mut handle = Handle::open(
+            &location,
+            id,
+            id,
+            Codec::None,
+            Some(cipher),
+            WriteMode::Buffered,
+        )
+        .await
+        .unwrap();
+
+        let log = files::read(&location.join("123.log")).unwrap();
+        assert!(!log.windows(6).any(|w| w == b"secret"));
+        let mut buf: Vec<u8> = Vec::new();
+        handle.stream(123, 16000, &mut buf).await.unwrap();
+        assert_eq!(buf, batch::entry_record(123, b"secret"));
+        let mut buf: Vec<u8> = Vec::new();
+        handle.stream_raw(123, 16000, &mut buf).await.unwrap();
+        assert!(!Batch::decode(&buf).unwrap()[0].is_encrypted());
+    }
+
+    #[tokio::test]
+    async fn write_one_batch_per_add() {
+        let id = 0;
+        let location = test::create_a_test_directory();
+        let mut handle = Handle::new(&location, id, id, Codec::None, None, WriteMode::Buffered)
+            .await
+            .unwrap();
+        handle.add(vec![vec![1], vec![2], vec![3]]).await.unwrap();
+        handle.add(vec![vec![4]]).await.unwrap();
+
+        let mut buf: Vec<u8> = Vec::new();
+        handle.stream(1, 16000, &mut buf).await.unwrap();
+
+        assert_eq!(handle.index_size(), 32);
+        let mut expected = batch::entry_record(1, &[2]);
+        expected.extend(batch::entry_record(2, &[3]));
+        expected.extend(batch::entry_record(3, &[4]));
+        assert_eq!(buf, expected);
+    }
 }
@@ -4,63 +4,228 @@ use crate::types::*;
 use byteorder::{BigEndian, ByteOrder};
 use std::io::SeekFrom;
 
-const ENTRY_SIZE: usize = 8;
+pub const ENTRY_SIZE: usize = 16; // last offset of the batch + log position
+const PREALLOCATED_BATCH_SIZE: u64 = 1024; // log bytes per entry when preallocating
 
 pub struct Index {
     pub id: u64,
     pub base_offset: u64,
     pub next_offset: u64,
+    pub entries: u64,
+    preallocated: u64,
     file: File,
+    direct: Option<DirectWriter>,
 }
 
 impl Index {
-    pub async fn new(location: &PathBuf, id: u64) -> Result<Index> {
+    pub async fn new(
+        location: &PathBuf,
+        id: u64,
+        base_offset: u64,
+        mode: WriteMode,
+    ) -> Result<Index> {
         let path = location.join(id.to_string()).with_extension("index");
-        let file = files::create(&path)?;
-        let (base_offset, next_offset) = (id, id);
+        let file = files::create(&path, mode)?;
+        let next_offset = base_offset;
         Ok(Index {
             id,
             base_offset,
             next_offset,
+            entries: 0,
+            preallocated: 0,
             file,
+            direct: direct_writer(&path, mode)?,
         })
     }
 
-    pub async fn open(location: &PathBuf, id: u64) -> Result<Index> {
+    pub async fn open(
+        location: &PathBuf,
+        id: u64,
+        base_offset: u64,
+        mode: WriteMode,
+    ) -> Result<Index> {
         let path = location.join(id.to_string()).with_extension("index");
-        let file = files::open(&path)?;
-        let size = file.metadata().await.unwrap().len();
-        let entries = size / ENTRY_SIZE as u64;
-        let base_offset = id;
-        let next_offset = base_offset + entries;
+        let file = files::open(&path, mode)?;
+        let direct = direct_writer(&path, mode)?;
+        let mut index = Index::from_file(id, base_offset, file, direct).await?;
+        let entries = index.unpadded_entries().await?;
+        if entries < index.entries {
+            index.truncate(entries).await?;
+        }
+        index.read_next_offset().await?;
+        Ok(index)
+    }
+
+    /// Opens the index for reading only, leaving out the padding `open` cuts off.
+    pub async fn open_read_only(location: &Path, id: u64, base_offset: u64) -> Result<Index> {
+        let path = location.join(id.to_string()).with_extension("index");
+        let file = files::open_read_only(&path)?;
+        let mut index = Index::from_file(id, base_offset, file, None).await?;
+        index.entries = index.unpadded_entries().await?;
+        index.read_next_offset().await?;
+        Ok(index)
+    }
+
+    async fn from_file(
+        id: u64,
+        base_offset: u64,
+        file: File,
+        direct: Option<DirectWriter>,
+    ) -> Result<Index> {
+        let size = file.metadata().await?.len();
         Ok(Index {
             id,
             base_offset,
-            next_offset,
+            next_offset: base_offset,
+            entries: size / ENTRY_SIZE as u64,
+            preallocated: 0,
             file,
+            direct,
         })
     }
 
-    pub async fn add_entry(&mut self, value: u64) -> Result<()> {
+    async fn read_next_offset(&mut self) -> Result<()> {
+        if self.entries > 0 {
+            let (last_offset, _) = self.read_entry(self.entries - 1).await?;
+            self.next_offset = last_offset + 1;
+        }
+        self.file.seek(SeekFrom::End(0)).await?;
+        Ok(())
+    }
+
+    pub async fn add_entry(&mut self, offset: u64, position: u64) -> Result<()> {
         let mut buf: [u8; ENTRY_SIZE] = [0; ENTRY_SIZE];
-        BigEndian::write_u64(&mut buf, value);
-        self.file.write_all(&buf).await?;
-        self.file.flush().await?; //TODO: Flush from segment as a batch
-        self.next_offset += 1;
+        BigEndian::write_u64(&mut buf[..8], offset);
+        BigEndian::write_u64(&mut buf[8..], position);
+        match &mut self.direct {
+            Some(direct) => direct.append(&buf).await?,
+            None => {
+                self.file.write_all(&buf).await?;
+                self.file.flush().await?; //TODO: Flush from segment as a batch
+            }
+        }
+        self.entries += 1;
+        self.next_offset = offset + 1;
+        Ok(())
+    }
+
+    /// The entries before the zero padding of a direct write, which a crash kept
+    /// from being trimmed. Only the first entry can be all zeros, every later one
+    /// points past the start of the log.
+    async fn unpadded_entries(&mut self) -> Result<u64> {
+        let mut entries = self.entries;
+        let padded = BLOCK_SIZE as u64 / ENTRY_SIZE as u64;
+        while entries > 1 && self.entries - entries < padded {
+            if self.read_entry(entries - 1).await? != (0, 0) {
+                break;
+            }
+            entries -= 1;
+        }
+        self.file.seek(SeekFrom::End(0)).await?;
+        Ok(entries)
+    }
+
+    /// The log position of the last batch.
+    pub async fn last_position(&mut self) -> Result<Option<u64>> {
+        if self.entries == 0 {
+            return Ok(None);
+        }
+        let (_, position) = self.read_entry(self.entries - 1).await?;
+        self.file.seek(SeekFrom::End(0)).await?;
+        Ok(Some(position))
+    }
+
+    /// Reserves disk space for the entries of a log of `log_bytes`, assuming
+    /// batches of around a kilobyte.
+    pub fn preallocate(&mut self, log_bytes: u64) -> Result<()> {
+        let bytes = log_bytes / PREALLOCATED_BATCH_SIZE * ENTRY_SIZE as u64;
+        files::preallocate(&self.file, bytes)?;
+        self.preallocated = bytes;
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
+        if let Some(direct) = &self.direct {
+            direct.trim()?;
+        }
+        self.file.sync_all().await?;
         Ok(())
     }
 
+    /// Drops every entry from slot `entries` onwards and rewinds `next_offset` to match.
+    pub async fn truncate(&mut self, entries: u64) -> Result<()> {
+        self.file.set_len(entries * ENTRY_SIZE as u64).await?;
+        if let Some(direct) = &mut self.direct {
+            direct.rewind(entries * ENTRY_SIZE as u64)?;
+        }
+        if self.preallocated > 0 {
+            files::preallocate(&self.file, self.preallocated)?;
+        }
+        self.entries = entries;
+        self.next_offset = match entries {
+            0 => self.base_offset,
+            _ => self.read_entry(entries - 1).await?.0 + 1,
+        };
+        self.file.seek(SeekFrom::End(0)).await?;
+        Ok(())
+    }
+
+    /// Finds the log position of the batch holding `offset`. Entries are keyed by
+    /// the last offset of their batch and compacted segments have gaps, so this
+    /// is the first entry at or after `offset`.
     pub async fn find_entry(&mut self, offset: u64) -> Result<u64> {
-        let index_offset = (offset - self.base_offset) * ENTRY_SIZE as u64;
-        self.file.seek(SeekFrom::Start(index_offset)).await?;
+        if offset < self.base_offset || offset >= self.next_offset {
+            return Err(Error::OffsetOutOfRange {
+                requested: offset,
+                first: self.base_offset,
+                next: self.next_offset,
+            });
+        }
+        let (mut low, mut high) = (0, self.entries);
+        // indexes of single entry batches are dense, so try the slot at the relative offset first
+        let slot = offset - self.base_offset;
+        if slot < self.entries && self.read_entry(slot).await?.0 == offset {
+            low = slot;
+            high = slot;
+        }
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.read_entry(mid).await?.0 < offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let (_, position) = self.read_entry(low).await?;
+        self.file.seek(SeekFrom::End(0)).await?;
+        Ok(position)
+    }
+
+    async fn read_entry(&mut self, slot: u64) -> Result<(u64, u64)> {
+        self.file
+            .seek(SeekFrom::Start(slot * ENTRY_SIZE as u64))
+            .await?;
         let mut buf: [u8; ENTRY_SIZE] = [0; ENTRY_SIZE];
         self.file.read_exact(&mut buf).await?;
+        Ok((
+            BigEndian::read_u64(&buf[..8]),
+            BigEndian::read_u64(&buf[8..]),
+        ))
+    }
+
+    /// Every entry, as the last offset of its batch and the log position of the batch.
+    #[cfg_attr(not(test), allow(dead_code))] // only the tool prints the index
+    pub async fn read_entries(&mut self) -> Result<Vec<(u64, u64)>> {
+        let mut entries = Vec::with_capacity(self.entries as usize);
+        for slot in 0..self.entries {
+            entries.push(self.read_entry(slot).await?);
+        }
         self.file.seek(SeekFrom::End(0)).await?;
-        Ok(BigEndian::read_u64(&buf))
+        Ok(entries)
     }
 
-    pub async fn size(&self) -> u64 {
-        self.file.metadata().await.unwrap().len()
+    pub fn size(&self) -> u64 {
+        self.entries * ENTRY_SIZE as u64
     }
 }
 
@@ -68,14 +233,15 @@ impl Index {
 mod tests {
     use super::*;
     use crate::test_util as test;
-    use uuid::Uuid;
 
     #[tokio::test]
     async fn create_new_index() {
         let location = test::create_a_test_directory();
         let base_offset = 5000;
 
-        let index = Index::new(&location.to_owned(), base_offset).await.unwrap();
+        let index = Index::new(&location.to_owned(), 1, base_offset, WriteMode::Buffered)
+            .await
+            .unwrap();
 
         assert_eq!(index.next_offset, base_offset);
     }
@@ -83,28 +249,86 @@ mod tests {
     #[tokio::test]
     async fn next_offset_for_existing_index() {
         let location = test::create_a_test_directory();
-        files::create(&location.join("1000").with_extension("index")).unwrap();
+        files::create(
+            &location.join("1000").with_extension("index"),
+            WriteMode::Buffered,
+        )
+        .unwrap();
 
-        let mut index = Index::open(&location.to_owned(), 1000).await.unwrap();
-        index.add_entry(100).await.unwrap();
-        index.add_entry(101).await.unwrap();
+        let mut index = Index::open(&location.to_owned(), 1000, 1000, WriteMode::Buffered)
+            .await
+            .unwrap();
+        index.add_entry(1000, 100).await.unwrap();
+        index.add_entry(1001, 101).await.unwrap();
 
         assert_eq!(index.next_offset, 1002);
+        let index = Index::open(&location.to_owned(), 1000, 1000, WriteMode::Buffered)
+            .await
+            .unwrap();
+        assert_eq!(index.next_offset, 1002);
     }
 
     #[tokio::test]
     async fn find_entry() {
         let base_offset = 5000;
         let location = test::create_a_test_directory();
-        let mut index = Index::new(&location.to_owned(), base_offset).await.unwrap();
-        index.add_entry(100).await.unwrap();
-        index.add_entry(101).await.unwrap();
-        index.add_entry(102).await.unwrap();
+        let mut index = Index::new(&location.to_owned(), 1, base_offset, WriteMode::Buffered)
+            .await
+            .unwrap();
+        index.add_entry(5000, 100).await.unwrap();
+        index.add_entry(5001, 101).await.unwrap();
+        index.add_entry(5002, 102).await.unwrap();
 
         assert_eq!(index.find_entry(5001).await.unwrap(), 101);
 
-        index.add_entry(103).await.unwrap();
+        index.add_entry(5003, 103).await.unwrap();
         assert_eq!(index.find_entry(5002).await.unwrap(), 102);
         assert_eq!(index.find_entry(5003).await.unwrap(), 103);
+        assert!(index
+            .find_entry(4999)
+            .await
+            .unwrap_err()
+            .is_offset_out_of_range());
+    }
+
+    #[tokio::test]
+    async fn find_entry_with_gaps() {
+        let base_offset = 5000;
+        let location = test::create_a_test_directory();
+        let mut index = Index::new(&location.to_owned(), 1, base_offset, WriteMode::Buffered)
+            .await
+            .unwrap();
+        index.add_entry(5001, 0).await.unwrap();
+        index.add_entry(5004, 20).await.unwrap();
+        index.add_entry(5005, 40).await.unwrap();
+
+        assert_eq!(index.find_entry(5000).await.unwrap(), 0);
+        assert_eq!(index.find_entry(5002).await.unwrap(), 20);
+        assert_eq!(index.find_entry(5005).await.unwrap(), 40);
+        assert!(index
+            .find_entry(5006)
+            .await
+            .unwrap_err()
+            .is_offset_out_of_range());
+        assert_eq!(index.next_offset, 5006);
+    }
+
+    #[tokio::test]
+    async fn truncate_entries() {
+        let location = test::create_a_test_directory();
+        let mut index = Index::new(&location.to_owned(), 1, 10, WriteMode::Buffered)
+            .await
+            .unwrap();
+        index.add_entry(11, 0).await.unwrap();
+        index.add_entry(13, 30).await.unwrap();
+
+        index.truncate(1).await.unwrap();
+
+        assert_eq!(index.next_offset, 12);
+        assert_eq!(index.size(), 16);
+        index.add_entry(12, 30).await.unwrap();
+        assert_eq!(index.find_entry(12).await.unwrap(), 30);
+        index.truncate(0).await.unwrap();
+        assert_eq!(index.next_offset, 10);
     }
 }
@@ -0,0 +1,520 @@
+use crate::batch::Batch;
+use crate::compression::Codec;
+use crate::directories::DataDirs;
+use crate::files;
+use crate::files::*;
+use crate::handle::Handle;
+use crate::ledger::LedgerRepository;
+use crate::segment::{read_base_offset, write_base_offset};
+use crate::types::{Error, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const RECORD_HEADER_SIZE: usize = 4 + 4; // record size + crc
+const JOURNAL: &str = "journal";
+/// The journal being checkpointed, which is removed once the segments are synced.
+const CHECKPOINT: &str = "journal.checkpoint";
+/// The records replay kept for ledgers in a data directory that was unavailable.
+const PENDING: &str = "journal.pending";
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+enum Request {
+    Append(Vec<u8>),
+    Flush(oneshot::Sender<io::Result<()>>),
+    Rotate(oneshot::Sender<io::Result<()>>),
+}
+
+/// A single sequential file every batch is written to before it goes to its
+/// segment, so that an append costs one fsync shared with the appends around it,
+/// whatever ledger they go to. Appends queue their record and write the segment
+/// files under the lock of the ledger, then wait for the next sync outside of it.
+/// Segment files are written through the page cache and only synced at checkpoints,
+/// which set the journal aside first and drop it once every ledger is synced.
+///
+/// Records are laid out as
+/// record size (u32) | crc (u32) | segment id (u64) | ledger id size (u16) | ledger id | batch
+/// where a batch of only a base offset (u64) rolls back the batches of the segment
+/// from there on. A batch written again at an offset rolls back the ones after it too.
+///
+/// A failed write or sync leaves the journal failed, every later append fails
+/// until a restart replays it.
+#[derive(Clone)]
+pub struct Journal {
+    location: PathBuf,
+    requests: mpsc::Sender<Request>,
+    failed: Arc<AtomicBool>,
+}
+
+impl Journal {
+    pub fn open(location: &Path) -> Result<Journal> {
+        files::create_dir(location)?;
+        let file = open(location)?;
+        let (requests, receiver) = mpsc::channel();
+        let failed = Arc::new(AtomicBool::new(false));
+        let writer = (location.to_owned(), failed.clone());
+        thread::spawn(move || write(writer, file, receiver));
+        Ok(Journal {
+            location: location.to_owned(),
+            requests,
+            failed,
+        })
+    }
+
+    /// The journal of one segment of a ledger.
+    pub fn segment(&self, ledger_id: &str, segment_id: u64) -> SegmentJournal {
+        SegmentJournal {
+            journal: self.clone(),
+            ledger_id: ledger_id.to_owned(),
+            segment_id,
+        }
+    }
+
+    /// Returns once every record queued before is on disk.
+    pub async fn flush(&self) -> Result<()> {
+        let (ack, done) = oneshot::channel();
+        self.send(Request::Flush(ack))?;
+        wait(done).await
+    }
+
+    /// Sets the records so far aside for a checkpoint, unless the last checkpoint
+    /// failed and left its records there, which then take these along.
+    pub async fn rotate(&self) -> Result<()> {
+        let (ack, done) = oneshot::channel();
+        self.send(Request::Rotate(ack))?;
+        wait(done).await
+    }
+
+    /// Drops the records set aside by `rotate`, once the segments are synced.
+    pub fn remove_checkpoint(&self) -> Result<()> {
+        let checkpoint = self.location.join(CHECKPOINT);
+        if checkpoint.exists() {
+            files::remove_file(&checkpoint)?;
+            files::sync_dir(&self.location)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, request: Request) -> Result<()> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(failed().into());
+        }
+        self.requests
+            .send(request)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "journal is closed").into())
+    }
+}
+
+#[derive(Clone)]
+pub struct SegmentJournal {
+    journal: Journal,
+    ledger_id: String,
+    segment_id: u64,
+}
+
+impl SegmentJournal {
+    /// Queues the batch, it is on disk once `Journal::flush` returns.
+    pub fn append(&self, batch: &Batch) -> Result<()> {
+        let record = record(&self.ledger_id, self.segment_id, &batch.encode());
+        if record.len() - RECORD_HEADER_SIZE > u32::MAX as usize {
+            return Err(Error::BatchTooLarge(batch.size(), u32::MAX));
+        }
+        self.journal.send(Request::Append(record))
+    }
+
+    /// Rolls back the batches queued from `base_offset` on, which never made it
+    /// to the segment.
+    pub fn roll_back(&self, base_offset: u64) -> Result<()> {
+        let record = record(&self.ledger_id, self.segment_id, &base_offset.to_be_bytes());
+        self.journal.send(Request::Append(record))
+    }
+}
+
+async fn wait(done: oneshot::Receiver<io::Result<()>>) -> Result<()> {
+    match done.await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "journal is closed").into()),
+    }
+}
+
+fn failed() -> io::Error {
+    io::Error::other("the journal failed, it takes no appends until a restart")
+}
+
+fn open(location: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(location.join(JOURNAL))
+}
+
+/// Writes the requests as they come, syncing the appends that queued up
+/// meanwhile together before acknowledging the flushes among them.
+fn write(
+    (location, failed): (PathBuf, Arc<AtomicBool>),
+    mut file: fs::File,
+    requests: mpsc::Receiver<Request>,
+) {
+    let fail = |e: io::Error| {
+        println!("journal failed: {}", e);
+        failed.store(true, Ordering::SeqCst);
+    };
+    while let Ok(first) = requests.recv() {
+        let mut flushes = Vec::new();
+        let mut written = false;
+        for request in std::iter::once(first).chain(requests.try_iter()) {
+            match request {
+                Request::Append(record) => {
+                    if !failed.load(Ordering::SeqCst) {
+                        file.write_all(&record).unwrap_or_else(fail);
+                        written = true;
+                    }
+                }
+                Request::Flush(ack) => flushes.push(ack),
+                Request::Rotate(ack) => {
+                    commit(&file, written, &mut flushes, &failed, fail);
+                    written = false;
+                    let rotated = match rotate(&location) {
+                        Ok(Some(rotated)) => {
+                            file = rotated;
+                            Ok(())
+                        }
+                        Ok(None) => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    ack.send(rotated).unwrap_or(());
+                }
+            }
+        }
+        commit(&file, written, &mut flushes, &failed, fail);
+    }
+}
+
+fn commit<F: Fn(io::Error)>(
+    file: &fs::File,
+    written: bool,
+    flushes: &mut Vec<oneshot::Sender<io::Result<()>>>,
+    failed: &AtomicBool,
+    fail: F,
+) {
+    if written && !failed.load(Ordering::SeqCst) {
+        file.sync_data().unwrap_or_else(fail);
+    }
+    for ack in flushes.drain(..) {
+        let result = if failed.load(Ordering::SeqCst) {
+            Err(self::failed())
+        } else {
+            Ok(())
+        };
+        ack.send(result).unwrap_or(());
+    }
+}
+
+/// Moves the journal aside for a checkpoint and starts a new one, unless the
+/// last checkpoint is still there.
+fn rotate(location: &Path) -> io::Result<Option<fs::File>> {
+    let checkpoint = location.join(CHECKPOINT);
+    if checkpoint.exists() {
+        return Ok(None);
+    }
+    files::rename(&location.join(JOURNAL), &checkpoint)?;
+    let file = open(location)?;
+    files::sync_dir(location)?;
+    Ok(Some(file))
+}
+
+fn record(ledger_id: &str, segment_id: u64, batch: &[u8]) -> Vec<u8> {
+    let id = ledger_id.as_bytes();
+    let mut record = vec![0; RECORD_HEADER_SIZE + 8 + 2];
+    BigEndian::write_u64(&mut record[RECORD_HEADER_SIZE..], segment_id);
+    BigEndian::write_u16(&mut record[RECORD_HEADER_SIZE + 8..], id.len() as u16);
+    record.extend_from_slice(id);
+    record.extend_from_slice(batch);
+    let size = record.len() - RECORD_HEADER_SIZE;
+    let crc = crc::crc32::checksum_ieee(&record[RECORD_HEADER_SIZE..]);
+    BigEndian::write_u32(&mut record, size as u32);
+    BigEndian::write_u32(&mut record[4..], crc);
+    record
+}
+
+/// The records of the journal up to the first torn or corrupt one, which is
+/// where writing stopped.
+fn records(bytes: &[u8]) -> Vec<(String, u64, &[u8])> {
+    let mut records = Vec::new();
+    let mut cursor = 0;
+    while cursor + RECORD_HEADER_SIZE <= bytes.len() {
+        let size = BigEndian::read_u32(&bytes[cursor..]) as usize;
+        let start = cursor + RECORD_HEADER_SIZE;
+        if size < 8 + 2 || start + size > bytes.len() {
+            break;
+        }
+        let body = &bytes[start..start + size];
+        if crc::crc32::checksum_ieee(body) != BigEndian::read_u32(&bytes[cursor + 4..]) {
+            break;
+        }
+        let segment_id = BigEndian::read_u64(body);
+        let id_size = BigEndian::read_u16(&body[8..]) as usize;
+        if 8 + 2 + id_size > size {
+            break;
+        }
+        let ledger_id = String::from_utf8_lossy(&body[10..10 + id_size]).into_owned();
+        records.push((ledger_id, segment_id, &body[10 + id_size..]));
+        cursor = start + size;
+    }
+    records
+}
+
+/// Writes the journaled batches missing from the segments, which the page cache
+/// lost in a crash, then syncs the segments and drops the journal. The records
+/// kept by an earlier replay come first, then the ones a checkpoint set aside.
+/// Records of segments below the first one left in their ledger, whose files are
+/// gone, are skipped. The records of ledgers not found while a data
+/// directory is unavailable are kept for the next replay, the ones of ledgers
+/// not found anywhere are dropped. Returns the number of batches written.
+pub async fn replay(location: &Path, dirs: &DataDirs) -> Result<usize> {
+    let mut journaled: HashMap<(String, u64), Vec<Batch>> = HashMap::new();
+    let mut pending = Vec::new();
+    let mut found = HashMap::new();
+    for name in &[PENDING, CHECKPOINT, JOURNAL] {
+        let path = location.join(name);
+        if !path.exists() {
+            continue;
+        }
+        let bytes = files::read(&path)?;
+        for (ledger_id, segment_id, batch) in records(&bytes) {
+            let ledger = ledger_id.clone();
+            if !*found
+                .entry(ledger)
+                .or_insert_with(|| dirs.find(&ledger_id).is_some())
+            {
+                if !dirs.all_available() {
+                    pending.extend(record(&ledger_id, segment_id, batch));
+                }
+                continue;
+            }
+            let batches = journaled.entry((ledger_id, segment_id)).or_default();
+            if batch.len() == 8 {
+                roll_back(batches, BigEndian::read_u64(batch));
+                continue;
+            }
+            let batch = Batch::decode(batch)?
+                .pop()
+                .ok_or(Error::CorruptBatch(segment_id))?;
+            roll_back(batches, batch.base_offset);
+            batches.push(batch);
+        }
+    }
+    let mut replayed = 0;
+    for ((ledger_id, segment_id), batches) in journaled {
+        let location = match dirs.find(&ledger_id) {
+            Some(path) => path.join(&ledger_id),
+            None => return Err(Error::LedgerNotFound(ledger_id)),
+        };
+        if batches.is_empty() || segment_id < first_segment(&location)? {
+            continue;
+        }
+        let mode = WriteMode::Buffered;
+        let mut handle = if Handle::exists(&location, segment_id) {
+            let base_offset = read_base_offset(&location, segment_id)?;
+            Handle::open(&location, segment_id, base_offset, Codec::None, None, mode).await?
+        } else {
+            let base_offset = batches[0].base_offset;
+            let handle =
+                Handle::new(&location, segment_id, base_offset, Codec::None, None, mode).await?;
+            write_base_offset(&location, segment_id, base_offset)?;
+            handle
+        };
+        for batch in batches {
+            if handle.restore(batch).await? {
+                replayed += 1;
+            }
+        }
+        handle.sync().await?;
+    }
+    if !pending.is_empty() {
+        files::write_atomic(&location.join(PENDING), &pending)?;
+        println!("kept journal records of ledgers in unavailable data directories");
+    }
+    let names: &[&str] = if pending.is_empty() {
+        &[JOURNAL, CHECKPOINT, PENDING]
+    } else {
+        &[JOURNAL, CHECKPOINT]
+    };
+    for name in names {
+        let path = location.join(name);
+        if path.exists() {
+            files::remove_file(&path)?;
+        }
+    }
+    Ok(replayed)
+}
+
+/// Drops the batches from `base_offset` on, which a later record rolled back.
+fn roll_back(batches: &mut Vec<Batch>, base_offset: u64) {
+    while batches.last().is_some_and(|b| b.base_offset >= base_offset) {
+        batches.pop();
+    }
+}
+
+/// The lowest id among the segments of the ledger stored at `location`.
+fn first_segment(location: &Path) -> Result<u64> {
+    let mut ids = Vec::new();
+    for extension in &["segment", "index", "remote"] {
+        ids.extend(files::list_files_as_u64(location, extension)?);
+    }
+    Ok(ids.into_iter().min().unwrap_or(0))
+}
+
+/// Syncs the segments and drops the journal they no longer need every `interval`.
+pub async fn run(repository: LedgerRepository, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = repository.checkpoint().await {
+            println!("checkpoint failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch;
+    use crate::config::LedgerConfig;
+    use crate::directories::Placement;
+    use crate::test_util as test;
+
+    fn batch(base_offset: u64, entries: &[Vec<u8>]) -> Batch {
+        Batch {
+            base_offset,
+            attributes: 0,
+            count: entries.len() as u32,
+            producer: None,
+            payload: batch::pack(entries),
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_journaled_batches() {
+        let location = test::create_a_test_directory();
+        let path = location.join("ledgers");
+        let journal = Journal::open(&location).unwrap();
+        let segment = journal.segment("a", 7);
+        segment.append(&batch(0, &[vec![1], vec![2]])).unwrap();
+        segment.append(&batch(2, &[vec![3]])).unwrap();
+        let segment = journal.segment("b", 3);
+        segment.append(&batch(5, &[vec![4]])).unwrap();
+        let segment = journal.segment("c", 0);
+        segment.append(&batch(0, &[vec![5]])).unwrap();
+        journal.flush().await.unwrap();
+        let (a, b) = (path.join("a"), path.join("b"));
+        files::create_dir(&b).unwrap();
+        let dirs = DataDirs::new(vec![path.clone()], Placement::FreeSpace);
+        let mode = WriteMode::Buffered;
+        let mut handle = Handle::new(&a, 7, 0, Codec::None, None, mode)
+            .await
+            .unwrap();
+        write_base_offset(&a, 7, 0).unwrap();
+        handle.add(vec![vec![1], vec![2]]).await.unwrap();
+        drop(handle);
+
+        assert_eq!(replay(&location, &dirs).await.unwrap(), 2);
+        let handle = Handle::open(&a, 7, 0, Codec::None, None, mode).await;
+        assert_eq!(handle.unwrap().next_offset(), 3);
+        let handle = Handle::open(&b, 3, 5, Codec::None, None, mode).await;
+        assert_eq!(handle.unwrap().next_offset(), 6);
+        assert_eq!(read_base_offset(&b, 3).unwrap(), 5);
+        assert_eq!(replay(&location, &dirs).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn skip_rolled_back_batches_and_deleted_segments() {
+        let location = test::create_a_test_directory();
+        let path = location.join("ledgers");
+        let journal = Journal::open(&location).unwrap();
+        let segment = journal.segment("a", 2);
+        segment.append(&batch(0, &[vec![1]])).unwrap();
+        segment.append(&batch(1, &[vec![2]])).unwrap();
+        segment.roll_back(1).unwrap();
+        segment.append(&batch(1, &[vec![3]])).unwrap();
+        segment.append(&batch(2, &[vec![4]])).unwrap();
+        segment.append(&batch(1, &[vec![3], vec![4]])).unwrap();
+        journal
+            .segment("a", 1)
+            .append(&batch(0, &[vec![5]]))
+            .unwrap();
+        journal.flush().await.unwrap();
+        let a = path.join("a");
+        files::create_dir(&a).unwrap();
+        write_base_offset(&a, 2, 0).unwrap();
+        let dirs = DataDirs::new(vec![path.clone()], Placement::FreeSpace);
+
+        assert_eq!(replay(&location, &dirs).await.unwrap(), 2);
+        let mode = WriteMode::Buffered;
+        let handle = Handle::open(&a, 2, 0, Codec::None, None, mode).await;
+        assert_eq!(handle.unwrap().next_offset(), 3);
+        assert!(!Handle::exists(&a, 1));
+    }
+
+    #[tokio::test]
+    async fn keep_records_of_unavailable_ledgers() {
+        let location = test::create_a_test_directory();
+        let path = location.join("ledgers");
+        let journal = Journal::open(&location).unwrap();
+        journal
+            .segment("a", 0)
+            .append(&batch(0, &[vec![1]]))
+            .unwrap();
+        journal.flush().await.unwrap();
+        files::write(&location.join("file"), b"").unwrap();
+        let failed = location.join("file").join("ledgers");
+        let dirs = DataDirs::new(vec![path.clone(), failed], Placement::FreeSpace);
+
+        assert_eq!(replay(&location, &dirs).await.unwrap(), 0);
+        assert!(location.join(PENDING).exists());
+        files::create_dir(&path.join("a")).unwrap();
+        let dirs = DataDirs::new(vec![path.clone()], Placement::FreeSpace);
+        assert_eq!(replay(&location, &dirs).await.unwrap(), 1);
+        assert!(!location.join(PENDING).exists());
+    }
+
+    #[tokio::test]
+    async fn checkpoint_ledgers() {
+        let location = test::create_a_test_directory();
+        let journal = Journal::open(&location).unwrap();
+        let repository = LedgerRepository::new(None).with_journal(journal);
+        let id = repository
+            .create(&location, LedgerConfig::new(1000))
+            .await
+            .unwrap();
+        repository.add(&id, 0, vec![vec![1, 2]]).await.unwrap();
+
+        let path = location.join(JOURNAL);
+        assert!(files::size(&path).unwrap() > 0);
+        repository.checkpoint().await.unwrap();
+        assert_eq!(files::size(&path).unwrap(), 0);
+        assert!(!location.join(CHECKPOINT).exists());
+        repository.add(&id, 0, vec![vec![3]]).await.unwrap();
+        assert_eq!(records(&files::read(&path).unwrap())[0].0, id);
+    }
+
+    #[test]
+    fn stop_at_torn_record() {
+        let mut bytes = record("a", 1, &batch(0, &[vec![1]]).encode());
+        bytes.extend(record("a", 1, &batch(1, &[vec![2]]).encode()));
+        let torn = bytes.len() - 1;
+
+        assert_eq!(records(&bytes).len(), 2);
+        assert_eq!(records(&bytes[..torn]).len(), 1);
+        bytes[torn] ^= 1;
+        assert_eq!(records(&bytes)[0].0, "a");
+        assert_eq!(records(&bytes).len(), 1);
+    }
+}
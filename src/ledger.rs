@@ -1,112 +1,837 @@
+use crate::archive::{ArchiveReader, ArchiveWriter};
+use crate::compaction;
+use crate::config::{ConfigUpdate, LedgerConfig};
+use crate::encryption::*;
 use crate::files::*;
+use crate::journal::Journal;
+use crate::producer::{Producer, Producers};
+use crate::scrubber;
 use crate::segment::*;
+use crate::tiering::ColdStore;
+use crate::transaction::{Coordinator, Marker, TransactionIndex};
 use crate::types::*;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::ops::Range;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWrite;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use uuid::Uuid;
 
+/// The open ledgers. Each ledger has a lock of its own, so that appends, compaction
+/// and the other background work on one ledger leave the others alone. Appends
+/// wait for the journal once they let go of their ledger, sharing its syncs.
+#[derive(Clone)]
 pub struct LedgerRepository {
-    ledgers: Arc<RwLock<HashMap<String, Ledger>>>,
+    ledgers: Arc<RwLock<HashMap<String, Arc<Mutex<Ledger>>>>>,
+    keyring: Option<Arc<Keyring>>,
+    coordinator: Arc<RwLock<Coordinator>>,
+    journal: Option<Journal>,
+    cold_store: Option<Arc<dyn ColdStore>>,
 }
 
 impl LedgerRepository {
-    pub fn new() -> LedgerRepository {
+    pub fn new(keyring: Option<Arc<Keyring>>) -> LedgerRepository {
         LedgerRepository {
             ledgers: Arc::new(RwLock::new(HashMap::new())),
+            keyring,
+            coordinator: Arc::new(RwLock::new(Coordinator::default())),
+            journal: None,
+            cold_store: None,
         }
     }
 
-    pub async fn create(&self, location: &Path, segment_size: u64) -> Result<String> {
-        let ledgers = self.ledgers.clone();
-        let mut ledgers = ledgers.write().await;
-        let ledger = Ledger::new(location, segment_size).await?;
+    /// Offloads sealed segments of the ledgers created or opened from now on to `store`.
+    pub fn with_cold_store(mut self, store: Arc<dyn ColdStore>) -> LedgerRepository {
+        self.cold_store = Some(store);
+        self
+    }
+
+    /// Journals the batches of the ledgers created from now on.
+    pub fn with_journal(mut self, journal: Journal) -> LedgerRepository {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Creates a ledger under `location`. The map is held meanwhile, so that
+    /// `open_if_new` cannot find the ledger before it is added.
+    pub async fn create(&self, location: &Path, config: LedgerConfig) -> Result<String> {
+        let mut ledgers = self.ledgers.write().await;
+        let mut ledger = Ledger::new(location, config, self.keyring.clone()).await?;
+        if let Some(journal) = &self.journal {
+            ledger.set_journal(journal.clone());
+        }
+        if let Some(store) = &self.cold_store {
+            ledger.set_cold_store(store.clone());
+        }
         let id = ledger.id.clone();
-        ledgers.insert(id.clone(), ledger);
+        ledgers.insert(id.clone(), Arc::new(Mutex::new(ledger)));
         Ok(id)
     }
+
+    /// Opens a ledger stored under `location`.
+    pub async fn open(&self, location: &Path, id: String, config: LedgerConfig) -> Result<()> {
+        let mut ledgers = self.ledgers.write().await;
+        self.open_into(&mut ledgers, location, id, config).await
+    }
+
+    /// Opens a ledger stored under `location` unless it is open already, such as
+    /// one moved in while the server runs. Returns whether it opened it.
+    pub async fn open_if_new(
+        &self,
+        location: &Path,
+        id: String,
+        config: LedgerConfig,
+    ) -> Result<bool> {
+        let mut ledgers = self.ledgers.write().await;
+        if ledgers.contains_key(&id) {
+            return Ok(false);
+        }
+        self.open_into(&mut ledgers, location, id, config).await?;
+        Ok(true)
+    }
+
+    async fn open_into(
+        &self,
+        ledgers: &mut HashMap<String, Arc<Mutex<Ledger>>>,
+        location: &Path,
+        id: String,
+        config: LedgerConfig,
+    ) -> Result<()> {
+        let keyring = self.keyring.clone();
+        let ledger = Ledger::open(location, id.clone(), config, keyring).await?;
+        let mut ledger = ledger.ok_or_else(|| Error::LedgerNotFound(id.clone()))?;
+        if let Some(journal) = &self.journal {
+            ledger.set_journal(journal.clone());
+        }
+        if let Some(store) = &self.cold_store {
+            ledger.set_cold_store(store.clone());
+        }
+        ledgers.insert(id, Arc::new(Mutex::new(ledger)));
+        Ok(())
+    }
+
+    /// The ledger `id`, to be locked on its own.
+    async fn ledger(&self, id: &str) -> Result<Arc<Mutex<Ledger>>> {
+        let ledgers = self.ledgers.read().await;
+        ledgers
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::LedgerNotFound(id.to_owned()))
+    }
+
+    /// Every ledger with its id, to be locked one at a time.
+    async fn ledgers(&self) -> Vec<(String, Arc<Mutex<Ledger>>)> {
+        let ledgers = self.ledgers.read().await;
+        let ledgers = ledgers.iter();
+        ledgers.map(|(id, l)| (id.clone(), l.clone())).collect()
+    }
+
+    pub async fn add(
+        &self,
+        id: &str,
+        segment_id: u64,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        let ledger = self.ledger(id).await?;
+        let range = ledger.lock().await.add(segment_id, entries).await?;
+        self.flush().await?;
+        Ok(range)
+    }
+
+    pub async fn add_from_producer(
+        &self,
+        id: &str,
+        segment_id: u64,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        let ledger = self.ledger(id).await?;
+        let mut ledger = ledger.lock().await;
+        let range = ledger
+            .add_from_producer(segment_id, producer, entries)
+            .await?;
+        drop(ledger);
+        self.flush().await?;
+        Ok(range)
+    }
+
+    pub async fn register_producer(&self, id: &str) -> Result<u64> {
+        let ledger = self.ledger(id).await?;
+        let mut ledger = ledger.lock().await;
+        ledger.producers.register()
+    }
+
+    /// Opens a transaction for a producer registered with any of the ledgers, which
+    /// its transactional batches are then taken by.
+    pub async fn begin_transaction(&self, producer_id: u64) -> Result<()> {
+        for (_, ledger) in self.ledgers().await {
+            if ledger.lock().await.producers.is_known(producer_id) {
+                return self.coordinator.write().await.begin(producer_id);
+            }
+        }
+        Err(Error::UnknownProducer(producer_id))
+    }
+
+    /// Adds a batch to the open transaction of the producer, marking the beginning of
+    /// the transaction in every segment it writes to.
+    pub async fn add_transactional(
+        &self,
+        id: &str,
+        segment_id: u64,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        let mut coordinator = self.coordinator.write().await;
+        let ledger = self.ledger(id).await?;
+        let mut ledger = ledger.lock().await;
+        let joined = coordinator.join(producer.id, id, segment_id)?;
+        ledger.producers.admit(producer.id);
+        if joined {
+            ledger.begin(segment_id, producer.id).await?;
+        }
+        let range = ledger
+            .add_transactional(segment_id, producer, entries)
+            .await?;
+        drop((ledger, coordinator));
+        self.flush().await?;
+        Ok(range)
+    }
+
+    /// Commits or aborts the transaction of the producer in every ledger it wrote to.
+    pub async fn end_transaction(&self, producer_id: u64, marker: Marker) -> Result<()> {
+        let mut coordinator = self.coordinator.write().await;
+        self.settle(&mut coordinator, producer_id, marker).await
+    }
+
+    async fn settle(
+        &self,
+        coordinator: &mut Coordinator,
+        producer_id: u64,
+        marker: Marker,
+    ) -> Result<()> {
+        let participants = coordinator.decide(producer_id, marker)?;
+        let ids: BTreeSet<&String> = participants.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            let ledger = self.ledger(id).await?;
+            let mut ledger = ledger.lock().await;
+            ledger.end(producer_id, marker).await?;
+        }
+        self.flush().await?;
+        for (id, segment_id) in participants {
+            coordinator.written(producer_id, &id, segment_id);
+        }
+        Ok(())
+    }
+
+    /// Ends each transaction with its marker, reporting the ones that fail so that
+    /// they are tried again later. Returns how many ended.
+    async fn settle_all(&self, coordinator: &mut Coordinator, ends: Vec<(u64, Marker)>) -> usize {
+        let mut ended = 0;
+        for (producer_id, marker) in ends {
+            match self.settle(coordinator, producer_id, marker).await {
+                Ok(_) => ended += 1,
+                Err(e) => println!("ending transaction {} failed: {}", producer_id, e),
+            }
+        }
+        ended
+    }
+
+    /// Keeps the coordinator log at `path`, after ending the transactions it left
+    /// pending in the open ledgers: the decided ones as decided, the others aborted.
+    /// Returns how many ended.
+    pub async fn recover_transactions(&self, path: &Path) -> Result<usize> {
+        let mut coordinator = self.coordinator.write().await;
+        *coordinator = Coordinator::open(path)?;
+        let pending = coordinator.pending();
+        Ok(self.settle_all(&mut coordinator, pending).await)
+    }
+
+    /// Aborts the transactions left without a step for `timeout`, and writes the
+    /// markers still missing from decided ones. Returns how many ended.
+    pub async fn expire_transactions(&self, timeout: Duration) -> usize {
+        let mut coordinator = self.coordinator.write().await;
+        let expired = coordinator.expired(timeout);
+        self.settle_all(&mut coordinator, expired).await
+    }
+
+    /// Reads from `offset` on as `read` says, and returns the records with the
+    /// offset to continue reading from.
+    /// The offloaded segments the read reaches are fetched back one at a time with
+    /// the ledger unlocked, before it is locked for the read.
+    pub async fn read(
+        &self,
+        id: &str,
+        offset: u64,
+        bytes: usize,
+        read: Read,
+    ) -> Result<(Vec<u8>, u64)> {
+        let ledger = self.ledger(id).await?;
+        loop {
+            let fetch = ledger.lock().await.segments.fetcher(offset, bytes);
+            match fetch {
+                Some(fetch) => blocking(fetch).await?,
+                None => break,
+            }
+        }
+        let mut ledger = ledger.lock().await;
+        let mut buf = Vec::new();
+        let next = ledger.read(read, offset, bytes, &mut buf).await?;
+        Ok((buf, next))
+    }
+
+    /// Waits for the journal to have the batches written so far on disk.
+    async fn flush(&self) -> Result<()> {
+        match &self.journal {
+            Some(journal) => journal.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the journal aside and syncs every segment to disk, after which the
+    /// records set aside are no longer needed. Appends go on to a new journal
+    /// meanwhile, only one ledger at a time waits for its sync.
+    pub async fn checkpoint(&self) -> Result<()> {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        journal.rotate().await?;
+        for (_, ledger) in self.ledgers().await {
+            ledger.lock().await.segments.sync().await?;
+        }
+        journal.remove_checkpoint()
+    }
+
+    /// The sealed segments of every ledger, which are the ones to scrub.
+    pub async fn sealed_segments(&self) -> Vec<(String, u64)> {
+        let mut sealed = Vec::new();
+        for (id, ledger) in self.ledgers().await {
+            let segments = ledger.lock().await.segments.sealed().into_iter();
+            sealed.extend(segments.map(|segment_id| (id.clone(), segment_id)));
+        }
+        sealed
+    }
+
+    /// Scrubs a segment on a blocking thread, with the ledger left unlocked, and
+    /// quarantines it if it is corrupt. A segment found corrupt is checked again
+    /// under the lock first, in case compaction changed its files meanwhile.
+    /// Returns the bytes read.
+    pub async fn scrub(&self, id: &str, segment_id: u64) -> Result<u64> {
+        let ledger = match self.ledger(id).await {
+            Ok(ledger) => ledger,
+            Err(_) => return Ok(0),
+        };
+        let scrub = match ledger.lock().await.segments.get(segment_id) {
+            Some(segment) if segment.quarantined().is_none() => {
+                segment.scrubber(scrubber::CHUNK_PAUSE)
+            }
+            _ => return Ok(0),
+        };
+        match blocking(scrub).await {
+            Err(e) if e.is_corrupt_segment() => {}
+            scrubbed => return scrubbed,
+        }
+        let mut ledger = ledger.lock().await;
+        let segment = match ledger.segments.get_mut(segment_id) {
+            Some(segment) if segment.quarantined().is_none() => segment,
+            _ => return Ok(0),
+        };
+        match blocking(segment.scrubber(Duration::from_secs(0))).await {
+            Err(e) if e.is_corrupt_segment() => {
+                segment.quarantine(&e.to_string())?;
+                Err(e)
+            }
+            scrubbed => scrubbed,
+        }
+    }
+
+    /// The quarantined segments of every ledger, with the reason they failed.
+    pub async fn quarantined(&self) -> Vec<(String, u64, String)> {
+        let mut quarantined = Vec::new();
+        for (id, ledger) in self.ledgers().await {
+            let ledger = ledger.lock().await;
+            for segment_id in ledger.segments.ids() {
+                let segment = ledger.segments.get(segment_id).unwrap();
+                if let Some(reason) = segment.quarantined() {
+                    quarantined.push((id.clone(), segment_id, reason.to_owned()));
+                }
+            }
+        }
+        quarantined
+    }
+
+    /// Takes the snapshot `name` of every ledger, holding appends to each ledger
+    /// off while it is taken. Returns where the snapshots of the ledgers are.
+    pub async fn snapshot(&self, name: &str) -> Result<Vec<PathBuf>> {
+        let mut snapshots = Vec::new();
+        for (_, ledger) in self.ledgers().await {
+            let mut ledger = ledger.lock().await;
+            snapshots.push(ledger.segments.snapshot(name).await?);
+        }
+        Ok(snapshots)
+    }
+
+    /// Offloads the sealed segments of every ledger older than `min_age`, see `Segments::offload`.
+    pub async fn offload(&self, min_age: Duration) -> Result<usize> {
+        let mut offloaded = 0;
+        for (_, ledger) in self.ledgers().await {
+            offloaded += ledger.lock().await.offload(min_age).await?;
+        }
+        Ok(offloaded)
+    }
+
+    /// Updates the settings of a ledger and returns them, see `Ledger::update_config`.
+    pub async fn update_config(&self, id: &str, update: ConfigUpdate) -> Result<LedgerConfig> {
+        let ledger = self.ledger(id).await?;
+        let mut ledger = ledger.lock().await;
+        Ok(ledger.update_config(update)?.clone())
+    }
+
+    /// Switches the ledger `id` to a new data key, see `Ledger::rotate_key`.
+    pub async fn rotate_key(&self, id: &str) -> Result<()> {
+        let ledger = self.ledger(id).await?;
+        let mut ledger = ledger.lock().await;
+        ledger.rotate_key()
+    }
+
+    /// The ids of the ledgers that are compacted.
+    pub async fn compacted_ledgers(&self) -> Vec<String> {
+        let mut compacted = Vec::new();
+        for (id, ledger) in self.ledgers().await {
+            if ledger.lock().await.config.compacted {
+                compacted.push(id);
+            }
+        }
+        compacted
+    }
+
+    /// Compacts the ledger `id`, holding off only the appends to it meanwhile.
+    pub async fn compact(&self, id: &str) -> Result<()> {
+        let ledger = self.ledger(id).await?;
+        let mut ledger = ledger.lock().await;
+        ledger.compact().await
+    }
 }
 
-pub fn new_repository() -> LedgerRepository {
-    LedgerRepository::new()
+pub fn new_repository(keyring: Option<Arc<Keyring>>) -> LedgerRepository {
+    LedgerRepository::new(keyring)
 }
 
 pub struct Ledger {
     pub id: String,
-    segment_size: u64,
+    config: LedgerConfig,
     segments: Segments,
+    producers: Producers,
 }
 
 impl Ledger {
-    pub async fn new(location: &Path, segment_size: u64) -> Result<Ledger> {
+    /// Creates a new ledger, encrypted with its own data key if a keyring is given.
+    pub async fn new(
+        location: &Path,
+        config: LedgerConfig,
+        keyring: Option<Arc<Keyring>>,
+    ) -> Result<Ledger> {
         let id = Uuid::new_v4().to_string();
+        Ledger::create(location, id, config, keyring).await
+    }
+
+    /// Creates a new ledger under a given id, such as the one of an imported ledger.
+    pub async fn create(
+        location: &Path,
+        id: String,
+        config: LedgerConfig,
+        keyring: Option<Arc<Keyring>>,
+    ) -> Result<Ledger> {
+        config.validate()?;
         let path = PathBuf::from(location).join(&id);
         create_dir(&path)?;
+        config.save(&path)?;
+        let keys = match keyring {
+            Some(keyring) => Some(LedgerKeys::create(&path, keyring)?),
+            None => None,
+        };
+        let producers = Producers::open(&path)?;
+        let segments = Segments::open(path, config.compression, keys)?
+            .with_preallocation(config.preallocation())
+            .with_write_mode(config.write_mode);
         Ok(Ledger {
             id,
-            segment_size,
-            segments: Segments::open(path)?,
+            config,
+            segments,
+            producers,
         })
     }
 
-    pub async fn open(location: &Path, id: String, segment_size: u64) -> Result<Option<Ledger>> {
+    pub async fn open(
+        location: &Path,
+        id: String,
+        config: LedgerConfig,
+        keyring: Option<Arc<Keyring>>,
+    ) -> Result<Option<Ledger>> {
         let path: PathBuf = location.join(&id);
         if !path.exists() {
             Ok(None)
         } else {
+            let keys = match keyring {
+                Some(keyring) => LedgerKeys::open(&path, keyring)?,
+                None if path.join("ledger.key").exists() => return Err(Error::KeyringMissing),
+                None => None,
+            };
+            let config = LedgerConfig::load(&path, config)?;
+            let mut segments = Segments::open(path, config.compression, keys)?
+                .with_preallocation(config.preallocation())
+                .with_write_mode(config.write_mode);
+            let mut producers = Producers::open(segments.location())?;
+            let sealed = segments.sealed();
+            for segment_id in segments.ids() {
+                let segment = segments.get_mut(segment_id).unwrap();
+                if sealed.contains(&segment_id) {
+                    segment.seal().await?;
+                }
+                for (producer, offsets) in segment.producers().await? {
+                    producers.record(&producer, offsets);
+                }
+            }
             Ok(Some(Ledger {
                 id,
-                segment_size,
-                segments: Segments::open(path)?,
+                config,
+                segments,
+                producers,
             }))
         }
     }
 
-    pub async fn add(&mut self, segment_id: u64, entries: Vec<Vec<u8>>) -> Result<()> {
-        let segment = self.segments.create_if_absent(segment_id);
-        if segment.size().await >= self.segment_size {
+    /// Adds `entries` to the segment and returns the offsets they got.
+    pub async fn add(&mut self, segment_id: u64, entries: Vec<Vec<u8>>) -> Result<Range<u64>> {
+        self.config.check_limits(&entries)?;
+        let segment = self.segments.writable(segment_id).await?;
+        if segment.size() >= self.config.segment_size {
             Err(Error::SegmentFull(segment_id))
         } else {
-            segment.add(entries).await?;
-            Ok(())
+            segment.add(entries).await
         }
     }
 
-    pub async fn stream<T>(
+    /// Adds a batch of `producer`, acknowledging batches it already wrote without
+    /// writing them again so that retries are safe.
+    pub async fn add_from_producer(
+        &mut self,
+        segment_id: u64,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        self.append(segment_id, producer, false, entries).await
+    }
+
+    pub async fn add_transactional(
         &mut self,
         segment_id: u64,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        self.append(segment_id, producer, true, entries).await
+    }
+
+    async fn append(
+        &mut self,
+        segment_id: u64,
+        producer: Producer,
+        transactional: bool,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        self.config.check_limits(&entries)?;
+        if let Some(offsets) = self.producers.duplicate(&producer)? {
+            return Ok(offsets);
+        }
+        let segment = self.segments.writable(segment_id).await?;
+        if segment.size() >= self.config.segment_size {
+            return Err(Error::SegmentFull(segment_id));
+        }
+        let offsets = if transactional {
+            segment.add_transactional(producer, entries).await?
+        } else {
+            segment.add_from_producer(producer, entries).await?
+        };
+        self.producers.record(&producer, offsets.clone());
+        Ok(offsets)
+    }
+
+    /// Marks the beginning of a transaction in the segment its batches are added to.
+    pub async fn begin(&mut self, segment_id: u64, producer_id: u64) -> Result<()> {
+        let segment = self.segments.writable(segment_id).await?;
+        if segment.size() >= self.config.segment_size {
+            return Err(Error::SegmentFull(segment_id));
+        }
+        segment.add_marker(producer_id, Marker::Begin).await
+    }
+
+    /// Ends a transaction in the active segment at the next offset of the ledger,
+    /// whichever segments it began in, as sealed segments are never written to.
+    pub async fn end(&mut self, producer_id: u64, marker: Marker) -> Result<()> {
+        let latest = self.segments.ids().last().cloned().unwrap_or(0);
+        let segment = self.segments.writable(latest).await?;
+        segment.add_marker(producer_id, marker).await
+    }
+
+    /// Streams entries from `offset` onwards, continuing into the following segments
+    /// until `bytes` are read. Returns the offset to continue reading from.
+    pub async fn stream<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<u64>
+    where
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        self.read(Read::Entries, offset, bytes, target).await
+    }
+
+    pub async fn stream_committed<T>(
+        &mut self,
+        offset: u64,
+        bytes: usize,
+        target: &mut T,
+    ) -> Result<u64>
+    where
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        self.read(Read::Committed, offset, bytes, target).await
+    }
+
+    pub async fn stream_raw<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<u64>
+    where
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        self.read(Read::Raw, offset, bytes, target).await
+    }
+
+    async fn read<T>(
+        &mut self,
+        read: Read,
         offset: u64,
         bytes: usize,
         target: &mut T,
-    ) -> Result<()>
+    ) -> Result<u64>
     where
         T: AsyncWrite + Unpin + ?Sized,
     {
-        if let Some(segment) = self.segments.get_mut(segment_id) {
-            segment.stream(offset, bytes, target).await?;
+        let (first, next) = (
+            self.segments.first_offset(),
+            self.segments.next_offset().await?,
+        );
+        if offset == next {
+            return Ok(offset);
         }
+        if offset < first || offset > next {
+            return Err(Error::OffsetOutOfRange {
+                requested: offset,
+                first,
+                next,
+            });
+        }
+        let mut offset = offset;
+        let mut remaining = bytes;
+        while let Some(id) = self.segments.find(offset) {
+            let transactions = match read {
+                Read::Committed => self.segments.transactions(id).await?,
+                _ => TransactionIndex::default(),
+            };
+            let segment = self.segments.get_mut(id).unwrap();
+            let mut buf = Vec::new();
+            let next = match read {
+                Read::Entries => segment.stream(offset, remaining, &mut buf).await?,
+                Read::Committed => {
+                    segment
+                        .stream_committed(offset, remaining, &mut buf, &transactions)
+                        .await?
+                }
+                Read::Raw => segment.stream_raw(offset, remaining, &mut buf).await?,
+            };
+            target.write_all(&buf).await?;
+            remaining = remaining.saturating_sub(buf.len());
+            let end = next >= segment.next_offset().await?;
+            offset = next;
+            if !end || remaining == 0 {
+                break;
+            }
+            let base_offset = segment.base_offset;
+            match self.segments.following(base_offset) {
+                Some(following) if self.segments.get(following).unwrap().base_offset == next => {}
+                _ => break,
+            }
+        }
+        Ok(offset)
+    }
+
+    /// The offsets of the ledger, from its first one up to the next one.
+    #[cfg_attr(not(test), allow(dead_code))] // only the tool exports and imports
+    pub async fn offsets(&mut self) -> Result<Range<u64>> {
+        Ok(self.segments.first_offset()..self.segments.next_offset().await?)
+    }
+
+    /// Writes the committed entries within `offsets` to `archive` and returns how many.
+    #[cfg_attr(not(test), allow(dead_code))] // only the tool exports and imports
+    pub async fn export<W: io::Write>(
+        &mut self,
+        offsets: Range<u64>,
+        archive: &mut ArchiveWriter<W>,
+    ) -> Result<u64> {
+        let mut exported = 0;
+        for id in self.segments.ids() {
+            if self.segments.get(id).unwrap().base_offset >= offsets.end {
+                break;
+            }
+            for (offset, entry) in self.segments.entries(id).await? {
+                if offsets.contains(&offset) {
+                    archive.add(offset, &entry)?;
+                    exported += 1;
+                }
+            }
+        }
+        Ok(exported)
+    }
+
+    /// Adds the entries of the archive read from `input`, renumbered from the next
+    /// offset of the ledger or at the offsets they had, which must come after it.
+    /// The whole archive is checked before anything is added, so that a corrupt
+    /// one leaves the ledger as it was. Runs of consecutive offsets are added in
+    /// batches, starting a new segment whenever one is full.
+    #[cfg_attr(not(test), allow(dead_code))] // only the tool exports and imports
+    pub async fn import<R: io::Read + io::Seek>(
+        &mut self,
+        mut input: R,
+        renumber: bool,
+    ) -> Result<Range<u64>> {
+        let mut archive = ArchiveReader::open(&mut input)?;
+        let mut next = self.segments.next_offset().await?;
+        if !renumber && self.segments.len() == 0 {
+            next = archive.manifest.offsets.start;
+        }
+        let first = next;
+        while let Some((offset, entry)) = archive.next()? {
+            let offset = if renumber { next } else { offset };
+            if offset < next {
+                let reason = format!("offset {} is before the next offset {}", offset, next);
+                return Err(Error::CorruptArchive(reason));
+            }
+            self.config.check_limits(&[entry])?;
+            next = offset + 1;
+        }
+        input.seek(io::SeekFrom::Start(0))?;
+        let mut archive = ArchiveReader::open(&mut input)?;
+        next = first;
+        let (mut run, mut run_offset, mut run_size) = (Vec::new(), next, 0);
+        while let Some((offset, entry)) = archive.next()? {
+            let offset = if renumber { next } else { offset };
+            let full = run_size + 4 + entry.len() > self.config.max_batch_size as usize;
+            if offset != run_offset + run.len() as u64 || full {
+                self.import_batch(run_offset, run).await?;
+                run = Vec::new();
+                run_offset = offset;
+                run_size = 0;
+            }
+            run_size += 4 + entry.len();
+            run.push(entry);
+            next = offset + 1;
+        }
+        self.import_batch(run_offset, run).await?;
+        Ok(first..next)
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))] // only the tool exports and imports
+    async fn import_batch(&mut self, base_offset: u64, entries: Vec<Vec<u8>>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.config.check_limits(&entries)?;
+        let latest = self.segments.ids().last().cloned();
+        let segment = match latest {
+            Some(id) if self.segments.get(id).unwrap().size() < self.config.segment_size => {
+                self.segments.get_mut(id).unwrap()
+            }
+            Some(id) => self.segments.create_at(id + 1, base_offset).await?,
+            None => self.segments.create_at(0, base_offset).await?,
+        };
+        segment.add_at(base_offset, entries).await?;
         Ok(())
     }
 
-    pub async fn segment_size(&self, segment_id: u64) -> u64 {
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.segments.set_journal(journal, &self.id);
+    }
+
+    pub fn set_cold_store(&mut self, store: Arc<dyn ColdStore>) {
+        self.segments.set_cold_store(store);
+    }
+
+    /// Offloads old sealed segments. Compacted ledgers stay local, as compaction
+    /// needs every segment at hand.
+    pub async fn offload(&mut self, min_age: Duration) -> Result<usize> {
+        if self.config.compacted {
+            return Ok(0);
+        }
+        self.segments.offload(min_age).await
+    }
+
+    pub fn config(&self) -> &LedgerConfig {
+        &self.config
+    }
+
+    /// Changes the settings that may change once the ledger exists, and keeps them.
+    pub fn update_config(&mut self, update: ConfigUpdate) -> Result<&LedgerConfig> {
+        let mut config = self.config.clone();
+        config.update(update)?;
+        config.save(self.segments.location())?;
+        self.segments.set_preallocation(config.preallocation());
+        self.config = config;
+        Ok(&self.config)
+    }
+
+    /// Switches to a new data key, which the segments started from now on use.
+    pub fn rotate_key(&mut self) -> Result<()> {
+        if !self.segments.is_encrypted() {
+            return Err(Error::LedgerNotEncrypted(self.id.clone()));
+        }
+        self.segments.rotate_keys()
+    }
+
+    pub async fn compact(&mut self) -> Result<()> {
+        compaction::compact(&mut self.segments).await
+    }
+
+    pub fn segment_size(&self, segment_id: u64) -> u64 {
         match self.segments.get(segment_id) {
-            Some(segment) => segment.size().await,
+            Some(segment) => segment.size(),
             None => 0,
         }
     }
 }
 
+/// How `Ledger::read` passes the batches on.
+#[derive(Clone, Copy)]
+pub enum Read {
+    /// Every entry, as an entry record.
+    Entries,
+    /// The entries of committed transactions and outside of them, up to the first open one.
+    Committed,
+    /// The batches as they are stored, compressed or not.
+    Raw,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compaction::keyed_entry;
+    use crate::compression::Codec;
+    use crate::files;
     use crate::test_util as test;
+    use crate::tiering::LocalStore;
 
     #[tokio::test]
     async fn create_new_ledger() {
         let location = test::create_a_test_directory();
 
-        let ledger = Ledger::new(&location, 100).await.unwrap();
+        let ledger = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
 
         assert_eq!(ledger.id.len(), 36);
         assert!(location.join(ledger.id).exists());
@@ -117,7 +842,9 @@ mod tests {
         let location = test::create_a_test_directory();
         let id = "unknown_id".to_owned();
 
-        let ledger = Ledger::open(&location, id, 100).await.unwrap();
+        let ledger = Ledger::open(&location, id, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
 
         assert!(ledger.is_none());
     }
@@ -127,11 +854,15 @@ mod tests {
         let segment_id = 10;
         let location = test::create_a_test_directory();
         let entries = vec![vec![1, 2], vec![3, 4]];
-        let mut ledger = Ledger::new(&location, 100).await.unwrap();
+        let mut ledger = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
 
-        ledger.add(segment_id, entries).await.unwrap();
+        let offsets = ledger.add(segment_id, entries).await.unwrap();
 
-        assert_eq!(ledger.segment_size(segment_id).await, 28);
+        assert_eq!(offsets, 0..2);
+        assert_eq!(ledger.segment_size(segment_id), 33);
+        assert_eq!(ledger.add(segment_id, vec![vec![5]]).await.unwrap(), 2..3);
     }
 
     #[tokio::test]
@@ -139,58 +870,602 @@ mod tests {
         let segment_id = 10;
         let location = test::create_a_test_directory();
         let entries = vec![vec![1, 2], vec![3, 4]];
-        let mut ledger = Ledger::new(&location, 28).await.unwrap();
+        let mut ledger = Ledger::new(&location, LedgerConfig::new(33), None)
+            .await
+            .unwrap();
         ledger.add(segment_id, entries).await.unwrap();
 
         let result = ledger.add(segment_id, vec![vec![1]]).await;
 
         assert!(result.err().unwrap().is_segment_full());
-        let offset = 10;
+        let offset = 0;
         let bytes = 16000;
         let mut buf = Vec::new();
-        ledger
-            .stream(segment_id, offset, bytes, &mut buf)
-            .await
-            .unwrap();
+        ledger.stream(offset, bytes, &mut buf).await.unwrap();
         assert_eq!(
             &buf,
             &vec![
-                0, 0, 0, 0, 0, 0, 0, 10, //offset
+                0, 0, 0, 0, 0, 0, 0, 0, //offset
                 0, 0, 0, 2, //size
                 1, 2, //entry
-                0, 0, 0, 0, 0, 0, 0, 11, //offset
+                0, 0, 0, 0, 0, 0, 0, 1, //offset
                 0, 0, 0, 2, //size
                 3, 4 //entry
             ]
         );
     }
 
+    #[tokio::test]
+    async fn reject_oversized_entries() {
+        let location = test::create_a_test_directory();
+        let config = LedgerConfig {
+            max_entry_size: 4,
+            max_batch_size: 12,
+            ..LedgerConfig::new(100)
+        };
+        let mut ledger = Ledger::new(&location, config, None).await.unwrap();
+
+        let entry = ledger.add(0, vec![vec![1], vec![0; 5]]).await;
+        let batch = ledger.add(0, vec![vec![0; 4], vec![0; 4]]).await;
+
+        assert!(entry.err().unwrap().is_entry_too_large());
+        let batch = batch.err().unwrap();
+        assert!(batch.is_batch_too_large() && !batch.is_entry_too_large());
+        assert_eq!(ledger.segment_size(0), 0);
+        ledger.add(0, vec![vec![0; 4], vec![]]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_config_with_ledger() {
+        let location = test::create_a_test_directory();
+        let mut config = LedgerConfig {
+            compression: Codec::Lz4,
+            replication_factor: 3,
+            ..LedgerConfig::new(100)
+        };
+        config
+            .labels
+            .insert("team".to_owned(), "payments".to_owned());
+        let ledger = Ledger::new(&location, config, None).await.unwrap();
+
+        let defaults = LedgerConfig::new(1000);
+        let ledger = Ledger::open(&location, ledger.id.clone(), defaults.clone(), None).await;
+        let mut ledger = ledger.unwrap().unwrap();
+        assert_eq!(ledger.config().segment_size, 100);
+        assert_eq!(ledger.config().compression, Codec::Lz4);
+        assert_eq!(ledger.config().replication_factor, 3);
+        let mut update = ConfigUpdate {
+            segment_size: Some(10),
+            ..ConfigUpdate::default()
+        };
+        update.labels.insert("team".to_owned(), String::new());
+        ledger.update_config(update).unwrap();
+        let invalid = ConfigUpdate {
+            segment_size: Some(0),
+            ..ConfigUpdate::default()
+        };
+        assert!(ledger
+            .update_config(invalid)
+            .unwrap_err()
+            .is_invalid_config());
+        ledger.add(0, vec![vec![0; 20]]).await.unwrap();
+        assert!(ledger
+            .add(0, vec![vec![1]])
+            .await
+            .unwrap_err()
+            .is_segment_full());
+
+        let ledger = Ledger::open(&location, ledger.id.clone(), defaults.clone(), None).await;
+        let ledger = ledger.unwrap().unwrap();
+        assert_eq!(ledger.config().segment_size, 10);
+        assert!(ledger.config().labels.is_empty());
+
+        let path = location.join(&ledger.id);
+        for corrupt in &[
+            "compacted: yes",
+            "max-entry-size: 4294967296",
+            "segment-size: 0",
+        ] {
+            files::write(&path.join("config"), corrupt.as_bytes()).unwrap();
+            let error = LedgerConfig::load(&path, defaults.clone()).unwrap_err();
+            assert!(error.is_invalid_config());
+        }
+    }
+
+    #[tokio::test]
+    async fn deduplicate_producer_batches() {
+        let location = test::create_a_test_directory();
+        let config = LedgerConfig::new(100);
+        let mut ledger = Ledger::new(&location, config.clone(), None).await.unwrap();
+        let producer = Producer {
+            id: ledger.producers.register().unwrap(),
+            sequence: 0,
+        };
+        ledger
+            .add_from_producer(0, producer, vec![vec![1]])
+            .await
+            .unwrap();
+        ledger
+            .add_from_producer(0, producer, vec![vec![1]])
+            .await
+            .unwrap();
+
+        let mut ledger = Ledger::open(&location, ledger.id.clone(), config, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let offsets = ledger
+            .add_from_producer(0, producer, vec![vec![1]])
+            .await
+            .unwrap();
+        assert_eq!(offsets, 0..1);
+        let next = Producer {
+            sequence: 1,
+            ..producer
+        };
+        ledger
+            .add_from_producer(0, next, vec![vec![2]])
+            .await
+            .unwrap();
+
+        let mut buf = Vec::new();
+        ledger.stream(0, 16000, &mut buf).await.unwrap();
+        let mut expected = crate::batch::entry_record(0, &[1]);
+        expected.extend(crate::batch::entry_record(1, &[2]));
+        assert_eq!(buf, expected);
+    }
+
+    async fn committed(repository: &LedgerRepository, id: &str) -> Vec<u8> {
+        repository
+            .read(id, 0, 16000, Read::Committed)
+            .await
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_across_ledgers() {
+        let location = test::create_a_test_directory();
+        let repository = LedgerRepository::new(None);
+        let config = LedgerConfig::new(1000);
+        let first = repository.create(&location, config.clone()).await.unwrap();
+        let second = repository.create(&location, config).await.unwrap();
+        let producer_id = repository.register_producer(&first).await.unwrap();
+        let producer = |sequence| Producer {
+            id: producer_id,
+            sequence,
+        };
+        let unknown = Producer {
+            id: producer_id + 1,
+            sequence: 0,
+        };
+        let error = repository
+            .add_from_producer(&second, 0, unknown, vec![vec![1]])
+            .await
+            .unwrap_err();
+        assert!(error.is_unknown_producer());
+        let error = repository.begin_transaction(unknown.id).await.unwrap_err();
+        assert!(error.is_unknown_producer());
+
+        repository.begin_transaction(producer_id).await.unwrap();
+        for id in &[&first, &second] {
+            repository
+                .add_transactional(id, 0, producer(0), vec![vec![1]])
+                .await
+                .unwrap();
+        }
+        assert!(committed(&repository, &first).await.is_empty());
+        repository
+            .end_transaction(producer_id, Marker::Commit)
+            .await
+            .unwrap();
+
+        repository.begin_transaction(producer_id).await.unwrap();
+        repository
+            .add_transactional(&first, 0, producer(1), vec![vec![2]])
+            .await
+            .unwrap();
+        repository
+            .end_transaction(producer_id, Marker::Abort)
+            .await
+            .unwrap();
+        repository.add(&first, 0, vec![vec![3]]).await.unwrap();
+
+        let mut expected = crate::batch::entry_record(1, &[1]);
+        expected.extend(crate::batch::entry_record(6, &[3]));
+        assert_eq!(committed(&repository, &first).await, expected);
+        assert_eq!(
+            committed(&repository, &second).await,
+            crate::batch::entry_record(1, &[1])
+        );
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_across_segments() {
+        let location = test::create_a_test_directory();
+        let repository = LedgerRepository::new(None);
+        let id = repository
+            .create(&location, LedgerConfig::new(50))
+            .await
+            .unwrap();
+        let producer_id = repository.register_producer(&id).await.unwrap();
+        let producer = |sequence| Producer {
+            id: producer_id,
+            sequence,
+        };
+        repository.begin_transaction(producer_id).await.unwrap();
+        repository
+            .add_transactional(&id, 0, producer(0), vec![vec![1]])
+            .await
+            .unwrap();
+        let error = repository
+            .add_transactional(&id, 0, producer(1), vec![vec![2]])
+            .await
+            .unwrap_err();
+        assert!(error.is_segment_full());
+        repository
+            .add_transactional(&id, 2, producer(1), vec![vec![2]])
+            .await
+            .unwrap();
+        assert!(committed(&repository, &id).await.is_empty());
+        repository
+            .end_transaction(producer_id, Marker::Commit)
+            .await
+            .unwrap();
+
+        let ledger = repository.ledger(&id).await.unwrap();
+        let mut ledger = ledger.lock().await;
+        let segment = ledger.segments.get_mut(0).unwrap();
+        assert_eq!(segment.next_offset().await.unwrap(), 2);
+        let segment = ledger.segments.get_mut(2).unwrap();
+        assert_eq!(segment.next_offset().await.unwrap(), 5);
+        drop(ledger);
+        let mut expected = crate::batch::entry_record(1, &[1]);
+        expected.extend(crate::batch::entry_record(3, &[2]));
+        assert_eq!(committed(&repository, &id).await, expected);
+    }
+
+    #[tokio::test]
+    async fn abort_transactions_left_open() {
+        let location = test::create_a_test_directory();
+        let log = location.join("transactions");
+        let repository = LedgerRepository::new(None);
+        repository.recover_transactions(&log).await.unwrap();
+        let id = repository
+            .create(&location, LedgerConfig::new(1000))
+            .await
+            .unwrap();
+        let producer = Producer {
+            id: repository.register_producer(&id).await.unwrap(),
+            sequence: 0,
+        };
+        repository.begin_transaction(producer.id).await.unwrap();
+        repository
+            .add_transactional(&id, 0, producer, vec![vec![1]])
+            .await
+            .unwrap();
+        drop(repository);
+
+        let repository = LedgerRepository::new(None);
+        let config = LedgerConfig::new(1000);
+        repository
+            .open(&location, id.clone(), config)
+            .await
+            .unwrap();
+        assert_eq!(repository.recover_transactions(&log).await.unwrap(), 1);
+        repository.add(&id, 0, vec![vec![2]]).await.unwrap();
+
+        assert_eq!(
+            committed(&repository, &id).await,
+            crate::batch::entry_record(3, &[2])
+        );
+        assert!(repository.begin_transaction(producer.id).await.is_ok());
+        assert_eq!(
+            repository.expire_transactions(Duration::from_secs(0)).await,
+            1
+        );
+        let end = repository
+            .end_transaction(producer.id, Marker::Commit)
+            .await;
+        assert!(end.is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_every_segment_a_read_reaches() {
+        let location = test::create_a_test_directory();
+        let store = Arc::new(LocalStore::new(&location.join("cold")).unwrap());
+        let path = location.join("ledgers");
+        let mut ledger = Ledger::new(&path, LedgerConfig::new(1000), None)
+            .await
+            .unwrap();
+        ledger.set_cold_store(store);
+        ledger.add(0, vec![vec![1]]).await.unwrap();
+        ledger.add(1, vec![vec![2]]).await.unwrap();
+        ledger.add(2, vec![vec![3]]).await.unwrap();
+        assert_eq!(ledger.offload(Duration::from_secs(0)).await.unwrap(), 2);
+        let segments = path.join(&ledger.id);
+        let log = |id: u64| segments.join(id.to_string()).with_extension("log");
+
+        let fetch = ledger.segments.fetcher(0, 1).unwrap();
+        fetch().unwrap();
+        assert!(log(0).exists() && !log(1).exists());
+        assert!(ledger.segments.fetcher(0, 1).is_none());
+        let fetch = ledger.segments.fetcher(0, 16000).unwrap();
+        fetch().unwrap();
+        assert!(log(1).exists());
+        assert!(ledger.segments.fetcher(0, 16000).is_none());
+    }
+
+    #[tokio::test]
+    async fn compact_ledger() {
+        let location = test::create_a_test_directory();
+        let mut ledger = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
+        let (first, second) = (keyed_entry(b"k", &[1]), keyed_entry(b"k", &[2]));
+        ledger
+            .add(0, vec![first.clone(), second.clone(), first])
+            .await
+            .unwrap();
+        ledger.add(3, vec![second]).await.unwrap();
+
+        ledger.compact().await.unwrap();
+
+        assert_eq!(ledger.segment_size(0), 21 + 4 + 6);
+    }
+
+    #[tokio::test]
+    async fn keep_producers_of_sealed_segments() {
+        let location = test::create_a_test_directory();
+        let config = LedgerConfig::new(100);
+        let mut ledger = Ledger::new(&location, config.clone(), None).await.unwrap();
+        let id = ledger.producers.register().unwrap();
+        let producer = |sequence| Producer { id, sequence };
+        ledger
+            .add_from_producer(0, producer(0), vec![keyed_entry(b"k", &[1])])
+            .await
+            .unwrap();
+        ledger
+            .add_from_producer(0, producer(1), vec![keyed_entry(b"k", &[2])])
+            .await
+            .unwrap();
+        ledger.add(1, vec![keyed_entry(b"k", &[3])]).await.unwrap();
+        let path = location.join(&ledger.id);
+        assert!(path.join("0.producers").exists());
+
+        ledger.compact().await.unwrap();
+        files::remove_file(&path.join("0.producers")).unwrap();
+        let mut ledger = Ledger::open(&location, ledger.id.clone(), config, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(path.join("0.producers").exists());
+        let retried = ledger
+            .add_from_producer(1, producer(1), vec![keyed_entry(b"k", &[2])])
+            .await
+            .unwrap();
+        assert_eq!(retried, 1..2);
+        assert_eq!(files::size(&path.join("producers")).unwrap(), 8);
+    }
+
+    #[tokio::test]
+    async fn segment_full_with_compressed_bytes() {
+        let segment_id = 10;
+        let location = test::create_a_test_directory();
+        let config = LedgerConfig {
+            compression: Codec::Lz4,
+            ..LedgerConfig::new(100)
+        };
+        let mut ledger = Ledger::new(&location, config, None).await.unwrap();
+
+        ledger.add(segment_id, vec![vec![0; 90]]).await.unwrap();
+        ledger.add(segment_id, vec![vec![0; 90]]).await.unwrap();
+
+        assert!(ledger.segment_size(segment_id) < 100);
+        let mut buf = Vec::new();
+        ledger.stream(1, 16000, &mut buf).await.unwrap();
+        assert_eq!(buf, crate::batch::entry_record(1, &[0; 90]));
+    }
+
+    #[tokio::test]
+    async fn encrypt_ledger() {
+        let location = test::create_a_test_directory();
+        let keyring = test::keyring(&location);
+        let config = LedgerConfig::new(100);
+        let mut ledger = Ledger::new(&location, config.clone(), Some(keyring.clone()))
+            .await
+            .unwrap();
+        ledger.add(0, vec![b"plain".to_vec()]).await.unwrap();
+        ledger.rotate_key().unwrap();
+        ledger.add(1, vec![b"text".to_vec()]).await.unwrap();
+        let path = location.join(&ledger.id);
+        assert_ne!(
+            files::read(&path.join("0.key")).unwrap(),
+            files::read(&path.join("1.key")).unwrap()
+        );
+
+        let id = ledger.id.clone();
+        let mut ledger = Ledger::open(&location, id.clone(), config.clone(), Some(keyring))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut buf = Vec::new();
+        ledger.stream(0, 16000, &mut buf).await.unwrap();
+        let mut expected = crate::batch::entry_record(0, b"plain");
+        expected.extend(crate::batch::entry_record(1, b"text"));
+        assert_eq!(buf, expected);
+        let reopened = Ledger::open(&location, id, config.clone(), None).await;
+        assert!(reopened.err().unwrap().is_keyring_missing());
+        let mut plain = Ledger::new(&location, config, None).await.unwrap();
+        assert!(plain.rotate_key().is_err());
+    }
+
     #[tokio::test]
     async fn stream_entries_from_ledger() {
         let location = test::create_a_test_directory();
         let segment_id = 10;
         let entries = vec![vec![1, 2], vec![3, 4]];
-        let mut ledger = Ledger::new(&location, 100).await.unwrap();
+        let mut ledger = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
         ledger.add(segment_id, entries).await.unwrap();
 
         let mut buf = Vec::new();
-        let offset = 10;
+        let offset = 0;
         let bytes = 16000;
-        ledger
-            .stream(segment_id, offset, bytes, &mut buf)
-            .await
-            .unwrap();
+        ledger.stream(offset, bytes, &mut buf).await.unwrap();
 
         assert_eq!(
             &buf,
             &vec![
-                0, 0, 0, 0, 0, 0, 0, 10, //offset
+                0, 0, 0, 0, 0, 0, 0, 0, //offset
                 0, 0, 0, 2, //size
                 1, 2, //entry
-                0, 0, 0, 0, 0, 0, 0, 11, //offset
+                0, 0, 0, 0, 0, 0, 0, 1, //offset
                 0, 0, 0, 2, //size
                 3, 4 //entry
             ]
         );
     }
+
+    #[tokio::test]
+    async fn stream_from_ledger_offset() {
+        let location = test::create_a_test_directory();
+        let mut ledger = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
+        ledger.add(10, vec![vec![1], vec![2]]).await.unwrap();
+
+        assert_eq!(ledger.add(20, vec![vec![3]]).await.unwrap(), 2..3);
+        assert!(ledger
+            .add(10, vec![vec![4]])
+            .await
+            .err()
+            .unwrap()
+            .is_segment_sealed());
+        let mut buf = Vec::new();
+        ledger.stream(2, 16000, &mut buf).await.unwrap();
+        assert_eq!(buf, crate::batch::entry_record(2, &[3]));
+    }
+
+    #[tokio::test]
+    async fn stream_across_segments() {
+        let location = test::create_a_test_directory();
+        let mut ledger = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
+        ledger.add(10, vec![vec![1], vec![2]]).await.unwrap();
+        ledger.add(20, vec![vec![3]]).await.unwrap();
+        ledger.add(30, vec![vec![4]]).await.unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(ledger.stream(1, 16000, &mut buf).await.unwrap(), 4);
+        let mut expected = crate::batch::entry_record(1, &[2]);
+        expected.extend(crate::batch::entry_record(2, &[3]));
+        expected.extend(crate::batch::entry_record(3, &[4]));
+        assert_eq!(buf, expected);
+
+        let mut buf = Vec::new();
+        assert_eq!(ledger.stream(0, 1, &mut buf).await.unwrap(), 2);
+        assert_eq!(ledger.stream(4, 16000, &mut buf).await.unwrap(), 4);
+        let error = ledger.stream(5, 16000, &mut buf).await.unwrap_err();
+        assert!(error.is_offset_out_of_range());
+    }
+
+    #[tokio::test]
+    async fn read_raw_batches() {
+        let location = test::create_a_test_directory();
+        let repository = LedgerRepository::new(None);
+        let config = LedgerConfig {
+            compression: Codec::Zstd,
+            ..LedgerConfig::new(1000)
+        };
+        let id = repository.create(&location, config).await.unwrap();
+        repository
+            .add(&id, 0, vec![vec![1; 50], vec![2; 50]])
+            .await
+            .unwrap();
+
+        let (raw, next) = repository.read(&id, 0, 16000, Read::Raw).await.unwrap();
+        assert_eq!(next, 2);
+        let batch = crate::batch::Batch::decode(&raw).unwrap().pop().unwrap();
+        assert_eq!(batch.codec_id(), Codec::Zstd.id());
+        assert_eq!(
+            Codec::Zstd.decompress(&batch.payload).unwrap(),
+            crate::batch::pack(&[vec![1; 50], vec![2; 50]])
+        );
+        let (entries, _) = repository.read(&id, 0, 16000, Read::Entries).await.unwrap();
+        let mut expected = crate::batch::entry_record(0, &[1; 50]);
+        expected.extend(crate::batch::entry_record(1, &[2; 50]));
+        assert_eq!(entries, expected);
+    }
+
+    #[tokio::test]
+    async fn open_ledgers_moved_in() {
+        let location = test::create_a_test_directory();
+        let repository = LedgerRepository::new(None);
+        let config = LedgerConfig::new(1000);
+        let id = repository.create(&location, config.clone()).await.unwrap();
+        let moved = Ledger::new(&location, config.clone(), None).await.unwrap();
+        let moved = moved.id.clone();
+
+        let open = repository.open_if_new(&location, id, config.clone()).await;
+        assert!(!open.unwrap());
+        let open = repository
+            .open_if_new(&location, moved.clone(), config)
+            .await;
+        assert!(open.unwrap());
+        assert_eq!(
+            repository.add(&moved, 0, vec![vec![1]]).await.unwrap(),
+            0..1
+        );
+    }
+
+    #[tokio::test]
+    async fn export_and_import_ledger() {
+        use crate::archive::Manifest;
+        let location = test::create_a_test_directory();
+        let mut ledger = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
+        ledger.add(10, vec![vec![1], vec![2]]).await.unwrap();
+        ledger.add(20, vec![vec![3]]).await.unwrap();
+        ledger.add(30, vec![vec![4]]).await.unwrap();
+        let manifest = Manifest {
+            ledger_id: ledger.id.clone(),
+            offsets: 1..3,
+        };
+        let mut archive = ArchiveWriter::new(Vec::new(), &manifest).unwrap();
+        assert_eq!(ledger.export(1..3, &mut archive).await.unwrap(), 2);
+        let archive = archive.finish().unwrap();
+
+        let id = ledger.id.clone();
+        let (other, config) = (location.join("other"), LedgerConfig::new(100));
+        let mut imported = Ledger::create(&other, id, config, None).await.unwrap();
+        let cut_off = io::Cursor::new(&archive[..archive.len() - 1]);
+        let error = imported.import(cut_off, false).await.unwrap_err();
+        assert!(error.is_corrupt_archive());
+        assert_eq!(imported.segments.len(), 0);
+        let reader = io::Cursor::new(&archive);
+        assert_eq!(imported.import(reader, false).await.unwrap(), 1..3);
+        assert_eq!(imported.offsets().await.unwrap(), 1..3);
+        let mut buf = Vec::new();
+        imported.stream(1, 16000, &mut buf).await.unwrap();
+        let mut expected = crate::batch::entry_record(1, &[2]);
+        expected.extend(crate::batch::entry_record(2, &[3]));
+        assert_eq!(buf, expected);
+
+        let mut renumbered = Ledger::new(&location, LedgerConfig::new(100), None)
+            .await
+            .unwrap();
+        renumbered.add(0, vec![vec![9]]).await.unwrap();
+        let reader = io::Cursor::new(&archive);
+        assert_eq!(renumbered.import(reader, true).await.unwrap(), 1..3);
+        let reader = io::Cursor::new(&archive);
+        let error = renumbered.import(reader, false).await.unwrap_err();
+        assert!(error.is_corrupt_archive());
+        assert_eq!(renumbered.offsets().await.unwrap(), 0..3);
+    }
 }
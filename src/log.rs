@@ -1,39 +1,138 @@
+use crate::batch::{self, Batch};
 use crate::files;
 use crate::files::*;
 use crate::types::Result;
+use byteorder::{BigEndian, ByteOrder};
 use std::io::SeekFrom;
 use tokio::io::AsyncWrite;
 
 pub struct Log {
     pub id: u64,
     pub position: u64,
+    preallocated: u64,
     file: File,
+    direct: Option<DirectWriter>,
 }
 
 impl Log {
-    pub async fn new(location: &PathBuf, id: u64) -> Result<Log> {
+    pub async fn new(location: &PathBuf, id: u64, mode: WriteMode) -> Result<Log> {
         let path = location.join(id.to_string()).with_extension("log");
-        let file = files::create(&path)?;
-        let position = 0;
-        Ok(Log { id, file, position })
+        let file = files::create(&path, mode)?;
+        Ok(Log {
+            id,
+            position: 0,
+            preallocated: 0,
+            file,
+            direct: direct_writer(&path, mode)?,
+        })
     }
-    pub async fn open(location: &PathBuf, id: u64) -> Result<Log> {
+    pub async fn open(location: &PathBuf, id: u64, mode: WriteMode) -> Result<Log> {
         let path = location.join(id.to_string()).with_extension("log");
-        let file = files::open(&path)?;
-        let position = file.metadata().await.unwrap().len();
-        Ok(Log { id, file, position })
-    }
-
-    pub async fn add_entry(&mut self, offset: u64, entry: Vec<u8>) -> Result<()> {
-        let entry_size = entry.len() as u32;
-        let total_size = 8 + 4 + entry_size as usize; // offset + entry size + entry
-        let mut bytes = Vec::with_capacity(total_size);
-        bytes.extend(offset.to_be_bytes().iter());
-        bytes.extend(entry_size.to_be_bytes().iter());
-        bytes.extend(entry.iter());
-        self.file.write_all(&bytes).await?;
-        self.file.flush().await?;
-        self.position += total_size as u64;
+        let file = files::open(&path, mode)?;
+        let position = file.metadata().await?.len();
+        Ok(Log {
+            id,
+            position,
+            preallocated: 0,
+            file,
+            direct: direct_writer(&path, mode)?,
+        })
+    }
+
+    /// Opens the log for reading only, see `skip_padding`.
+    pub async fn open_read_only(location: &Path, id: u64) -> Result<Log> {
+        let path = location.join(id.to_string()).with_extension("log");
+        let file = files::open_read_only(&path)?;
+        let position = file.metadata().await?.len();
+        Ok(Log {
+            id,
+            position,
+            preallocated: 0,
+            file,
+            direct: None,
+        })
+    }
+
+    /// Reserves disk space for `bytes` of batches up front, so the log does not
+    /// fragment and running out of space cannot fail an add halfway through it.
+    pub fn preallocate(&mut self, bytes: u64) -> Result<()> {
+        files::preallocate(&self.file, bytes)?;
+        self.preallocated = bytes;
+        Ok(())
+    }
+
+    pub async fn add_batch(&mut self, batch: &Batch) -> Result<()> {
+        let bytes = batch.encode();
+        match &mut self.direct {
+            Some(direct) => direct.append(&bytes).await?,
+            None => {
+                self.file.write_all(&bytes).await?;
+                self.file.flush().await?;
+            }
+        }
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
+        if let Some(direct) = &self.direct {
+            direct.trim()?;
+        }
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    /// The end of the batch at `position`, if the log holds its header.
+    pub async fn batch_end(&mut self, position: u64) -> Result<Option<u64>> {
+        if position + batch::HEADER_SIZE as u64 > self.position {
+            return Ok(None);
+        }
+        self.file.seek(SeekFrom::Start(position)).await?;
+        let mut header = [0; batch::HEADER_SIZE];
+        self.file.read_exact(&mut header).await?;
+        self.file.seek(SeekFrom::End(0)).await?;
+        let size = BigEndian::read_u32(&header[8..]) as u64;
+        Ok(Some(position + batch::HEADER_SIZE as u64 + size))
+    }
+
+    /// Cuts off the zero padding of a direct write left past `end`, the end of
+    /// the last batch, when a crash kept the file from being trimmed.
+    pub async fn trim_padding(&mut self, end: u64) -> Result<()> {
+        if self.is_padding(end).await? {
+            self.truncate(end).await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the log at `end` if only padding follows, leaving the file as it is.
+    pub async fn skip_padding(&mut self, end: u64) -> Result<()> {
+        if self.is_padding(end).await? {
+            self.position = end;
+        }
+        Ok(())
+    }
+
+    async fn is_padding(&mut self, end: u64) -> Result<bool> {
+        if self.position <= end || self.position - end >= BLOCK_SIZE as u64 {
+            return Ok(false);
+        }
+        self.file.seek(SeekFrom::Start(end)).await?;
+        let mut padding = vec![0; (self.position - end) as usize];
+        self.file.read_exact(&mut padding).await?;
+        self.file.seek(SeekFrom::End(0)).await?;
+        Ok(padding.iter().all(|b| *b == 0))
+    }
+
+    /// Drops everything written from `position` onwards.
+    pub async fn truncate(&mut self, position: u64) -> Result<()> {
+        self.file.set_len(position).await?;
+        if let Some(direct) = &mut self.direct {
+            direct.rewind(position)?;
+        }
+        self.position = position;
+        if self.preallocated > 0 {
+            files::preallocate(&self.file, self.preallocated)?;
+        }
         Ok(())
     }
 
@@ -47,7 +146,7 @@ impl Log {
     where
         T: AsyncWrite + Unpin + ?Sized,
     {
-        let max_bytes = self.size().await as usize;
+        let max_bytes = self.size() as usize;
         let bytes = if bytes > max_bytes { max_bytes } else { bytes };
         self.file.seek(SeekFrom::Start(position)).await?;
         let mut buf = vec![0; bytes];
@@ -57,8 +156,31 @@ impl Log {
         Ok(())
     }
 
-    pub async fn size(&self) -> u64 {
-        self.file.metadata().await.unwrap().len()
+    /// Reads the whole batches within `bytes` from `position`, or just the first
+    /// one if it is larger than that.
+    pub async fn read_batches(&mut self, position: u64, bytes: usize) -> Result<Vec<Batch>> {
+        let available = self.position.saturating_sub(position) as usize;
+        if available < batch::HEADER_SIZE {
+            return Ok(vec![]);
+        }
+        self.file.seek(SeekFrom::Start(position)).await?;
+        let mut header = [0; batch::HEADER_SIZE];
+        self.file.read_exact(&mut header).await?;
+        let first = batch::HEADER_SIZE + BigEndian::read_u32(&header[8..]) as usize;
+        let mut buf = vec![0; bytes.max(first).min(available)];
+        self.file.seek(SeekFrom::Start(position)).await?;
+        self.file.read_exact(&mut buf).await?;
+        self.file.seek(SeekFrom::End(0)).await?;
+        Batch::decode(&buf)
+    }
+
+    pub async fn batches(&mut self) -> Result<Vec<Batch>> {
+        self.read_batches(0, self.position as usize).await
+    }
+
+    /// The logical end of the log, which is not where preallocated space ends.
+    pub fn size(&self) -> u64 {
+        self.position
     }
 }
 
@@ -67,81 +189,165 @@ mod tests {
     use super::*;
     use crate::test_util as test;
 
+    fn batch(base_offset: u64, entries: &[Vec<u8>]) -> Batch {
+        Batch {
+            base_offset,
+            attributes: 0,
+            count: entries.len() as u32,
+            producer: None,
+            payload: batch::pack(entries),
+        }
+    }
+
     #[tokio::test]
     async fn create_new_log() {
         let location = test::create_a_test_directory();
 
-        let log = Log::new(&location, 123).await.unwrap();
+        let log = Log::new(&location, 123, WriteMode::Buffered).await.unwrap();
 
         assert_eq!(log.position, 0);
-        assert_eq!(log.size().await, 0);
+        assert_eq!(log.size(), 0);
     }
 
     #[tokio::test]
-    async fn add_entry() {
+    async fn add_batch() {
         let id = 5000;
         let location = test::create_a_test_directory();
-        let mut log = Log::new(&location, id).await.unwrap();
+        let mut log = Log::new(&location, id, WriteMode::Buffered).await.unwrap();
 
-        log.add_entry(5000, vec![1, 2, 3]).await.unwrap();
-        log.add_entry(5001, vec![3, 4]).await.unwrap();
+        log.add_batch(&batch(5000, &[vec![1, 2, 3]])).await.unwrap();
+        log.add_batch(&batch(5001, &[vec![3, 4]])).await.unwrap();
 
-        assert_eq!(log.position, 29);
+        assert_eq!(log.position, 28 + 27);
     }
 
     #[tokio::test]
     async fn open_existing_log() {
         let location = test::create_a_test_directory();
         let id = 10;
-        let mut log = Log::new(&location, id).await.unwrap();
-        log.add_entry(5000, vec![1, 2, 3]).await.unwrap();
+        let mut log = Log::new(&location, id, WriteMode::Buffered).await.unwrap();
+        log.add_batch(&batch(5000, &[vec![1, 2, 3]])).await.unwrap();
+
+        let log = Log::open(&location, id, WriteMode::Buffered).await.unwrap();
+        assert_eq!(log.position, 28);
+    }
+
+    #[tokio::test]
+    async fn add_batches_in_every_write_mode() {
+        for mode in &[WriteMode::Buffered, WriteMode::Sync, WriteMode::Direct] {
+            let location = test::create_a_test_directory();
+            let mut log = Log::new(&location, 10, *mode).await.unwrap();
+            let large = vec![7; 5000];
+            log.add_batch(&batch(10, &[vec![1, 2, 3]])).await.unwrap();
+            log.add_batch(&batch(11, &[large.clone()])).await.unwrap();
+            log.add_batch(&batch(12, &[vec![4]])).await.unwrap();
+            log.truncate(28).await.unwrap();
+            log.add_batch(&batch(11, &[vec![5]])).await.unwrap();
+            drop(log);
+
+            let mut log = Log::open(&location, 10, *mode).await.unwrap();
+            log.add_batch(&batch(12, &[large])).await.unwrap();
+            log.sync().await.unwrap();
+            let batches = log.batches().await.unwrap();
+            assert_eq!(log.size(), 28 + 26 + 5025);
+            assert_eq!(files::size(&location.join("10.log")).unwrap(), log.size());
+            assert_eq!(batches[1].payload, batch::pack(&[vec![5]]));
+            assert_eq!(batches[2].base_offset, 12);
+        }
+    }
+
+    #[tokio::test]
+    async fn preallocate_log() {
+        let location = test::create_a_test_directory();
+        let id = 10;
+        let mut log = Log::new(&location, id, WriteMode::Buffered).await.unwrap();
+        log.preallocate(64 * 1024).unwrap();
+        log.add_batch(&batch(10, &[vec![1, 2, 3]])).await.unwrap();
+        log.add_batch(&batch(11, &[vec![4]])).await.unwrap();
+        log.truncate(28).await.unwrap();
 
-        let log = Log::open(&location, id).await.unwrap();
-        assert_eq!(log.position, 15);
+        assert_eq!(log.size(), 28);
+        let path = location.join("10.log");
+        assert_eq!(files::size(&path).unwrap(), 28);
+        let log = Log::open(&location, id, WriteMode::Buffered).await.unwrap();
+        assert_eq!(log.position, 28);
     }
 
     #[tokio::test]
     async fn stream_entries() {
         let id = 10;
         let location = test::create_a_test_directory();
-        let mut log = Log::new(&location, id).await.unwrap();
-        log.add_entry(10, vec![1, 2]).await.unwrap();
-        log.add_entry(11, vec![3, 4]).await.unwrap();
+        let mut log = Log::new(&location, id, WriteMode::Buffered).await.unwrap();
+        log.add_batch(&batch(10, &[vec![1, 2], vec![3, 4]]))
+            .await
+            .unwrap();
 
         let mut entries = Vec::new();
         log.stream_entries(0, 16000, &mut entries).await.unwrap();
 
+        assert_eq!(entries.len(), 33);
         assert_eq!(
-            &entries,
+            &entries[..12],
             &[
-                0, 0, 0, 0, 0, 0, 0, 10, //offset
+                0, 0, 0, 0, 0, 0, 0, 10, //base offset
+                0, 0, 0, 21, //batch size
+            ]
+        );
+        assert_eq!(
+            &entries[16..],
+            &[
+                0, //attributes
+                0, 0, 0, 2, //count
                 0, 0, 0, 2, //len
                 1, 2, //entry
-                0, 0, 0, 0, 0, 0, 0, 11, //offset
                 0, 0, 0, 2, //len
                 3, 4 //entry
             ]
         );
     }
 
+    #[tokio::test]
+    async fn read_batches() {
+        let location = test::create_a_test_directory();
+        let mut log = Log::new(&location, 10, WriteMode::Buffered).await.unwrap();
+        log.add_batch(&batch(10, &[vec![1, 2]])).await.unwrap();
+        log.add_batch(&batch(11, &[vec![3]])).await.unwrap();
+
+        let batches = log.batches().await.unwrap();
+
+        assert_eq!(
+            batches,
+            vec![batch(10, &[vec![1, 2]]), batch(11, &[vec![3]])]
+        );
+    }
+
+    #[tokio::test]
+    async fn read_at_least_one_batch() {
+        let location = test::create_a_test_directory();
+        let mut log = Log::new(&location, 10, WriteMode::Buffered).await.unwrap();
+        log.add_batch(&batch(10, &[vec![1, 2]])).await.unwrap();
+        log.add_batch(&batch(11, &[vec![3]])).await.unwrap();
+        log.add_batch(&batch(12, &[vec![4]])).await.unwrap();
+
+        assert_eq!(
+            log.read_batches(27, 1).await.unwrap(),
+            vec![batch(11, &[vec![3]])]
+        );
+        assert_eq!(log.read_batches(27, 52).await.unwrap().len(), 2);
+        assert_eq!(log.read_batches(79, 100).await.unwrap(), vec![]);
+    }
+
     #[tokio::test]
     async fn stream_partial() {
         let id = 10;
         let location = test::create_a_test_directory();
-        let mut log = Log::new(&location, id).await.unwrap();
-        log.add_entry(10, vec![1, 2]).await.unwrap();
-        log.add_entry(11, vec![3, 4]).await.unwrap();
+        let mut log = Log::new(&location, id, WriteMode::Buffered).await.unwrap();
+        log.add_batch(&batch(10, &[vec![1, 2]])).await.unwrap();
+        log.add_batch(&batch(11, &[vec![3, 4]])).await.unwrap();
 
         let mut entries = Vec::new();
-        log.stream_entries(0, 14, &mut entries).await.unwrap();
+        log.stream_entries(0, 27, &mut entries).await.unwrap();
 
-        assert_eq!(
-            &entries,
-            &[
-                0, 0, 0, 0, 0, 0, 0, 10, //offset
-                0, 0, 0, 2, //len
-                1, 2, //entry
-            ]
-        );
+        assert_eq!(entries, batch(10, &[vec![1, 2]]).encode());
     }
 }
@@ -1,34 +1,196 @@
+mod archive;
+mod batch;
+mod compaction;
+mod compression;
+mod config;
+mod directories;
+mod encryption;
 mod files;
 mod handle;
 mod index;
+mod journal;
 mod ledger;
 mod log;
+mod producer;
+mod scrubber;
 mod segment;
+mod snapshot;
+#[cfg(test)]
 mod test_util;
+mod tiering;
+mod transaction;
 mod types;
 use api::ledger_api_server::LedgerApiServer;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use service::Settings;
+use std::fmt::Display;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use tonic::transport::Server;
 
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
+    if let Err(e) = start().await {
+        eprintln!("ledgers could not start: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn start() -> Result<()> {
     println!("Starting ledgers..");
+    let value = |name| Arg::with_name(name).long(name).takes_value(true);
+    let values = |name| value(name).multiple(true).use_delimiter(true);
     let matches = App::new("Ledgers")
-        .arg(Arg::with_name("port").long("port").takes_value(true))
-        .arg(Arg::with_name("path").long("path").takes_value(true))
+        .arg(value("config").help("TOML file to read the settings from"))
+        .arg(value("bind").help("Address to listen on, such as [::1]:5678"))
+        .arg(value("port"))
+        .arg(values("path"))
+        .arg(value("placement").possible_values(&["free-space", "fewest-writers"]))
+        .arg(value("key-file"))
+        .arg(value("segment-size"))
+        .arg(value("max-entry-size"))
+        .arg(value("max-batch-size"))
+        .arg(Arg::with_name("preallocate").long("preallocate"))
+        .arg(value("journal-path"))
+        .arg(
+            value("checkpoint-interval")
+                .help("Seconds between syncing the segments and dropping the journal"),
+        )
+        .arg(value("write-mode").possible_values(&["buffered", "sync", "direct"]))
+        .arg(value("high-water-mark"))
+        .arg(value("low-water-mark"))
+        .arg(value("cold-store-path"))
+        .arg(
+            value("offload-after")
+                .help("Seconds after its last write that a sealed segment is offloaded"),
+        )
+        .arg(values("etcd").help("etcd endpoints to register the server with"))
+        .arg(value("advertise").help("Address clients reach the server on"))
+        .arg(value("node-id"))
         .get_matches();
 
-    let port = matches.value_of("port").unwrap_or("5678");
-    let path = matches
-        .value_of("path")
-        .unwrap_or("./target/default_ledgers");
-    let path = PathBuf::from(path);
-    let addr = format!("[::1]:{}", port);
-    let service = LedgerApiServer::new(service::new(path, 1000));
+    let mut settings = match matches.value_of("config") {
+        Some(path) => Settings::load(&PathBuf::from(path))?,
+        None => Settings::default(),
+    };
+    override_settings(&mut settings, &matches)?;
+    settings.validate()?;
+
+    let (storage, limits) = (&settings.storage, &settings.limits);
+    let path = storage.paths[0].clone();
+    let service = match &settings.server.key_file {
+        Some(key_file) => service::with_key_file(path, storage.segment_size, key_file)?,
+        None => service::new(path, storage.segment_size),
+    };
+    let service = service
+        .with_data_dirs(storage.paths.clone(), storage.placement)
+        .with_water_marks(storage.high_water_mark, storage.low_water_mark)?
+        .with_limits(limits.max_entry_size, limits.max_batch_size)
+        .with_preallocation(storage.preallocate)
+        .with_write_mode(storage.write_mode);
+    let service = match &storage.journal_path {
+        Some(location) => {
+            let interval = settings.checkpoint_interval();
+            service.with_journal(location, interval).await?
+        }
+        None => service,
+    };
+    let service = match &storage.cold_store_path {
+        Some(location) => {
+            let min_age = Duration::from_secs(storage.offload_after_secs);
+            service.with_cold_store(location, min_age)?
+        }
+        None => service,
+    };
+    let service = service.open_ledgers().await?;
+    let service = LedgerApiServer::new(service);
+    service::register(&settings.cluster).await?;
     Server::builder()
         .add_service(service)
-        .serve(addr.parse()?)
+        .serve(settings.bind_address()?)
         .await?;
     Ok(())
 }
+
+/// Applies the flags given on the command line over the settings of the file.
+fn override_settings(settings: &mut Settings, matches: &ArgMatches) -> Result<()> {
+    if let Some(bind) = matches.value_of("bind") {
+        settings.server.bind = bind.to_owned();
+    }
+    if let Some(port) = flag(matches, "port")? {
+        let mut addr = settings.bind_address()?;
+        addr.set_port(port);
+        settings.server.bind = addr.to_string();
+    }
+    let (server, storage) = (&mut settings.server, &mut settings.storage);
+    if let Some(key_file) = matches.value_of("key-file") {
+        server.key_file = Some(PathBuf::from(key_file));
+    }
+    if let Some(paths) = matches.values_of("path") {
+        storage.paths = paths.map(PathBuf::from).collect();
+    }
+    set(&mut storage.placement, flag(matches, "placement")?);
+    set(&mut storage.segment_size, flag(matches, "segment-size")?);
+    set(&mut storage.write_mode, flag(matches, "write-mode")?);
+    storage.preallocate |= matches.is_present("preallocate");
+    if let Some(location) = matches.value_of("journal-path") {
+        storage.journal_path = Some(PathBuf::from(location));
+    }
+    set(
+        &mut storage.checkpoint_interval_secs,
+        flag(matches, "checkpoint-interval")?,
+    );
+    set(
+        &mut storage.high_water_mark,
+        flag(matches, "high-water-mark")?,
+    );
+    set(
+        &mut storage.low_water_mark,
+        flag(matches, "low-water-mark")?,
+    );
+    if let Some(location) = matches.value_of("cold-store-path") {
+        storage.cold_store_path = Some(PathBuf::from(location));
+    }
+    set(
+        &mut storage.offload_after_secs,
+        flag(matches, "offload-after")?,
+    );
+    let limits = &mut settings.limits;
+    set(&mut limits.max_entry_size, flag(matches, "max-entry-size")?);
+    set(&mut limits.max_batch_size, flag(matches, "max-batch-size")?);
+    let cluster = &mut settings.cluster;
+    if let Some(endpoints) = matches.values_of("etcd") {
+        cluster.etcd_endpoints = endpoints.map(str::to_owned).collect();
+    }
+    if let Some(advertise) = matches.value_of("advertise") {
+        cluster.advertise = Some(advertise.to_owned());
+    }
+    if let Some(node_id) = matches.value_of("node-id") {
+        cluster.node_id = Some(node_id.to_owned());
+    }
+    Ok(())
+}
+
+fn set<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+/// The value of a flag, naming the flag when it does not parse.
+fn flag<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match matches.value_of(name) {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(format!("invalid --{} {:?}: {}", name, value, e).into()),
+        },
+        None => Ok(None),
+    }
+}
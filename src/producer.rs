@@ -0,0 +1,218 @@
+use crate::files;
+use crate::files::*;
+use crate::types::{Error, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::ops::Range;
+
+/// The file in a ledger directory the registered producer ids are kept in, one
+/// id (u64) after the other.
+const REGISTERED: &str = "producers";
+const SNAPSHOT_ENTRY_SIZE: usize = 32;
+
+/// The producer of a batch and its sequence number, which grows by one with every batch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Producer {
+    pub id: u64,
+    pub sequence: u64,
+}
+
+/// The last sequence written by each producer of a ledger, with the offsets it got,
+/// and the producer ids handed out for the ledger.
+#[derive(Default)]
+pub struct Producers {
+    sequences: HashMap<u64, (u64, Range<u64>)>,
+    registered: HashSet<u64>,
+    location: Option<PathBuf>,
+}
+
+impl Producers {
+    /// The producers registered for the ledger stored at `location`. A torn id at
+    /// the end of the file is cut off, as it was never handed out.
+    pub fn open(location: &Path) -> Result<Producers> {
+        let path = location.join(REGISTERED);
+        let mut ids = if path.exists() {
+            files::read(&path)?
+        } else {
+            vec![]
+        };
+        let whole = ids.len() - ids.len() % 8;
+        if whole < ids.len() {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(whole as u64)?;
+            ids.truncate(whole);
+        }
+        Ok(Producers {
+            sequences: HashMap::new(),
+            registered: ids.chunks(8).map(BigEndian::read_u64).collect(),
+            location: Some(location.to_owned()),
+        })
+    }
+
+    /// Hands out a new producer id, never 0 which stands for no producer nor one
+    /// handed out before. The id is on disk once it is returned.
+    pub fn register(&mut self) -> Result<u64> {
+        let id = loop {
+            let id = rand::random();
+            if id != 0 && !self.registered.contains(&id) && !self.sequences.contains_key(&id) {
+                break id;
+            }
+        };
+        if let Some(location) = &self.location {
+            let path = location.join(REGISTERED);
+            let created = !path.exists();
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            file.write_all(&id.to_be_bytes())?;
+            file.sync_data()?;
+            if created {
+                files::sync_dir(location)?;
+            }
+        }
+        self.registered.insert(id);
+        Ok(id)
+    }
+
+    /// Returns the offsets of the batch if it was the last one written. Older batches
+    /// fail with `DuplicateSequence`, as their offsets are no longer known. A registered
+    /// producer seen for the first time may start at any sequence, after that each
+    /// batch must be the next one. Ids never handed out fail with `UnknownProducer`.
+    pub fn duplicate(&self, producer: &Producer) -> Result<Option<Range<u64>>> {
+        match self.sequences.get(&producer.id) {
+            Some((last, offsets)) if producer.sequence == *last => Ok(Some(offsets.clone())),
+            Some((last, _)) if producer.sequence < *last => {
+                Err(Error::DuplicateSequence(producer.id, producer.sequence))
+            }
+            Some((last, _)) if producer.sequence != last + 1 => {
+                Err(Error::OutOfOrderSequence(producer.id, last + 1))
+            }
+            Some(_) => Ok(None),
+            None if self.registered.contains(&producer.id) => Ok(None),
+            None => Err(Error::UnknownProducer(producer.id)),
+        }
+    }
+
+    /// Whether batches from the producer `id` are taken.
+    pub fn is_known(&self, id: u64) -> bool {
+        self.registered.contains(&id) || self.sequences.contains_key(&id)
+    }
+
+    /// Takes batches from the producer `id`, which was registered with another
+    /// ledger, for as long as the ledger is open. Its batches keep it known after.
+    pub fn admit(&mut self, id: u64) {
+        self.registered.insert(id);
+    }
+
+    pub fn record(&mut self, producer: &Producer, offsets: Range<u64>) {
+        match self.sequences.get(&producer.id) {
+            Some((last, _)) if *last > producer.sequence => {}
+            _ => {
+                self.sequences
+                    .insert(producer.id, (producer.sequence, offsets));
+            }
+        }
+    }
+}
+
+/// Keeps the last batch of every producer among `batches` in the snapshot at
+/// `path`, as producer id (u64) | sequence (u64) | first offset (u64) | next offset (u64)
+/// per producer.
+pub fn write_snapshot(path: &Path, batches: &[(Producer, Range<u64>)]) -> Result<()> {
+    let mut producers = Producers::default();
+    for (producer, offsets) in batches {
+        producers.record(producer, offsets.clone());
+    }
+    let mut bytes = Vec::with_capacity(producers.sequences.len() * SNAPSHOT_ENTRY_SIZE);
+    for (id, (sequence, offsets)) in producers.sequences {
+        for value in &[id, sequence, offsets.start, offsets.end] {
+            bytes.extend(value.to_be_bytes().iter());
+        }
+    }
+    files::write_atomic(path, &bytes)?;
+    Ok(())
+}
+
+/// The producers in the snapshot of segment `id` at `path`, see `write_snapshot`.
+pub fn read_snapshot(path: &Path, id: u64) -> Result<Vec<(Producer, Range<u64>)>> {
+    let bytes = files::read(path)?;
+    if bytes.len() % SNAPSHOT_ENTRY_SIZE != 0 {
+        return Err(Error::CorruptSegmentMetadata(id));
+    }
+    Ok(bytes
+        .chunks(SNAPSHOT_ENTRY_SIZE)
+        .map(|entry| {
+            let producer = Producer {
+                id: BigEndian::read_u64(entry),
+                sequence: BigEndian::read_u64(&entry[8..]),
+            };
+            let offsets = BigEndian::read_u64(&entry[16..])..BigEndian::read_u64(&entry[24..]);
+            (producer, offsets)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util as test;
+
+    fn producer(sequence: u64) -> Producer {
+        Producer { id: 7, sequence }
+    }
+
+    #[test]
+    fn detect_duplicates() {
+        let mut producers = Producers::default();
+        let error = producers.duplicate(&producer(5)).unwrap_err();
+        assert!(error.is_unknown_producer());
+        producers.admit(7);
+        assert_eq!(producers.duplicate(&producer(5)).unwrap(), None);
+        producers.record(&producer(5), 10..12);
+
+        assert_eq!(producers.duplicate(&producer(5)).unwrap(), Some(10..12));
+        let error = producers.duplicate(&producer(4)).unwrap_err();
+        assert!(error.is_duplicate_sequence());
+        assert_eq!(producers.duplicate(&producer(6)).unwrap(), None);
+        assert!(producers.duplicate(&producer(8)).is_err());
+        assert_ne!(producers.register().unwrap(), 0);
+    }
+
+    #[test]
+    fn keep_registered_producers() {
+        let location = test::create_a_test_directory();
+        let mut producers = Producers::open(&location).unwrap();
+        let first = producers.register().unwrap();
+        let second = producers.register().unwrap();
+        let path = location.join(REGISTERED);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+
+        let producers = Producers::open(&location).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(
+            producers.registered,
+            vec![first, second].into_iter().collect()
+        );
+        assert_eq!(files::size(&path).unwrap(), 16);
+    }
+
+    #[test]
+    fn snapshot_last_batch_per_producer() {
+        let path = test::create_a_test_directory().join("0.producers");
+        let other = Producer { id: 9, sequence: 0 };
+        let batches = vec![(producer(1), 0..2), (other, 2..3), (producer(2), 3..4)];
+
+        write_snapshot(&path, &batches).unwrap();
+
+        let mut snapshot = read_snapshot(&path, 0).unwrap();
+        snapshot.sort_by_key(|(producer, _)| producer.id);
+        assert_eq!(snapshot, vec![(producer(2), 3..4), (other, 2..3)]);
+    }
+}
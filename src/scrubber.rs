@@ -0,0 +1,326 @@
+use crate::batch::{self, Batch};
+use crate::files;
+use crate::files::BLOCK_SIZE;
+use crate::ledger::LedgerRepository;
+use crate::types::{Error, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const SCRUB_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SEGMENT_PAUSE: Duration = Duration::from_millis(100); // between segments, to leave room for clients
+pub const CHUNK_PAUSE: Duration = Duration::from_millis(10); // between chunks of a log
+const CHUNK_SIZE: usize = 1 << 20;
+const INDEX_ENTRY_SIZE: usize = 16;
+
+/// What the scrubber found since the server started.
+#[derive(Default)]
+pub struct ScrubStats {
+    pub passes: AtomicU64,
+    pub segments: AtomicU64,
+    pub bytes: AtomicU64,
+    pub corrupt: AtomicU64,
+}
+
+/// Checks the log and index of the sealed segment `id` at `path` against each
+/// other: every batch has to pass its crc and follow the one before it, the log
+/// must end with the last batch, and every index entry has to point at the batch
+/// it names. The zero padding a direct write leaves after the last batch and
+/// index entry, less than a block, is allowed. Reads the log a chunk at a time, pausing for `pause` after each one,
+/// and does blocking I/O. Returns the number of bytes read.
+pub fn verify_files(id: u64, base_offset: u64, path: &Path, pause: Duration) -> Result<u64> {
+    let index = files::read(&path.with_extension("index"))?;
+    let mut verifier = Verifier::new(id, base_offset, &index)?;
+    let mut log = fs::File::open(path.with_extension("log"))?;
+    let (mut pending, mut chunk) = (Vec::new(), vec![0; CHUNK_SIZE]);
+    let mut bytes = index.len() as u64;
+    loop {
+        let read = log.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        bytes += read as u64;
+        pending.extend_from_slice(&chunk[..read]);
+        let verified = verifier.add(&pending)?;
+        pending.drain(..verified);
+        thread::sleep(pause);
+    }
+    verifier.finish(&pending)?;
+    Ok(bytes)
+}
+
+/// Checks the batches of a log as they are read against the index.
+struct Verifier<'a> {
+    id: u64,
+    entries: std::slice::Chunks<'a, u8>,
+    indexed: usize,
+    batches: usize,
+    position: u64,
+    next_offset: u64,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(id: u64, base_offset: u64, index: &'a [u8]) -> Result<Verifier<'a>> {
+        if !index.len().is_multiple_of(INDEX_ENTRY_SIZE) {
+            return corrupt(id, "index has a partial entry".to_owned());
+        }
+        Ok(Verifier {
+            id,
+            entries: index.chunks(INDEX_ENTRY_SIZE),
+            indexed: index.len() / INDEX_ENTRY_SIZE,
+            batches: 0,
+            position: 0,
+            next_offset: base_offset,
+        })
+    }
+
+    /// Checks the whole batches at the start of `log`, returning their size. Stops
+    /// at padding, which is left for `finish`.
+    fn add(&mut self, log: &[u8]) -> Result<usize> {
+        let batches = match Batch::decode(&log[..unpadded(log)]) {
+            Ok(batches) => batches,
+            Err(Error::CorruptBatch(offset)) => {
+                let reason = format!("batch at offset {} fails its crc", offset);
+                return corrupt(self.id, reason);
+            }
+            Err(e) => return Err(e),
+        };
+        let mut read = 0;
+        for batch in batches {
+            self.check(&batch)?;
+            read += batch.size();
+        }
+        Ok(read)
+    }
+
+    fn check(&mut self, batch: &Batch) -> Result<()> {
+        self.batches += 1;
+        if batch.base_offset < self.next_offset {
+            let reason = format!("batch at offset {} is out of order", batch.base_offset);
+            return corrupt(self.id, reason);
+        }
+        if let Some(entry) = self.entries.next() {
+            let (last_offset, entry_position) =
+                (BigEndian::read_u64(entry), BigEndian::read_u64(&entry[8..]));
+            if last_offset != batch.last_offset() || entry_position != self.position {
+                let entry = format!(
+                    "index entry for offset {} at {}",
+                    last_offset, entry_position
+                );
+                let batch = format!(
+                    "batch ending at {} at {}",
+                    batch.last_offset(),
+                    self.position
+                );
+                return corrupt(self.id, format!("{} does not match the {}", entry, batch));
+            }
+        }
+        self.position += batch.size() as u64;
+        self.next_offset = batch.last_offset() + 1;
+        Ok(())
+    }
+
+    /// Checks the index against the batches found, with the `trailing` bytes of
+    /// the log left after the last whole one.
+    fn finish(mut self, trailing: &[u8]) -> Result<()> {
+        let left = self.entries.len();
+        let indexed = match self.entries.all(is_zero) {
+            true if left * INDEX_ENTRY_SIZE < BLOCK_SIZE => self.indexed - left,
+            _ => self.indexed,
+        };
+        if indexed != self.batches {
+            let found = format!("{} index entries for {} batches", indexed, self.batches);
+            return corrupt(self.id, found);
+        }
+        if trailing.len() >= BLOCK_SIZE || !is_zero(trailing) {
+            return corrupt(
+                self.id,
+                format!("log has {} bytes after its last batch", trailing.len()),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The length of `log` up to the padding of a direct write, which starts with a
+/// batch header of zeros as no batch is empty.
+fn unpadded(log: &[u8]) -> usize {
+    let mut position = 0;
+    while position + batch::HEADER_SIZE <= log.len() {
+        if is_zero(&log[position..position + batch::HEADER_SIZE]) {
+            return position;
+        }
+        let size = BigEndian::read_u32(&log[position + 8..]) as usize;
+        position += batch::HEADER_SIZE + size;
+    }
+    log.len()
+}
+
+fn is_zero(bytes: &[u8]) -> bool {
+    bytes.iter().all(|b| *b == 0)
+}
+
+fn corrupt<T>(id: u64, reason: String) -> Result<T> {
+    Err(Error::CorruptSegment(id, reason))
+}
+
+/// Scrubs every sealed segment once, one at a time.
+pub async fn scrub(repository: &LedgerRepository, stats: &ScrubStats) {
+    for (id, segment_id) in repository.sealed_segments().await {
+        match repository.scrub(&id, segment_id).await {
+            Ok(bytes) => {
+                stats.bytes.fetch_add(bytes, Ordering::SeqCst);
+            }
+            Err(e) if e.is_corrupt_segment() => {
+                println!("quarantined segment of ledger {}: {}", id, e);
+                stats.corrupt.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(e) => println!(
+                "scrubbing segment {} of ledger {} failed: {}",
+                segment_id, id, e
+            ),
+        }
+        stats.segments.fetch_add(1, Ordering::SeqCst);
+        tokio::time::delay_for(SEGMENT_PAUSE).await;
+    }
+    stats.passes.fetch_add(1, Ordering::SeqCst);
+}
+
+pub async fn run(repository: LedgerRepository, stats: Arc<ScrubStats>) {
+    let mut interval = tokio::time::interval(SCRUB_INTERVAL);
+    loop {
+        interval.tick().await;
+        scrub(&repository, &stats).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LedgerConfig;
+    use crate::files;
+    use crate::files::WriteMode;
+    use crate::test_util as test;
+
+    fn verify(id: u64, base_offset: u64, log: &[u8], index: &[u8]) -> Result<()> {
+        let mut verifier = Verifier::new(id, base_offset, index)?;
+        let read = verifier.add(log)?;
+        verifier.finish(&log[read..])
+    }
+
+    #[tokio::test]
+    async fn quarantine_corrupt_segment() {
+        let location = test::create_a_test_directory();
+        let repository = LedgerRepository::new(None);
+        let id = repository
+            .create(&location, LedgerConfig::new(1000))
+            .await
+            .unwrap();
+        repository
+            .add(&id, 0, vec![vec![1], vec![2]])
+            .await
+            .unwrap();
+        repository.add(&id, 0, vec![vec![3]]).await.unwrap();
+        repository.add(&id, 1, vec![vec![4]]).await.unwrap();
+        let stats = ScrubStats::default();
+
+        scrub(&repository, &stats).await;
+        assert_eq!(stats.segments.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.corrupt.load(Ordering::SeqCst), 0);
+        let log = location.join(&id).join("0.log");
+        let mut bytes = files::read(&log).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        files::write(&log, &bytes).unwrap();
+
+        scrub(&repository, &stats).await;
+        assert_eq!(stats.corrupt.load(Ordering::SeqCst), 1);
+        let quarantined = repository.quarantined().await;
+        assert_eq!(
+            (quarantined[0].0.as_str(), quarantined[0].1),
+            (id.as_str(), 0)
+        );
+        assert!(repository.sealed_segments().await.is_empty());
+        assert!(location.join(&id).join("0.quarantine").exists());
+    }
+
+    #[tokio::test]
+    async fn scrub_sealed_direct_segment() {
+        let location = test::create_a_test_directory();
+        let repository = LedgerRepository::new(None);
+        let config = LedgerConfig {
+            write_mode: WriteMode::Direct,
+            ..LedgerConfig::new(1000)
+        };
+        let id = repository.create(&location, config).await.unwrap();
+        repository
+            .add(&id, 0, vec![vec![1], vec![2]])
+            .await
+            .unwrap();
+        repository.add(&id, 2, vec![vec![3]]).await.unwrap();
+        let log = location.join(&id).join("0.log");
+        assert_eq!(files::size(&log).unwrap(), BLOCK_SIZE as u64);
+        let stats = ScrubStats::default();
+
+        scrub(&repository, &stats).await;
+        assert_eq!(stats.segments.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.corrupt.load(Ordering::SeqCst), 0);
+        assert!(repository.quarantined().await.is_empty());
+    }
+
+    #[test]
+    fn verify_batches_across_chunks() {
+        let location = test::create_a_test_directory();
+        let batch = |base_offset, payload| Batch {
+            base_offset,
+            attributes: 0,
+            count: 1,
+            producer: None,
+            payload,
+        };
+        let (first, second) = (batch(0, vec![1; CHUNK_SIZE * 3 / 2]), batch(1, vec![2]));
+        let mut log = first.encode();
+        log.extend(second.encode());
+        let mut index = vec![0; 32];
+        BigEndian::write_u64(&mut index[16..], 1);
+        BigEndian::write_u64(&mut index[24..], first.size() as u64);
+        let path = location.join("0");
+        files::write(&path.with_extension("log"), &log).unwrap();
+        files::write(&path.with_extension("index"), &index).unwrap();
+
+        let bytes = verify_files(0, 0, &path, Duration::from_secs(0)).unwrap();
+        assert_eq!(bytes, (log.len() + index.len()) as u64);
+        files::write(&path.with_extension("log"), &log[..log.len() - 1]).unwrap();
+        let error = verify_files(0, 0, &path, Duration::from_secs(0)).unwrap_err();
+        assert!(error.is_corrupt_segment());
+    }
+
+    #[test]
+    fn find_index_pointing_elsewhere() {
+        let batch = |base_offset| Batch {
+            base_offset,
+            attributes: 0,
+            count: 1,
+            producer: None,
+            payload: vec![1],
+        };
+        let mut log = batch(5).encode();
+        log.extend(batch(6).encode());
+        let mut index = vec![0; 32];
+        BigEndian::write_u64(&mut index, 5);
+        BigEndian::write_u64(&mut index[16..], 6);
+        BigEndian::write_u64(&mut index[24..], batch(5).size() as u64);
+
+        assert!(verify(0, 5, &log, &index).is_ok());
+        assert!(verify(0, 5, &log, &index[..16]).is_err());
+        assert!(verify(0, 5, &log[..log.len() - 1], &index).is_err());
+        BigEndian::write_u64(&mut index[24..], 0);
+        let error = verify(0, 5, &log, &index).unwrap_err();
+        assert!(error.is_corrupt_segment());
+    }
+}
@@ -1,28 +1,220 @@
+use crate::batch::Batch;
+use crate::compaction;
+use crate::compression::Codec;
+use crate::encryption::{Cipher, LedgerKeys};
 use crate::files;
 use crate::files::*;
 use crate::handle::*;
-use crate::types::Result;
-use std::collections::HashMap;
+use crate::journal::{Journal, SegmentJournal};
+use crate::producer::{self, Producer};
+use crate::scrubber;
+use crate::snapshot::{self, SegmentState};
+use crate::tiering::ColdStore;
+use crate::transaction::{Marker, TransactionIndex};
+use crate::types::{Error, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::AsyncWrite;
 
 pub struct Segments {
     location: PathBuf,
+    codec: Codec,
+    keys: Option<LedgerKeys>,
     map: HashMap<u64, Segment>,
+    /// The segment ids by base offset. Empty segments share the base offset of the
+    /// segment after them, which then takes their place.
+    offsets: BTreeMap<u64, u64>,
+    preallocate: u64,
+    write_mode: WriteMode,
+    journal: Option<(Journal, String)>,
+    cold_store: Option<Arc<dyn ColdStore>>,
 }
 
 impl Segments {
-    pub fn open(location: PathBuf) -> Result<Segments> {
-        let ids = files::list_files_as_u64(&location, "index")?;
-        let mut map = HashMap::new();
+    pub fn open(location: PathBuf, codec: Codec, keys: Option<LedgerKeys>) -> Result<Segments> {
+        compaction::recover(&location)?;
+        Segments::load(location, codec, keys)
+    }
+
+    /// Opens the segments for reading only, leaving the files as they are on disk,
+    /// an interrupted compaction included.
+    pub fn open_read_only(
+        location: PathBuf,
+        codec: Codec,
+        keys: Option<LedgerKeys>,
+    ) -> Result<Segments> {
+        let mut segments = Segments::load(location, codec, keys)?;
+        for segment in segments.map.values_mut() {
+            segment.read_only = true;
+        }
+        Ok(segments)
+    }
+
+    fn load(location: PathBuf, codec: Codec, keys: Option<LedgerKeys>) -> Result<Segments> {
+        let mut ids = files::list_files_as_u64(&location, "segment")?;
+        ids.extend(files::list_files_as_u64(&location, "index")?);
+        ids.extend(files::list_files_as_u64(&location, "remote")?);
+        ids.sort();
+        ids.dedup();
+        let (mut map, mut offsets) = (HashMap::new(), BTreeMap::new());
         for id in ids {
-            map.insert(id, Segment::new(location.to_owned(), id));
+            let base_offset = read_base_offset(&location, id)?;
+            let mut segment =
+                Segment::new(location.to_owned(), id, base_offset, codec, keys.clone());
+            segment.size = files::size(&location.join(id.to_string()).with_extension("log"))?;
+            segment.quarantine = read_quarantine(&location, id)?;
+            segment.offloaded = read_offloaded(&location, id)?;
+            map.insert(id, segment);
+            let latest = offsets.entry(base_offset).or_insert(id);
+            *latest = id.max(*latest);
+        }
+        Ok(Segments {
+            location,
+            codec,
+            keys,
+            map,
+            offsets,
+            preallocate: 0,
+            write_mode: WriteMode::Buffered,
+            journal: None,
+            cold_store: None,
+        })
+    }
+
+    /// Reserves disk space for `bytes` of batches whenever a segment is started.
+    pub fn with_preallocation(mut self, bytes: u64) -> Segments {
+        self.set_preallocation(bytes);
+        self
+    }
+
+    pub fn set_preallocation(&mut self, bytes: u64) {
+        for segment in self.map.values_mut() {
+            segment.preallocate = bytes;
+        }
+        self.preallocate = bytes;
+    }
+
+    /// The directory of the ledger the segments belong to.
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Journals the batches added to the segments of the ledger from now on.
+    pub fn set_journal(&mut self, journal: Journal, ledger_id: &str) {
+        for segment in self.map.values_mut() {
+            segment.set_journal(journal.clone(), ledger_id);
+        }
+        self.journal = Some((journal, ledger_id.to_owned()));
+    }
+
+    /// Offloads segments to `store` from now on, and fetches them back from it.
+    pub fn set_cold_store(&mut self, store: Arc<dyn ColdStore>) {
+        for segment in self.map.values_mut() {
+            segment.cold_store = Some(store.clone());
+        }
+        self.cold_store = Some(store);
+    }
+
+    /// Offloads the sealed segments last written to more than `min_age` ago, and
+    /// drops the local copies of offloaded segments last read more than `min_age`
+    /// ago. Returns the number of segments offloaded.
+    pub async fn offload(&mut self, min_age: Duration) -> Result<usize> {
+        if self.cold_store.is_none() {
+            return Ok(0);
+        }
+        let mut offloaded = 0;
+        for id in self.sealed() {
+            let segment = self.map.get_mut(&id).unwrap();
+            if segment.offloaded.is_some() {
+                if segment.unread_for(min_age) {
+                    segment.evict()?;
+                }
+            } else if segment.is_local() && segment.age()? >= min_age {
+                segment.offload().await?;
+                offloaded += 1;
+            }
+        }
+        Ok(offloaded)
+    }
+
+    /// Syncs the open segments to disk.
+    pub async fn sync(&mut self) -> Result<()> {
+        for segment in self.map.values_mut() {
+            segment.sync().await?;
         }
-        Ok(Segments { location, map })
+        Ok(())
+    }
+
+    /// Syncs the segments and takes the snapshot `name` of them, see `snapshot::take`.
+    /// Returns where the snapshot is.
+    pub async fn snapshot(&mut self, name: &str) -> Result<PathBuf> {
+        let target = snapshot::path(&self.location, name)?;
+        self.sync().await?;
+        let mut states = Vec::new();
+        for segment in self.map.values() {
+            states.push(SegmentState {
+                id: segment.id,
+                log_bytes: segment.size(),
+                index_bytes: segment.index_size()?,
+            });
+        }
+        snapshot::take(&self.location, &target, &states)?;
+        Ok(target)
+    }
+
+    /// Writes the segments in `mode` once they are next opened.
+    pub fn with_write_mode(mut self, mode: WriteMode) -> Segments {
+        for segment in self.map.values_mut() {
+            segment.write_mode = mode;
+        }
+        self.write_mode = mode;
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// Switches to a new data key, which only new segments pick up as the
+    /// existing ones keep the key stored next to them.
+    pub fn rotate_keys(&mut self) -> Result<()> {
+        if let Some(keys) = &self.keys {
+            let keys = keys.rotate(&self.location)?;
+            for segment in self.map.values_mut() {
+                segment.keys = Some(keys.clone());
+            }
+            self.keys = Some(keys);
+        }
+        Ok(())
     }
 
+    /// Creates a segment starting at the next offset of the ledger.
     pub async fn create(&mut self, id: u64) -> Result<&mut Segment> {
-        let segment = Segment::new(self.location.to_owned(), id);
+        let base_offset = self.next_offset().await?;
+        self.create_at(id, base_offset).await
+    }
+
+    /// Creates a segment starting at `base_offset`, which may leave a gap after
+    /// the next offset of the ledger. The segment before it is sealed.
+    pub async fn create_at(&mut self, id: u64, base_offset: u64) -> Result<&mut Segment> {
+        if let Some(latest) = self.ids().last() {
+            self.map.get_mut(latest).unwrap().seal().await?;
+        }
+        write_base_offset(&self.location, id, base_offset)?;
+        let location = self.location.to_owned();
+        let mut segment = Segment::new(location, id, base_offset, self.codec, self.keys.clone());
+        segment.preallocate = self.preallocate;
+        segment.write_mode = self.write_mode;
+        if let Some((journal, ledger_id)) = &self.journal {
+            segment.set_journal(journal.clone(), ledger_id);
+        }
+        segment.cold_store = self.cold_store.clone();
         self.map.insert(id, segment);
+        self.offsets.insert(base_offset, id);
         Ok(self.map.get_mut(&id).unwrap())
     }
 
@@ -30,12 +222,60 @@ impl Segments {
         self.map.get(&id)
     }
 
-    pub fn create_if_absent(&mut self, id: u64) -> &mut Segment {
-        if !self.map.contains_key(&id) {
-            let segment = Segment::new(self.location.to_owned(), id);
-            self.map.insert(id, segment);
+    /// The segment to add entries to, which is the latest one. A higher id starts
+    /// a new segment, lower ones are sealed.
+    pub async fn writable(&mut self, id: u64) -> Result<&mut Segment> {
+        match self.ids().last() {
+            Some(latest) if id < *latest => Err(Error::SegmentSealed(id)),
+            Some(latest) if id == *latest => Ok(self.map.get_mut(&id).unwrap()),
+            _ => self.create(id).await,
+        }
+    }
+
+    /// The id of the segment holding `offset`, which is the last one starting at or before it.
+    pub fn find(&self, offset: u64) -> Option<u64> {
+        self.offsets.range(..=offset).next_back().map(|(_, id)| *id)
+    }
+
+    /// Fetches back the first offloaded segment a read of `bytes` from `offset` may
+    /// reach, see `Segment::fetcher`. The segments on the way count for their whole
+    /// log, so that every segment the read touches ends up fetched.
+    pub fn fetcher(
+        &mut self,
+        offset: u64,
+        bytes: usize,
+    ) -> Option<impl FnOnce() -> Result<()> + Send + 'static> {
+        let first = self.find(offset)?;
+        let mut remaining = bytes as u64;
+        for id in self.ids().into_iter().filter(|id| *id >= first) {
+            let segment = self.map.get_mut(&id).unwrap();
+            if let Some(fetch) = segment.fetcher() {
+                return Some(fetch);
+            }
+            remaining = remaining.saturating_sub(segment.local_size());
+            if remaining == 0 {
+                break;
+            }
+        }
+        None
+    }
+
+    /// The first offset of the ledger, which is the base offset of its earliest segment.
+    pub fn first_offset(&self) -> u64 {
+        self.offsets.keys().next().cloned().unwrap_or(0)
+    }
+
+    /// The id of the segment following the one starting at `base_offset`.
+    pub fn following(&self, base_offset: u64) -> Option<u64> {
+        let mut later = self.offsets.range(base_offset + 1..);
+        later.next().map(|(_, id)| *id)
+    }
+
+    pub async fn next_offset(&mut self) -> Result<u64> {
+        match self.ids().last() {
+            Some(latest) => self.map.get_mut(latest).unwrap().next_offset().await,
+            None => Ok(0),
         }
-        self.map.get_mut(&id).unwrap()
     }
 
     pub fn get_mut(&mut self, id: u64) -> Option<&mut Segment> {
@@ -45,48 +285,539 @@ impl Segments {
     pub fn len(&self) -> usize {
         self.map.len()
     }
+
+    /// The ids of the segments no longer written to, except for quarantined ones.
+    pub fn sealed(&self) -> Vec<u64> {
+        let ids = self.ids();
+        let sealed = &ids[..ids.len().saturating_sub(1)];
+        sealed
+            .iter()
+            .filter(|id| self.map[id].quarantined().is_none())
+            .cloned()
+            .collect()
+    }
+
+    pub fn ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.map.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// The transaction markers of segment `id` and of as many following segments as
+    /// it takes to see how the transactions open in it ended, as transactions end in
+    /// the segment active when they are settled.
+    pub async fn transactions(&mut self, id: u64) -> Result<TransactionIndex> {
+        let mut transactions = TransactionIndex::default();
+        for id in self.ids().into_iter().filter(|other| *other >= id) {
+            let segment = self.map.get_mut(&id).unwrap();
+            transactions.extend(&segment.transactions().await?);
+            if transactions.stable_offset().is_none() {
+                break;
+            }
+        }
+        Ok(transactions)
+    }
+
+    /// The committed entries of segment `id`.
+    pub async fn entries(&mut self, id: u64) -> Result<Vec<(u64, Vec<u8>)>> {
+        let transactions = self.transactions(id).await?;
+        match self.map.get_mut(&id) {
+            Some(segment) => segment.entries(&transactions).await,
+            None => Ok(vec![]),
+        }
+    }
 }
 
 pub struct Segment {
     pub id: u64,
+    pub base_offset: u64,
     location: PathBuf,
+    codec: Codec,
+    keys: Option<LedgerKeys>,
+    preallocate: u64,
+    write_mode: WriteMode,
+    read_only: bool,
+    journal: Option<SegmentJournal>,
+    size: u64,
+    quarantine: Option<String>,
+    /// The next offset of a segment moved to the cold store.
+    offloaded: Option<u64>,
+    cold_store: Option<Arc<dyn ColdStore>>,
+    /// When the local copy of an offloaded segment was last read.
+    last_read: Option<Instant>,
     handle: Option<Handle>,
 }
 
 impl Segment {
-    pub fn new(location: PathBuf, id: u64) -> Segment {
+    pub fn new(
+        location: PathBuf,
+        id: u64,
+        base_offset: u64,
+        codec: Codec,
+        keys: Option<LedgerKeys>,
+    ) -> Segment {
         Segment {
             id,
-            location: location,
+            base_offset,
+            location,
+            codec,
+            keys,
+            preallocate: 0,
+            write_mode: WriteMode::Buffered,
+            read_only: false,
+            journal: None,
+            size: 0,
+            quarantine: None,
+            offloaded: None,
+            cold_store: None,
+            last_read: None,
             handle: None,
         }
     }
 
-    pub async fn add(&mut self, entries: Vec<Vec<u8>>) -> Result<()> {
+    fn cipher(&self) -> Result<Option<Cipher>> {
+        let (location, id) = (&self.location, self.id);
+        match &self.keys {
+            Some(keys) if self.read_only => Ok(Some(keys.segment_cipher_read_only(location, id)?)),
+            Some(keys) => Ok(Some(keys.segment_cipher(location, id)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Drops the handle, keeping the size of the log it tracked as `size`.
+    fn close(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.size = handle.log_size();
+        }
+    }
+
+    async fn handle(&mut self) -> Result<&mut Handle> {
+        if self.quarantine.is_some() {
+            return Err(Error::SegmentQuarantined(self.id));
+        }
+        if self.offloaded.is_some() {
+            self.last_read = Some(Instant::now());
+            if !self.is_local() {
+                let fetch = self.fetcher().ok_or(Error::SegmentOffloaded(self.id))?;
+                blocking(fetch).await?;
+            }
+        }
         if self.handle.is_none() {
-            self.handle = Some(Handle::new(&self.location, self.id).await?)
+            let (location, id, codec) = (&self.location, self.id, self.codec);
+            let (base_offset, cipher) = (self.base_offset, self.cipher()?);
+            let mode = self.write_mode;
+            let handle = if self.read_only {
+                Handle::open_read_only(location, id, base_offset, codec, cipher).await?
+            } else if Handle::exists(location, id) {
+                Handle::open(location, id, base_offset, codec, cipher, mode).await?
+            } else {
+                let mut handle =
+                    Handle::new(location, id, base_offset, codec, cipher, mode).await?;
+                if self.preallocate > 0 {
+                    handle.preallocate(self.preallocate)?;
+                }
+                handle
+            };
+            self.handle = Some(handle);
+            if let Some(journal) = &self.journal {
+                self.handle.as_mut().unwrap().set_journal(journal.clone());
+            }
         }
-        let handle = self.handle.as_mut().unwrap();
-        handle.add(entries).await?;
+        Ok(self.handle.as_mut().unwrap())
+    }
+
+    /// Whether the log and index are on local disk.
+    fn is_local(&self) -> bool {
+        self.handle.is_some() || Handle::exists(&self.location, self.id)
+    }
+
+    /// The size of the log on local disk, which is nothing for an evicted segment.
+    fn local_size(&self) -> u64 {
+        match self.handle.as_ref() {
+            Some(h) => h.log_size(),
+            None => {
+                let path = self.location.join(self.id.to_string());
+                files::size(&path.with_extension("log")).unwrap_or(0)
+            }
+        }
+    }
+
+    /// Whether an offloaded segment was not read for `idle`, or not since it was opened.
+    fn unread_for(&self, idle: Duration) -> bool {
+        self.last_read.is_none_or(|read| read.elapsed() >= idle)
+    }
+
+    /// Whether the segment has any files, here or in the cold store.
+    fn exists(&self) -> bool {
+        self.is_local() || self.offloaded.is_some()
+    }
+
+    /// The time since the log was last written to, or since the segment was
+    /// offloaded once the log is gone.
+    pub fn age(&self) -> Result<Duration> {
+        let path = self.location.join(self.id.to_string());
+        let written = ["log", "remote", "index"]
+            .iter()
+            .map(|extension| path.with_extension(extension))
+            .find(|path| path.exists());
+        match written {
+            Some(path) => {
+                let modified = files::modified(&path)?;
+                Ok(SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default())
+            }
+            None => Ok(Duration::default()),
+        }
+    }
+
+    /// The ledger id, which the files of the segment are kept under in the cold store.
+    fn store_prefix(&self) -> String {
+        let ledger_id = self.location.file_name().unwrap_or_default();
+        ledger_id.to_string_lossy().into_owned()
+    }
+
+    /// Moves the log and index to the cold store. The rest of the segment stays
+    /// here, along with `<id>.remote` holding its next offset.
+    pub async fn offload(&mut self) -> Result<()> {
+        self.upload().await?;
+        self.evict()?;
+        Ok(())
+    }
+
+    async fn upload(&mut self) -> Result<()> {
+        let store = self.cold_store.clone();
+        let store = store.ok_or(Error::SegmentOffloaded(self.id))?;
+        let next_offset = self.next_offset().await?;
+        self.sync().await?;
+        let (location, id, prefix) = (self.location.clone(), self.id, self.store_prefix());
+        blocking(move || Handle::upload(&location, id, store.as_ref(), &prefix)).await?;
+        let path = self.location.join(self.id.to_string());
+        files::write_atomic(
+            &path.with_extension("remote"),
+            next_offset.to_string().as_bytes(),
+        )?;
+        self.offloaded = Some(next_offset);
         Ok(())
     }
 
-    pub async fn stream<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<()>
+    /// Copies the log and index of an offloaded segment back from the cold store
+    /// unless they are here already. The copy needs nothing of the segment, so it
+    /// can run without holding up the ledger; reading the segment counts as of now.
+    pub fn fetcher(&mut self) -> Option<impl FnOnce() -> Result<()> + Send + 'static> {
+        if self.offloaded.is_none() || self.is_local() {
+            return None;
+        }
+        let store = self.cold_store.clone()?;
+        self.last_read = Some(Instant::now());
+        let (location, id, prefix) = (self.location.clone(), self.id, self.store_prefix());
+        Some(move || Handle::fetch(&location, id, store.as_ref(), &prefix))
+    }
+
+    /// Drops the local copy of an offloaded segment, which is fetched again when read.
+    pub fn evict(&mut self) -> Result<bool> {
+        if self.offloaded.is_none() || !self.is_local() {
+            return Ok(false);
+        }
+        self.handle = None;
+        Handle::remove(&self.location, self.id)?;
+        self.size = 0;
+        Ok(true)
+    }
+
+    fn set_journal(&mut self, journal: Journal, ledger_id: &str) {
+        let journal = journal.segment(ledger_id, self.id);
+        if let Some(handle) = self.handle.as_mut() {
+            handle.set_journal(journal.clone());
+        }
+        self.journal = Some(journal);
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
+        match self.handle.as_mut() {
+            Some(handle) => handle.sync().await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn add(&mut self, entries: Vec<Vec<u8>>) -> Result<Range<u64>> {
+        self.handle().await?.add(entries).await
+    }
+
+    pub async fn add_from_producer(
+        &mut self,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        self.handle()
+            .await?
+            .add_from_producer(producer, entries)
+            .await
+    }
+
+    pub async fn add_transactional(
+        &mut self,
+        producer: Producer,
+        entries: Vec<Vec<u8>>,
+    ) -> Result<Range<u64>> {
+        self.handle()
+            .await?
+            .add_transactional(producer, entries)
+            .await
+    }
+
+    /// Adds `entries` from `base_offset` on, which must not be before the next offset.
+    pub async fn add_at(&mut self, base_offset: u64, entries: Vec<Vec<u8>>) -> Result<Range<u64>> {
+        self.handle()
+            .await?
+            .append(base_offset, None, entries)
+            .await
+    }
+
+    /// Writes a transaction marker at the next offset.
+    pub async fn add_marker(&mut self, producer_id: u64, marker: Marker) -> Result<()> {
+        self.handle().await?.add_marker(producer_id, marker).await
+    }
+
+    /// The producer of every batch written by one, from the snapshot taken when
+    /// the segment was sealed or else from the batches themselves.
+    pub async fn producers(&mut self) -> Result<Vec<(Producer, Range<u64>)>> {
+        let path = self.location.join(self.id.to_string());
+        let snapshot = path.with_extension("producers");
+        if snapshot.exists() {
+            return producer::read_snapshot(&snapshot, self.id);
+        }
+        if !self.exists() || self.quarantine.is_some() {
+            return Ok(vec![]);
+        }
+        self.handle().await?.producers().await
+    }
+
+    /// Keeps the last batch of every producer in `<id>.producers` once the segment
+    /// is no longer written to, so that opening the ledger does not read it again.
+    /// The snapshot stays here when the segment is offloaded or compacted.
+    pub async fn seal(&mut self) -> Result<()> {
+        let snapshot = self.location.join(self.id.to_string());
+        let snapshot = snapshot.with_extension("producers");
+        if snapshot.exists() || !self.exists() || self.quarantine.is_some() {
+            return Ok(());
+        }
+        let producers = self.handle().await?.producers().await?;
+        producer::write_snapshot(&snapshot, &producers)
+    }
+
+    /// Streams entries from `offset` onwards and returns the offset to continue reading from.
+    pub async fn stream<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<u64>
     where
         T: AsyncWrite + Unpin + ?Sized,
     {
-        if let Some(handle) = self.handle.as_mut() {
-            handle.stream(offset, bytes, target).await?;
+        if !self.exists() {
+            return Ok(offset);
+        }
+        self.handle().await?.stream(offset, bytes, target).await
+    }
+
+    pub async fn stream_raw<T>(&mut self, offset: u64, bytes: usize, target: &mut T) -> Result<u64>
+    where
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        if !self.exists() {
+            return Ok(offset);
+        }
+        self.handle().await?.stream_raw(offset, bytes, target).await
+    }
+
+    /// Streams the committed entries, as told by `transactions` from this segment on,
+    /// see `Segments::transactions`.
+    pub async fn stream_committed<T>(
+        &mut self,
+        offset: u64,
+        bytes: usize,
+        target: &mut T,
+        transactions: &TransactionIndex,
+    ) -> Result<u64>
+    where
+        T: AsyncWrite + Unpin + ?Sized,
+    {
+        if !self.exists() {
+            return Ok(offset);
+        }
+        self.handle()
+            .await?
+            .stream_committed(offset, bytes, target, transactions)
+            .await
+    }
+
+    pub async fn entries(
+        &mut self,
+        transactions: &TransactionIndex,
+    ) -> Result<Vec<(u64, Vec<u8>)>> {
+        if !self.exists() {
+            return Ok(vec![]);
+        }
+        self.handle().await?.entries(transactions).await
+    }
+
+    /// The transaction markers in the segment alone.
+    pub async fn transactions(&mut self) -> Result<TransactionIndex> {
+        if !self.exists() {
+            return Ok(TransactionIndex::default());
+        }
+        Ok(self.handle().await?.transactions().await?.clone())
+    }
+
+    /// Rewrites the segment with only the committed entries accepted by `retain`.
+    /// The last entry is always kept, along with a transaction marker ending the
+    /// segment, so that the next offset survives a reopen. That marker is kept as
+    /// the end of its transaction, which may have ended in a later segment, so that
+    /// the segment does not look open once the later one is compacted too. A
+    /// segment with a transaction still open, as told by `transactions` from it
+    /// on, is left for a later pass and false returned.
+    pub async fn compact<F>(&mut self, retain: F, transactions: &TransactionIndex) -> Result<bool>
+    where
+        F: Fn(u64, &[u8]) -> bool,
+    {
+        if !self.exists() {
+            return Ok(true);
+        }
+        if transactions.stable_offset().is_some() {
+            return Ok(false);
+        }
+        let handle = self.handle().await?;
+        let entries = handle.producer_entries(transactions).await?;
+        let marker = handle.last_batch().await?.filter(Batch::is_control);
+        let marker = marker.and_then(|batch| {
+            let (offset, producer_id) = (batch.base_offset, batch.producer?.id);
+            Some((offset, producer_id, transactions.end(producer_id, offset)?))
+        });
+        if entries.is_empty() {
+            return Ok(true);
         }
+        let last = entries.len() - 1;
+        let survivors = entries
+            .into_iter()
+            .enumerate()
+            .filter(|(i, (_, offset, entry))| *i == last || retain(*offset, entry))
+            .map(|(_, entry)| entry)
+            .collect();
+        self.close();
+        let cipher = self.cipher()?;
+        let (location, id, base_offset) = (&self.location, self.id, self.base_offset);
+        let codec = self.codec;
+        compaction::rewrite(location, id, base_offset, codec, cipher, survivors, marker).await?;
+        self.handle().await?;
+        Ok(true)
+    }
+
+    pub async fn next_offset(&mut self) -> Result<u64> {
+        if !self.is_local() {
+            return Ok(self.offloaded.unwrap_or(self.base_offset));
+        }
+        Ok(self.handle().await?.next_offset())
+    }
+
+    /// Checks the files of the segment as they are on disk, see `scrubber::verify_files`,
+    /// and returns the number of bytes read. Runs apart from the segment, such as on
+    /// a blocking thread once the ledger is unlocked, pausing for `pause` between
+    /// chunks.
+    pub fn scrubber(&self, pause: Duration) -> impl FnOnce() -> Result<u64> + Send + 'static {
+        let exists = self.handle.is_some() || Handle::exists(&self.location, self.id);
+        let (id, base_offset) = (self.id, self.base_offset);
+        let path = self.location.join(id.to_string());
+        move || {
+            if exists {
+                scrubber::verify_files(id, base_offset, &path, pause)
+            } else {
+                Ok(0)
+            }
+        }
+    }
+
+    /// Takes the segment out of service for `reason`, leaving its files in place
+    /// to be looked into. Reads of its offsets fail from then on.
+    pub fn quarantine(&mut self, reason: &str) -> Result<()> {
+        let path = self.location.join(self.id.to_string());
+        files::write_atomic(&path.with_extension("quarantine"), reason.as_bytes())?;
+        self.close();
+        self.quarantine = Some(reason.to_owned());
         Ok(())
     }
 
-    pub async fn size(&self) -> u64 {
+    /// Why the segment is quarantined, if it is.
+    pub fn quarantined(&self) -> Option<&str> {
+        self.quarantine.as_deref()
+    }
+
+    /// The size of the index, as tracked by the handle or on disk.
+    pub fn index_size(&self) -> Result<u64> {
         match self.handle.as_ref() {
-            Some(h) => h.log_size().await,
-            None => 0,
+            Some(h) => Ok(h.index_size()),
+            None => {
+                let path = self.location.join(self.id.to_string());
+                Ok(files::size(&path.with_extension("index"))?)
+            }
         }
     }
+
+    /// The size of the log, tracked by the handle once the segment is in use.
+    pub fn size(&self) -> u64 {
+        match self.handle.as_ref() {
+            Some(h) => h.log_size(),
+            None => self.size,
+        }
+    }
+}
+
+/// The base offset of a segment is kept in `<id>.segment`. Segments written
+/// before that started at their id.
+pub fn read_base_offset(location: &Path, id: u64) -> Result<u64> {
+    let path = location.join(id.to_string()).with_extension("segment");
+    if !path.exists() {
+        return Ok(id);
+    }
+    let metadata = files::read(&path)?;
+    if metadata.len() < 8 {
+        return Err(Error::CorruptSegmentMetadata(id));
+    }
+    Ok(BigEndian::read_u64(&metadata))
+}
+
+/// Runs blocking I/O off the runtime.
+pub async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let result = tokio::task::spawn_blocking(f).await;
+    result.map_err(|e| io::Error::other(e.to_string()))?
+}
+
+/// The next offset of an offloaded segment, kept in `<id>.remote`.
+fn read_offloaded(location: &Path, id: u64) -> Result<Option<u64>> {
+    let path = location.join(id.to_string()).with_extension("remote");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let next_offset = String::from_utf8_lossy(&files::read(&path)?).trim().parse();
+    next_offset
+        .map(Some)
+        .map_err(|_| Error::CorruptSegmentMetadata(id))
+}
+
+/// The reason a segment was quarantined for, kept in `<id>.quarantine`.
+fn read_quarantine(location: &Path, id: u64) -> Result<Option<String>> {
+    let path = location.join(id.to_string()).with_extension("quarantine");
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&files::read(&path)?).into_owned(),
+    ))
+}
+
+/// Writes `<id>.segment` whole or not at all, as it is how the segment is found.
+pub fn write_base_offset(location: &Path, id: u64, base_offset: u64) -> Result<()> {
+    let path = location.join(id.to_string()).with_extension("segment");
+    files::write_atomic(&path, &base_offset.to_be_bytes())?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -97,7 +828,7 @@ mod tests {
     #[tokio::test]
     async fn create_new_segment() {
         let location = test::create_a_test_directory();
-        let mut segments = Segments::open(location).unwrap();
+        let mut segments = Segments::open(location, Codec::None, None).unwrap();
 
         let segment = segments.create(5).await.unwrap();
 
@@ -108,39 +839,96 @@ mod tests {
     async fn no_segments() {
         let location = test::create_a_test_directory();
 
-        assert_eq!(Segments::open(location).unwrap().len(), 0);
+        assert_eq!(
+            Segments::open(location, Codec::None, None).unwrap().len(),
+            0
+        );
     }
 
     #[tokio::test]
     async fn add_entries_to_segment() {
-        let mut segments = Segments::open(test::create_a_test_directory()).unwrap();
+        let mut segments =
+            Segments::open(test::create_a_test_directory(), Codec::None, None).unwrap();
         let segment = segments.create(5).await.unwrap();
 
         segment.add(vec![vec![1, 2], vec![5, 6]]).await.unwrap();
 
-        assert_eq!(segment.size().await, 28);
+        assert_eq!(segment.size(), 33);
+    }
+
+    #[tokio::test]
+    async fn keep_size_once_closed() {
+        let mut segments =
+            Segments::open(test::create_a_test_directory(), Codec::None, None).unwrap();
+        let segment = segments.create(5).await.unwrap();
+        segment.add(vec![vec![1, 2], vec![5, 6]]).await.unwrap();
+
+        segment.quarantine("test").unwrap();
+
+        assert_eq!(segment.size(), 33);
     }
 
     #[tokio::test]
     async fn stream_entries_from_segment() {
         let segment_id = 5;
-        let mut segments = Segments::open(test::create_a_test_directory()).unwrap();
+        let mut segments =
+            Segments::open(test::create_a_test_directory(), Codec::None, None).unwrap();
         let segment = segments.create(segment_id).await.unwrap();
         segment.add(vec![vec![1, 2], vec![5, 6]]).await.unwrap();
 
         let mut buf = Vec::new();
-        segment.stream(segment_id, 16000, &mut buf).await.unwrap();
+        segment.stream(0, 16000, &mut buf).await.unwrap();
 
         assert_eq!(
             &buf,
             &vec![
-                0, 0, 0, 0, 0, 0, 0, 5, //offset
+                0, 0, 0, 0, 0, 0, 0, 0, //offset
                 0, 0, 0, 2, //len
                 1, 2, //payload
-                0, 0, 0, 0, 0, 0, 0, 6, //offset
+                0, 0, 0, 0, 0, 0, 0, 1, //offset
                 0, 0, 0, 2, //len
                 5, 6 //payload
             ]
         );
     }
+
+    #[tokio::test]
+    async fn continue_offsets_in_new_segment() {
+        let location = test::create_a_test_directory();
+        let mut segments = Segments::open(location.clone(), Codec::None, None).unwrap();
+        let segment = segments.writable(5).await.unwrap();
+        segment.add(vec![vec![1], vec![2]]).await.unwrap();
+
+        let segment = segments.writable(9).await.unwrap();
+
+        assert_eq!(segment.base_offset, 2);
+        assert_eq!(segment.add(vec![vec![3]]).await.unwrap(), 2..3);
+        assert!(segments
+            .writable(5)
+            .await
+            .err()
+            .unwrap()
+            .is_segment_sealed());
+        let mut segments = Segments::open(location, Codec::None, None).unwrap();
+        assert_eq!(segments.find(2), Some(9));
+        assert_eq!(segments.find(1), Some(5));
+        assert_eq!(segments.following(0), Some(9));
+        assert_eq!(segments.get(5).unwrap().size(), 31);
+        assert_eq!(segments.next_offset().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn find_segments_without_entries() {
+        let location = test::create_a_test_directory();
+        let mut segments = Segments::open(location.clone(), Codec::None, None).unwrap();
+        segments.create_at(4, 10).await.unwrap();
+        drop(segments);
+
+        let mut segments = Segments::open(location, Codec::None, None).unwrap();
+
+        assert_eq!(segments.ids(), vec![4]);
+        assert_eq!(segments.next_offset().await.unwrap(), 10);
+        let segment = segments.writable(4).await.unwrap();
+        assert_eq!(segment.add(vec![vec![1]]).await.unwrap(), 10..11);
+    }
 }
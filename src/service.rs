@@ -1,43 +1,542 @@
+mod archive;
+mod batch;
+mod cluster;
+mod compaction;
+mod compression;
+mod config;
+mod directories;
+mod encryption;
 mod files;
 mod handle;
 mod index;
+mod journal;
 mod ledger;
 mod log;
+mod producer;
+mod scrubber;
 mod segment;
+mod settings;
+mod snapshot;
+#[cfg(test)]
 mod test_util;
+mod tiering;
+mod transaction;
 mod types;
 
 use api::ledger_api_server::LedgerApi;
-use api::{CreateLedgerRequest, LedgerCreatedResponse};
-use std::path::PathBuf;
-use tonic::{Request, Response, Status};
+use api::{
+    AppendRequest, AppendResponse, CreateLedgerRequest, LedgerConfigResponse,
+    LedgerCreatedResponse, ProducerRegisteredResponse, QuarantinedSegment, ReadRequest,
+    ReadResponse, RegisterProducerRequest, RotateLedgerKeyRequest, RotateLedgerKeyResponse,
+    ScrubStatusRequest, ScrubStatusResponse, SnapshotRequest, SnapshotResponse, TransactionRequest,
+    TransactionResponse, UpdateLedgerConfigRequest,
+};
+pub use cluster::register;
+use compression::Codec;
+use config::{ConfigUpdate, LedgerConfig, Retention};
+use directories::DataDirs;
+pub use directories::Placement;
+pub use files::WriteMode;
+use ledger::Read;
+use producer::Producer;
+use scrubber::ScrubStats;
+pub use settings::Settings;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status};
+use transaction::Marker;
+use types::Error;
+
+const TRANSACTION_LOG: &str = "transactions";
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_READ_BYTES: usize = 1 << 20;
 
 pub struct LedgerService {
-    path: PathBuf,
-    segment_size: u64,
+    dirs: Arc<DataDirs>,
+    defaults: LedgerConfig,
     repository: ledger::LedgerRepository,
+    scrub_stats: Arc<ScrubStats>,
+    transaction_log: PathBuf,
+}
+
+impl LedgerService {
+    /// Spreads the ledgers over `paths`, usually one directory per disk.
+    pub fn with_data_dirs(mut self, paths: Vec<PathBuf>, placement: Placement) -> LedgerService {
+        self.dirs = watch(DataDirs::new(paths, placement));
+        self
+    }
+
+    /// Stops appends to a data directory once its disk is `high` percent used, and
+    /// resumes them once it is back down to `low` percent.
+    pub fn with_water_marks(
+        self,
+        high: usize,
+        low: usize,
+    ) -> Result<LedgerService, Box<dyn std::error::Error>> {
+        self.dirs.set_water_marks(high, low)?;
+        self.dirs.refresh();
+        Ok(self)
+    }
+
+    /// Opens the ledgers found in the data directories. A directory that cannot
+    /// be read is left out, a ledger that cannot be opened is skipped. Then ends
+    /// the transactions the last run left open, see `recover_transactions`, and
+    /// keeps opening the ledgers that show up later, see `discover`.
+    pub async fn open_ledgers(self) -> Result<LedgerService, Box<dyn std::error::Error>> {
+        for (location, id) in self.dirs.ledgers() {
+            let config = self.defaults.clone();
+            match self.repository.open(&location, id.clone(), config).await {
+                Err(e @ Error::IOError(_)) => self.dirs.fail(&location, &e),
+                Err(e) => println!("ledger {} could not be opened: {}", id, e),
+                Ok(_) => self.dirs.add_ledger(&id, &location),
+            }
+        }
+        let repository = &self.repository;
+        let ended = repository
+            .recover_transactions(&self.transaction_log)
+            .await?;
+        println!("ended {} transactions left open by the last run", ended);
+        tokio::spawn(transaction::run(self.repository.clone()));
+        let (repository, dirs) = (self.repository.clone(), self.dirs.clone());
+        tokio::spawn(discover(repository, dirs, self.defaults.clone()));
+        Ok(self)
+    }
+
+    /// The status of an error on the ledger `id`. A full disk stops appends to the
+    /// directory of the ledger, any other I/O error takes it out of use.
+    fn ledger_status(&self, id: &str, error: Error) -> Status {
+        if error.is_disk_full() {
+            self.dirs.disk_full(id);
+        } else if let Error::IOError(_) = error {
+            self.dirs.ledger_failed(id, &error);
+        }
+        status(error)
+    }
+
+    /// Sets the server wide entry and batch size limits, which ledgers can only lower.
+    pub fn with_limits(mut self, max_entry_size: u32, max_batch_size: u32) -> LedgerService {
+        self.defaults.max_entry_size = max_entry_size;
+        self.defaults.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Makes new segments reserve their full size on disk when they are started.
+    pub fn with_preallocation(mut self, preallocate: bool) -> LedgerService {
+        self.defaults.preallocate = preallocate;
+        self
+    }
+
+    /// Acknowledges appends once they are in the journal under `location`, after
+    /// replaying the batches the segments lost since the last run. The segments
+    /// are synced and the journal dropped every `interval`.
+    pub async fn with_journal(
+        mut self,
+        location: &Path,
+        interval: Duration,
+    ) -> Result<LedgerService, Box<dyn std::error::Error>> {
+        let replayed = journal::replay(location, &self.dirs).await?;
+        println!("replayed {} batches from the journal", replayed);
+        let journal = journal::Journal::open(location)?;
+        self.repository = self.repository.with_journal(journal);
+        tokio::spawn(journal::run(self.repository.clone(), interval));
+        Ok(self)
+    }
+
+    /// Moves sealed segments last written to more than `min_age` ago to the
+    /// directory `location`, fetching them back when they are read.
+    pub fn with_cold_store(
+        mut self,
+        location: &Path,
+        min_age: Duration,
+    ) -> Result<LedgerService, Box<dyn std::error::Error>> {
+        let store = tiering::LocalStore::new(location)?;
+        self.repository = self.repository.with_cold_store(Arc::new(store));
+        tokio::spawn(tiering::run(self.repository.clone(), min_age));
+        Ok(self)
+    }
+
+    /// Sets how segment files are written, see `WriteMode`.
+    pub fn with_write_mode(mut self, mode: WriteMode) -> LedgerService {
+        self.defaults.write_mode = mode;
+        self
+    }
 }
 
 #[tonic::async_trait]
 impl LedgerApi for LedgerService {
     async fn create(
         &self,
-        _request: Request<CreateLedgerRequest>,
+        request: Request<CreateLedgerRequest>,
     ) -> Result<Response<LedgerCreatedResponse>, Status> {
         println!("creating new ledger...");
         let repo = &self.repository;
-        let response = match repo.create(&self.path, self.segment_size).await {
-            Ok(id) => api::LedgerCreatedResponse { ledger_id: id },
-            Err(_) => panic!("error creating ledger"), //TODO: handle error gracefully
+        let request = request.into_inner();
+        let defaults = self.defaults.clone();
+        let config = LedgerConfig {
+            compacted: request.compacted,
+            compression: codec(request.compression()),
+            max_entry_size: limit(request.max_entry_size, defaults.max_entry_size),
+            max_batch_size: limit(request.max_batch_size, defaults.max_batch_size),
+            segment_size: match request.segment_size {
+                0 => defaults.segment_size,
+                size => size,
+            },
+            retention: match &request.retention {
+                Some(r) => retention(r),
+                None => defaults.retention.clone(),
+            },
+            replication_factor: request.replication_factor.max(1),
+            labels: request.labels.into_iter().collect(),
+            ..defaults
         };
-        Ok(Response::new(response))
+        loop {
+            let location = self.dirs.place().map_err(status)?;
+            match repo.create(&location, config.clone()).await {
+                Ok(id) => {
+                    self.dirs.add_ledger(&id, &location);
+                    self.dirs.written(&id);
+                    return Ok(Response::new(api::LedgerCreatedResponse { ledger_id: id }));
+                }
+                Err(e @ Error::IOError(_)) => self.dirs.fail(&location, &e),
+                Err(e) => return Err(status(e)),
+            }
+        }
+    }
+
+    async fn append(
+        &self,
+        request: Request<AppendRequest>,
+    ) -> Result<Response<AppendResponse>, Status> {
+        let request = request.into_inner();
+        let repo = &self.repository;
+        let (id, segment_id, entries) = (&request.ledger_id, request.segment_id, request.entries);
+        self.dirs.check_writable(id).map_err(status)?;
+        let producer = Producer {
+            id: request.producer_id,
+            sequence: request.sequence,
+        };
+        let result = match (request.producer_id, request.transactional) {
+            (0, true) => return Err(Status::invalid_argument("transactions need a producer")),
+            (0, false) => repo.add(id, segment_id, entries).await,
+            (_, false) => {
+                repo.add_from_producer(id, segment_id, producer, entries)
+                    .await
+            }
+            (_, true) => {
+                repo.add_transactional(id, segment_id, producer, entries)
+                    .await
+            }
+        };
+        let offsets = result.map_err(|e| self.ledger_status(id, e))?;
+        self.dirs.written(id);
+        Ok(Response::new(AppendResponse {
+            base_offset: offsets.start,
+            count: (offsets.end - offsets.start) as u32,
+        }))
+    }
+
+    async fn register_producer(
+        &self,
+        request: Request<RegisterProducerRequest>,
+    ) -> Result<Response<ProducerRegisteredResponse>, Status> {
+        let request = request.into_inner();
+        let producer_id = self
+            .repository
+            .register_producer(&request.ledger_id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(ProducerRegisteredResponse { producer_id }))
+    }
+
+    async fn begin_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        let producer_id = request.into_inner().producer_id;
+        self.repository
+            .begin_transaction(producer_id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(TransactionResponse {}))
+    }
+
+    async fn commit_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        let producer_id = request.into_inner().producer_id;
+        self.repository
+            .end_transaction(producer_id, Marker::Commit)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(TransactionResponse {}))
+    }
+
+    async fn abort_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        let producer_id = request.into_inner().producer_id;
+        self.repository
+            .end_transaction(producer_id, Marker::Abort)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(TransactionResponse {}))
+    }
+
+    async fn read(&self, request: Request<ReadRequest>) -> Result<Response<ReadResponse>, Status> {
+        let request = request.into_inner();
+        let bytes = match request.max_bytes {
+            0 => DEFAULT_READ_BYTES,
+            bytes => bytes as usize,
+        };
+        let read = match (request.raw_batches, request.read_committed) {
+            (true, true) => {
+                let message = "raw batches cannot be read committed";
+                return Err(Status::invalid_argument(message));
+            }
+            (true, false) => Read::Raw,
+            (false, true) => Read::Committed,
+            (false, false) => Read::Entries,
+        };
+        let id = &request.ledger_id;
+        self.dirs.check_available(id).map_err(status)?;
+        let (entries, next_offset) = self
+            .repository
+            .read(id, request.offset, bytes, read)
+            .await
+            .map_err(|e| self.ledger_status(id, e))?;
+        Ok(Response::new(ReadResponse {
+            entries,
+            next_offset,
+        }))
+    }
+
+    async fn update_ledger_config(
+        &self,
+        request: Request<UpdateLedgerConfigRequest>,
+    ) -> Result<Response<LedgerConfigResponse>, Status> {
+        let request = request.into_inner();
+        let defaults = &self.defaults;
+        let update = ConfigUpdate {
+            segment_size: Some(request.segment_size).filter(|size| *size > 0),
+            max_entry_size: Some(request.max_entry_size)
+                .filter(|size| *size > 0)
+                .map(|size| size.min(defaults.max_entry_size)),
+            max_batch_size: Some(request.max_batch_size)
+                .filter(|size| *size > 0)
+                .map(|size| size.min(defaults.max_batch_size)),
+            retention: request.retention.as_ref().map(retention),
+            labels: request.labels.into_iter().collect(),
+        };
+        let id = request.ledger_id;
+        let config = self
+            .repository
+            .update_config(&id, update)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(LedgerConfigResponse {
+            ledger_id: id,
+            segment_size: config.segment_size,
+            compacted: config.compacted,
+            compression: compression(config.compression) as i32,
+            max_entry_size: config.max_entry_size,
+            max_batch_size: config.max_batch_size,
+            retention: Some(api::Retention {
+                max_bytes: config.retention.bytes.unwrap_or(0),
+                max_age_secs: config.retention.age.map_or(0, |age| age.as_secs()),
+            }),
+            replication_factor: config.replication_factor,
+            labels: config.labels.into_iter().collect(),
+        }))
+    }
+
+    async fn get_scrub_status(
+        &self,
+        _request: Request<ScrubStatusRequest>,
+    ) -> Result<Response<ScrubStatusResponse>, Status> {
+        let stats = &self.scrub_stats;
+        let quarantined = self.repository.quarantined().await;
+        Ok(Response::new(ScrubStatusResponse {
+            passes: stats.passes.load(Ordering::SeqCst),
+            segments_scrubbed: stats.segments.load(Ordering::SeqCst),
+            bytes_scrubbed: stats.bytes.load(Ordering::SeqCst),
+            corrupt_segments: stats.corrupt.load(Ordering::SeqCst),
+            quarantined: quarantined
+                .into_iter()
+                .map(|(ledger_id, segment_id, reason)| QuarantinedSegment {
+                    ledger_id,
+                    segment_id,
+                    reason,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        let name = request.into_inner().name;
+        let snapshots = self.repository.snapshot(&name).await.map_err(status)?;
+        let mut directories: Vec<String> = snapshots
+            .iter()
+            .filter_map(|path| path.parent())
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .collect();
+        directories.sort();
+        directories.dedup();
+        println!("took snapshot {} of {} ledgers", name, snapshots.len());
+        Ok(Response::new(SnapshotResponse {
+            ledgers: snapshots.len() as u32,
+            directories,
+        }))
+    }
+
+    async fn rotate_ledger_key(
+        &self,
+        request: Request<RotateLedgerKeyRequest>,
+    ) -> Result<Response<RotateLedgerKeyResponse>, Status> {
+        let id = request.into_inner().ledger_id;
+        self.repository.rotate_key(&id).await.map_err(status)?;
+        Ok(Response::new(RotateLedgerKeyResponse {}))
+    }
+}
+
+fn codec(compression: api::Compression) -> Codec {
+    match compression {
+        api::Compression::None => Codec::None,
+        api::Compression::Lz4 => Codec::Lz4,
+        api::Compression::Zstd => Codec::Zstd,
+        api::Compression::Snappy => Codec::Snappy,
+    }
+}
+
+fn compression(codec: Codec) -> api::Compression {
+    match codec {
+        Codec::None => api::Compression::None,
+        Codec::Lz4 => api::Compression::Lz4,
+        Codec::Zstd => api::Compression::Zstd,
+        Codec::Snappy => api::Compression::Snappy,
+    }
+}
+
+/// A limit of 0 is no limit.
+fn retention(retention: &api::Retention) -> Retention {
+    Retention {
+        bytes: Some(retention.max_bytes).filter(|bytes| *bytes > 0),
+        age: Some(retention.max_age_secs)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    }
+}
+
+fn limit(requested: u32, server: u32) -> u32 {
+    match requested {
+        0 => server,
+        requested => requested.min(server),
+    }
+}
+
+fn status(error: Error) -> Status {
+    let message = error.to_string();
+    match error {
+        Error::EntryTooLarge(_, _) | Error::BatchTooLarge(_, _) => {
+            Status::invalid_argument(message)
+        }
+        Error::LedgerNotFound(_) => Status::not_found(message),
+        Error::DuplicateSequence(_, _) => Status::already_exists(message),
+        Error::SegmentFull(_)
+        | Error::SegmentSealed(_)
+        | Error::OutOfOrderSequence(_, _)
+        | Error::UnknownProducer(_)
+        | Error::NoTransaction(_)
+        | Error::TransactionInProgress(_)
+        | Error::LedgerNotEncrypted(_) => Status::failed_precondition(message),
+        Error::OffsetOutOfRange { first, next, .. } => {
+            let mut metadata = MetadataMap::new();
+            metadata.insert("first-offset", first.into());
+            metadata.insert("next-offset", next.into());
+            Status::with_metadata(Code::OutOfRange, message, metadata)
+        }
+        Error::NoDataDirectory | Error::SegmentOffloaded(_) => Status::unavailable(message),
+        Error::InvalidSnapshot(_) => Status::failed_precondition(message),
+        Error::InvalidConfig(_) => Status::invalid_argument(message),
+        Error::CorruptSegment(_, _) | Error::SegmentQuarantined(_) => Status::data_loss(message),
+        ref e if e.is_disk_full() => Status::resource_exhausted(message),
+        _ => Status::internal(message),
     }
 }
 
 pub fn new(path: PathBuf, segment_size: u64) -> LedgerService {
+    with_keyring(path, segment_size, None)
+}
+
+/// Creates a service that encrypts every new ledger with the master keys in `key_file`.
+pub fn with_key_file(
+    path: PathBuf,
+    segment_size: u64,
+    key_file: &Path,
+) -> Result<LedgerService, Box<dyn std::error::Error>> {
+    let keyring = encryption::Keyring::load(key_file)?;
+    Ok(with_keyring(path, segment_size, Some(Arc::new(keyring))))
+}
+
+fn with_keyring(
+    path: PathBuf,
+    segment_size: u64,
+    keyring: Option<Arc<encryption::Keyring>>,
+) -> LedgerService {
+    let repository = ledger::new_repository(keyring);
+    tokio::spawn(compaction::run(repository.clone()));
+    let scrub_stats = Arc::new(ScrubStats::default());
+    tokio::spawn(scrubber::run(repository.clone(), scrub_stats.clone()));
     LedgerService {
-        path: path,
-        segment_size: segment_size,
-        repository: ledger::new_repository(),
+        dirs: watch(DataDirs::new(vec![path.clone()], Placement::FreeSpace)),
+        defaults: LedgerConfig::new(segment_size),
+        repository,
+        scrub_stats,
+        transaction_log: path.join(TRANSACTION_LOG),
     }
 }
+
+/// Opens the ledgers moved into the data directories while the server runs, such
+/// as the ones `ledgers-tool import` creates. A ledger that fails to open is
+/// reported once and left alone.
+async fn discover(repository: ledger::LedgerRepository, dirs: Arc<DataDirs>, config: LedgerConfig) {
+    let mut failed = HashSet::new();
+    let mut interval = tokio::time::interval(DISCOVERY_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (location, id) in dirs.ledgers() {
+            if failed.contains(&id) {
+                continue;
+            }
+            match repository
+                .open_if_new(&location, id.clone(), config.clone())
+                .await
+            {
+                Ok(true) => {
+                    println!("opened ledger {} found in {:?}", id, location);
+                    dirs.add_ledger(&id, &location);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    println!("ledger {} could not be opened: {}", id, e);
+                    failed.insert(id);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the disk usage of the data directories up to date while they are in use.
+fn watch(dirs: DataDirs) -> Arc<DataDirs> {
+    let dirs = Arc::new(dirs);
+    tokio::spawn(directories::watch(Arc::downgrade(&dirs)));
+    dirs
+}
@@ -0,0 +1,241 @@
+use crate::config;
+use crate::directories::{self, Placement};
+use crate::files::WriteMode;
+use crate::journal;
+use crate::types::{Error, Result};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const DEFAULT_BIND: &str = "[::1]:5678";
+pub const DEFAULT_PATH: &str = "./target/default_ledgers";
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1000;
+pub const DEFAULT_OFFLOAD_AFTER: u64 = 7 * 24 * 60 * 60;
+
+/// The settings of the server, read from a TOML file laid out as
+///
+/// ```toml
+/// [server]
+/// bind = "[::1]:5678"
+///
+/// [storage]
+/// paths = ["/data/1", "/data/2"]
+/// segment_size = 67108864
+/// write_mode = "sync"
+///
+/// [cluster]
+/// etcd_endpoints = ["http://localhost:2379"]
+/// advertise = "http://ledgers-1:5678"
+/// ```
+///
+/// Every field may be left out for its default, and command line flags take
+/// precedence over the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub storage: StorageSettings,
+    pub limits: LimitSettings,
+    pub cluster: ClusterSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind: String,
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    pub paths: Vec<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    pub placement: Placement,
+    pub segment_size: u64,
+    /// When segment files are synced to disk, see `WriteMode`.
+    #[serde(deserialize_with = "parsed")]
+    pub write_mode: WriteMode,
+    pub preallocate: bool,
+    pub journal_path: Option<PathBuf>,
+    /// How often the segments are synced and the journal behind them dropped.
+    pub checkpoint_interval_secs: u64,
+    pub high_water_mark: usize,
+    pub low_water_mark: usize,
+    pub cold_store_path: Option<PathBuf>,
+    pub offload_after_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    pub max_entry_size: u32,
+    pub max_batch_size: u32,
+}
+
+/// Where the server registers itself for clients to find it, under
+/// `/ledgers/nodes/<node id>`. Nothing is registered without etcd endpoints.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterSettings {
+    pub etcd_endpoints: Vec<String>,
+    /// The address clients reach the server on, such as `http://ledgers-1:5678`.
+    pub advertise: Option<String>,
+    /// Defaults to the advertised address.
+    pub node_id: Option<String>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind: DEFAULT_BIND.to_owned(),
+            key_file: None,
+        }
+    }
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        StorageSettings {
+            paths: vec![PathBuf::from(DEFAULT_PATH)],
+            placement: Placement::FreeSpace,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            write_mode: WriteMode::Buffered,
+            preallocate: false,
+            journal_path: None,
+            checkpoint_interval_secs: journal::DEFAULT_CHECKPOINT_INTERVAL.as_secs(),
+            high_water_mark: directories::DEFAULT_HIGH_WATER_MARK,
+            low_water_mark: directories::DEFAULT_LOW_WATER_MARK,
+            cold_store_path: None,
+            offload_after_secs: DEFAULT_OFFLOAD_AFTER,
+        }
+    }
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings {
+            max_entry_size: config::DEFAULT_MAX_ENTRY_SIZE,
+            max_batch_size: config::DEFAULT_MAX_BATCH_SIZE,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Settings> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| invalid(format!("cannot read {:?}: {}", path, e)))?;
+        toml::from_str(&text).map_err(|e| invalid(format!("{:?}: {}", path, e)))
+    }
+
+    /// Checks the settings against each other, naming the first one that is off.
+    pub fn validate(&self) -> Result<()> {
+        let (storage, limits) = (&self.storage, &self.limits);
+        self.bind_address()?;
+        if storage.paths.is_empty() {
+            return Err(invalid(
+                "storage.paths needs at least one directory".to_owned(),
+            ));
+        }
+        if storage.segment_size == 0 {
+            return Err(invalid("storage.segment_size must be above 0".to_owned()));
+        }
+        if storage.checkpoint_interval_secs == 0 {
+            return Err(invalid(
+                "storage.checkpoint_interval_secs must be above 0".to_owned(),
+            ));
+        }
+        if storage.high_water_mark > 100 || storage.low_water_mark >= storage.high_water_mark {
+            return Err(invalid(format!(
+                "storage.low_water_mark ({}) must be below storage.high_water_mark ({}), which is at most 100",
+                storage.low_water_mark, storage.high_water_mark
+            )));
+        }
+        if limits.max_entry_size == 0 || limits.max_entry_size > limits.max_batch_size {
+            return Err(invalid(format!(
+                "limits.max_entry_size ({}) must be above 0 and at most limits.max_batch_size ({})",
+                limits.max_entry_size, limits.max_batch_size
+            )));
+        }
+        let cluster = &self.cluster;
+        if !cluster.etcd_endpoints.is_empty() && cluster.advertise.is_none() {
+            return Err(invalid(
+                "cluster.advertise is needed to register with etcd".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn bind_address(&self) -> Result<SocketAddr> {
+        let bind = &self.server.bind;
+        bind.parse()
+            .map_err(|_| invalid(format!("server.bind {:?} is not a socket address", bind)))
+    }
+
+    pub fn checkpoint_interval(&self) -> Duration {
+        Duration::from_secs(self.storage.checkpoint_interval_secs)
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidConfig(reason)
+}
+
+/// Deserializes a string through `FromStr`, for the enums that are named on the command line too.
+fn parsed<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse().map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_settings() {
+        let text = r#"
+            [server]
+            bind = "0.0.0.0:7000"
+
+            [storage]
+            paths = ["/data/1", "/data/2"]
+            segment_size = 4096
+            write_mode = "sync"
+
+            [cluster]
+            etcd_endpoints = ["http://localhost:2379"]
+            advertise = "http://ledgers-1:7000"
+        "#;
+        let settings: Settings = toml::from_str(text).unwrap();
+        settings.validate().unwrap();
+        assert_eq!(settings.bind_address().unwrap().port(), 7000);
+        assert_eq!(settings.storage.paths.len(), 2);
+        assert_eq!(settings.storage.write_mode, WriteMode::Sync);
+        assert_eq!(
+            settings.limits.max_entry_size,
+            config::DEFAULT_MAX_ENTRY_SIZE
+        );
+        assert_eq!(settings.cluster.etcd_endpoints.len(), 1);
+        assert_eq!(settings.cluster.node_id, None);
+
+        assert!(toml::from_str::<Settings>("[storage]\nwrite_mode = \"never\"").is_err());
+        assert!(toml::from_str::<Settings>("[storage]\nsegment_sise = 1").is_err());
+        let mut settings = Settings::default();
+        settings.storage.low_water_mark = 99;
+        assert!(settings.validate().unwrap_err().is_invalid_config());
+        let mut settings = Settings::default();
+        settings.cluster.etcd_endpoints = vec!["http://localhost:2379".to_owned()];
+        assert!(settings.validate().is_err());
+        assert!(toml::from_str::<Settings>("[retention]\nmax_bytes = 1").is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::files;
+use crate::files::*;
+use crate::types::{Error, Result};
+use std::fs;
+use std::io;
+use std::io::Read;
+
+/// Snapshots are kept in the data directory, so that segment files can be hard
+/// linked into them, under a name ledgers cannot have.
+const SNAPSHOTS: &str = ".snapshots";
+const MANIFEST: &str = "snapshot";
+
+/// The size of a segment when the snapshot was taken.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentState {
+    pub id: u64,
+    pub log_bytes: u64,
+    pub index_bytes: u64,
+}
+
+/// Where the snapshot `name` of the ledger stored at `location` goes.
+pub fn path(location: &Path, name: &str) -> Result<PathBuf> {
+    let data_dir = location.parent().unwrap_or_else(|| Path::new("."));
+    let ledger_id = location.file_name().unwrap_or_default();
+    Ok(dir(data_dir, name)?.join(ledger_id))
+}
+
+/// The directory of the snapshot `name` in a data directory.
+pub fn dir(data_dir: &Path, name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty() && !name.starts_with('.') && !name.contains('/');
+    if !valid {
+        let reason = format!("{} is not a snapshot name", name);
+        return Err(Error::InvalidSnapshot(reason));
+    }
+    Ok(data_dir.join(SNAPSHOTS).join(name))
+}
+
+/// The snapshots in a data directory.
+#[cfg_attr(not(test), allow(dead_code))] // only the tool lists and restores snapshots
+pub fn list(data_dir: &Path) -> Result<Vec<String>> {
+    let snapshots = data_dir.join(SNAPSHOTS);
+    if !snapshots.exists() {
+        return Ok(vec![]);
+    }
+    Ok(files::list_dirs(&snapshots)?)
+}
+
+/// Hard links the segment files of the ledger at `location` into `target`, and
+/// copies the small files next to them that may be rewritten in place. Segment
+/// files keep growing after the snapshot, so their sizes at the time are kept
+/// in a manifest to cut them back to on restore. Appends must be paused and the
+/// segments synced while this runs.
+pub fn take(location: &Path, target: &Path, segments: &[SegmentState]) -> Result<()> {
+    if target.exists() {
+        let reason = format!("snapshot {:?} already exists", target);
+        return Err(Error::InvalidSnapshot(reason));
+    }
+    build_dir(target, |staging| {
+        for entry in fs::read_dir(location)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let (from, to) = (entry.path(), staging.join(entry.file_name()));
+            match from.extension().and_then(|e| e.to_str()) {
+                Some("log") | Some("index") => fs::hard_link(&from, &to)?,
+                _ => {
+                    fs::copy(&from, &to)?;
+                }
+            }
+        }
+        let manifest: String = segments
+            .iter()
+            .map(|s| format!("{} {} {}\n", s.id, s.log_bytes, s.index_bytes))
+            .collect();
+        files::write_atomic(&staging.join(MANIFEST), manifest.as_bytes())?;
+        Ok(())
+    })
+}
+
+/// Copies every ledger of the snapshot at `snapshot` into the data directory
+/// `target`, with its segments cut back to their sizes at the time. Returns the
+/// ids of the ledgers restored.
+#[cfg_attr(not(test), allow(dead_code))] // only the tool lists and restores snapshots
+pub fn restore(snapshot: &Path, target: &Path) -> Result<Vec<String>> {
+    let ids = files::list_dirs(snapshot)?;
+    for id in &ids {
+        let (from, to) = (snapshot.join(id), target.join(id));
+        if to.exists() {
+            let reason = format!("ledger {} is already in {:?}", id, target);
+            return Err(Error::InvalidSnapshot(reason));
+        }
+        build_dir(&to, |staging| {
+            let segments = read_manifest(&from)?;
+            for entry in fs::read_dir(&from)? {
+                let name = entry?.file_name();
+                let path = from.join(&name);
+                if name == MANIFEST || !path.is_file() {
+                    continue;
+                }
+                let length = segment_length(&path, &segments).unwrap_or(u64::MAX);
+                let mut source = fs::File::open(&path)?.take(length);
+                let mut copy = fs::File::create(staging.join(&name))?;
+                io::copy(&mut source, &mut copy)?;
+                copy.sync_all()?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(ids)
+}
+
+/// Fills the directory `target` through `build` in a hidden sibling that is
+/// only renamed into place once complete, so that a failure leaves nothing
+/// behind to block a retry.
+fn build_dir<F: FnOnce(&Path) -> Result<()>>(target: &Path, build: F) -> Result<()> {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let staging = target.with_file_name(format!(".{}.tmp", name));
+    if staging.exists() {
+        files::remove_dir(&staging)?;
+    }
+    files::create_dir(&staging)?;
+    if let Err(e) = build(&staging) {
+        files::remove_dir(&staging).unwrap_or(());
+        return Err(e);
+    }
+    files::rename(&staging, target)?;
+    files::sync_dir(target.parent().unwrap_or_else(|| Path::new(".")))?;
+    Ok(())
+}
+
+/// How much of a snapshot file belongs to the snapshot, for segment files.
+#[cfg_attr(not(test), allow(dead_code))] // only the tool lists and restores snapshots
+fn segment_length(path: &Path, segments: &[SegmentState]) -> Option<u64> {
+    let id = path.file_stem()?.to_str()?.parse::<u64>().ok()?;
+    let segment = segments.iter().find(|s| s.id == id)?;
+    match path.extension()?.to_str()? {
+        "log" => Some(segment.log_bytes),
+        "index" => Some(segment.index_bytes),
+        _ => None,
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))] // only the tool lists and restores snapshots
+fn read_manifest(location: &Path) -> Result<Vec<SegmentState>> {
+    let manifest = files::read(&location.join(MANIFEST))?;
+    let mut segments = Vec::new();
+    for line in String::from_utf8_lossy(&manifest).lines() {
+        let fields: Vec<u64> = line.split(' ').filter_map(|f| f.parse().ok()).collect();
+        match fields[..] {
+            [id, log_bytes, index_bytes] => segments.push(SegmentState {
+                id,
+                log_bytes,
+                index_bytes,
+            }),
+            _ => {
+                let reason = format!("bad manifest line {:?} in {:?}", line, location);
+                return Err(Error::InvalidSnapshot(reason));
+            }
+        }
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LedgerConfig;
+    use crate::ledger::{Ledger, LedgerRepository};
+    use crate::test_util as test;
+
+    #[tokio::test]
+    async fn restore_snapshot() {
+        let location = test::create_a_test_directory();
+        let repository = LedgerRepository::new(None);
+        let id = repository
+            .create(&location, LedgerConfig::new(1000))
+            .await
+            .unwrap();
+        repository
+            .add(&id, 0, vec![vec![1], vec![2]])
+            .await
+            .unwrap();
+        repository.add(&id, 1, vec![vec![3]]).await.unwrap();
+
+        let snapshots = repository.snapshot("first").await.unwrap();
+        assert_eq!(snapshots, vec![path(&location.join(&id), "first").unwrap()]);
+        assert!(repository.snapshot("first").await.is_err());
+        assert!(repository.snapshot("../first").await.is_err());
+        repository.add(&id, 1, vec![vec![4]]).await.unwrap();
+        assert_eq!(list(&location).unwrap(), vec!["first".to_owned()]);
+        assert_eq!(files::list_dirs(&location).unwrap(), vec![id.clone()]);
+
+        let target = location.join("restored");
+        let snapshot = dir(&location, "first").unwrap();
+        assert_eq!(restore(&snapshot, &target).unwrap(), vec![id.clone()]);
+        let config = LedgerConfig::new(1000);
+        let ledger = Ledger::open(&target, id.clone(), config, None).await;
+        let mut ledger = ledger.unwrap().unwrap();
+        assert_eq!(ledger.offsets().await.unwrap(), 0..3);
+        let mut buf = Vec::new();
+        ledger.stream(2, 16000, &mut buf).await.unwrap();
+        assert_eq!(buf, crate::batch::entry_record(2, &[3]));
+        assert!(restore(&snapshot, &target).is_err());
+    }
+
+    #[test]
+    fn leave_nothing_behind_on_failure() {
+        let location = test::create_a_test_directory();
+        let ledger = location.join("ledger");
+        files::create_dir(&ledger).unwrap();
+        files::write(&ledger.join("config"), b"config").unwrap();
+        let target = path(&ledger, "first").unwrap();
+
+        assert!(take(&location.join("missing"), &target, &[]).is_err());
+        let snapshot = dir(&location, "first").unwrap();
+        assert_eq!(fs::read_dir(&snapshot).unwrap().count(), 0);
+        take(&ledger, &target, &[]).unwrap();
+        assert_eq!(files::read(&target.join("config")).unwrap(), b"config");
+
+        files::remove_file(&target.join(MANIFEST)).unwrap();
+        let restored = location.join("restored");
+        assert!(restore(&snapshot, &restored).is_err());
+        assert_eq!(fs::read_dir(&restored).unwrap().count(), 0);
+    }
+}
@@ -1,5 +1,7 @@
+use crate::encryption::*;
 use crate::files;
 use crate::files::*;
+use std::sync::Arc;
 use uuid::Uuid;
 
 pub fn create_a_test_directory() -> PathBuf {
@@ -9,3 +11,14 @@ pub fn create_a_test_directory() -> PathBuf {
     files::create_dir(&location).unwrap();
     location
 }
+
+pub fn keyring(location: &Path) -> Arc<Keyring> {
+    let path = location.join("master.keys");
+    files::write(&path, format!("1 {}\n", "2a".repeat(32)).as_bytes()).unwrap();
+    Arc::new(Keyring::load(&path).unwrap())
+}
+
+pub fn cipher(location: &Path) -> Cipher {
+    let keys = LedgerKeys::create(location, keyring(location)).unwrap();
+    keys.segment_cipher(location, 0).unwrap()
+}
@@ -0,0 +1,125 @@
+use crate::files;
+use crate::files::*;
+use crate::ledger::LedgerRepository;
+use crate::types::Result;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const OFFLOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+static COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// An object store that sealed segments are moved to once they are old enough,
+/// keeping only their metadata on local disk. Keys look like `<ledger id>/<file name>`.
+pub trait ColdStore: Send + Sync {
+    /// Stores the file at `path` under `key`, replacing what was there.
+    fn upload(&self, key: &str, path: &Path) -> Result<()>;
+
+    /// Writes what is stored under `key` to `path`.
+    fn download(&self, key: &str, path: &Path) -> Result<()>;
+}
+
+/// Keeps the objects as files under a local directory, which may well be a
+/// mounted network share.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path) -> Result<LocalStore> {
+        files::create_dir(root)?;
+        Ok(LocalStore {
+            root: root.to_owned(),
+        })
+    }
+}
+
+impl ColdStore for LocalStore {
+    fn upload(&self, key: &str, path: &Path) -> Result<()> {
+        copy(path, &self.root.join(key))
+    }
+
+    fn download(&self, key: &str, path: &Path) -> Result<()> {
+        copy(&self.root.join(key), path)
+    }
+}
+
+/// Copies through a temporary file, so that a copy cut short is never taken for
+/// a whole one. Each copy has a file of its own, as the same segment may be
+/// fetched by two reads at once.
+fn copy(from: &Path, to: &Path) -> Result<()> {
+    if let Some(dir) = to.parent() {
+        files::create_dir(dir)?;
+    }
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let copy = COPIES.fetch_add(1, Ordering::Relaxed);
+    let partial = to.with_file_name(format!("{}.{}.partial", name, copy));
+    fs::copy(from, &partial)?;
+    fs::File::open(&partial)?.sync_all()?;
+    files::rename(&partial, to)?;
+    Ok(())
+}
+
+/// Offloads the sealed segments last written to more than `min_age` ago, now and then.
+pub async fn run(repository: LedgerRepository, min_age: Duration) {
+    let mut interval = tokio::time::interval(OFFLOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        match repository.offload(min_age).await {
+            Ok(0) => {}
+            Ok(offloaded) => println!("offloaded {} segments", offloaded),
+            Err(e) => println!("offloading failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LedgerConfig;
+    use crate::ledger::Ledger;
+    use crate::test_util as test;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn read_offloaded_segments() {
+        let location = test::create_a_test_directory();
+        let store = Arc::new(LocalStore::new(&location.join("cold")).unwrap());
+        let (path, config) = (location.join("ledgers"), LedgerConfig::new(1000));
+        let mut ledger = Ledger::new(&path, config.clone(), None).await.unwrap();
+        ledger.set_cold_store(store.clone());
+        ledger.add(0, vec![vec![1], vec![2]]).await.unwrap();
+        ledger.add(1, vec![vec![3]]).await.unwrap();
+
+        assert_eq!(ledger.offload(Duration::from_secs(3600)).await.unwrap(), 0);
+        assert_eq!(ledger.offload(Duration::from_secs(0)).await.unwrap(), 1);
+        let segment = path.join(&ledger.id).join("0");
+        assert!(!segment.with_extension("log").exists());
+        assert!(location
+            .join("cold")
+            .join(&ledger.id)
+            .join("0.log")
+            .exists());
+
+        let id = ledger.id.clone();
+        let mut ledger = Ledger::open(&path, id, config, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = Vec::new();
+        let error = ledger.stream(0, 16000, &mut buf).await.unwrap_err();
+        assert!(error.is_segment_offloaded());
+        ledger.set_cold_store(store);
+        ledger.stream(1, 16000, &mut buf).await.unwrap();
+        let mut expected = crate::batch::entry_record(1, &[2]);
+        expected.extend(crate::batch::entry_record(2, &[3]));
+        assert_eq!(buf, expected);
+        assert!(segment.with_extension("log").exists());
+        assert_eq!(ledger.offload(Duration::from_secs(3600)).await.unwrap(), 0);
+        assert!(segment.with_extension("log").exists());
+        assert_eq!(ledger.offload(Duration::from_secs(0)).await.unwrap(), 0);
+        assert!(!segment.with_extension("log").exists());
+        assert_eq!(ledger.offsets().await.unwrap(), 0..3);
+    }
+}
@@ -0,0 +1,431 @@
+mod archive;
+mod batch;
+mod compaction;
+mod compression;
+mod config;
+mod directories;
+mod encryption;
+mod files;
+mod handle;
+mod index;
+mod journal;
+mod ledger;
+mod log;
+mod producer;
+mod scrubber;
+mod segment;
+mod settings;
+mod snapshot;
+#[cfg(test)]
+mod test_util;
+mod tiering;
+mod transaction;
+mod types;
+use archive::{ArchiveReader, ArchiveWriter, Manifest};
+use batch::Batch;
+use byteorder::{BigEndian, ByteOrder};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use compression::Codec;
+use config::LedgerConfig;
+use encryption::{Keyring, LedgerKeys};
+use index::Index;
+use ledger::Ledger;
+use segment::{read_base_offset, Segments};
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Where new ledgers are imported into before they are moved into the data directory.
+const IMPORT_STAGING: &str = ".import";
+
+/// Works on the files of a data directory directly, while the server is not
+/// running against it.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let ledger = || Arg::with_name("ledger").required(true);
+    let segment = || Arg::with_name("segment").required(true);
+    let matches = App::new("ledgers-tool")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("key-file")
+                .long("key-file")
+                .takes_value(true),
+        )
+        .subcommand(SubCommand::with_name("ledgers").about("Lists the ledgers"))
+        .subcommand(
+            SubCommand::with_name("segments")
+                .about("Lists the segments of a ledger")
+                .arg(ledger()),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Prints the committed entries of a segment with their offsets")
+                .arg(ledger())
+                .arg(segment())
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .possible_values(&["hex", "utf8", "json"])
+                        .default_value("hex"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Prints the index of a segment")
+                .arg(ledger())
+                .arg(segment()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks the logs and indexes of a ledger, or of every ledger")
+                .arg(Arg::with_name("ledger")),
+        )
+        .subcommand(
+            SubCommand::with_name("rebuild-index")
+                .about("Writes the index of a segment anew from its log")
+                .arg(ledger())
+                .arg(segment()),
+        )
+        .subcommand(SubCommand::with_name("snapshots").about("Lists the snapshots"))
+        .subcommand(
+            SubCommand::with_name("restore-snapshot")
+                .about("Copies the ledgers of a snapshot into another data directory")
+                .arg(Arg::with_name("snapshot").required(true))
+                .arg(Arg::with_name("target").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes the committed entries of a ledger to an archive")
+                .arg(ledger())
+                .arg(Arg::with_name("archive").required(true))
+                .arg(Arg::with_name("from").long("from").takes_value(true))
+                .arg(Arg::with_name("to").long("to").takes_value(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Adds the entries of an archive to the ledger it was exported from")
+                .arg(Arg::with_name("archive").required(true))
+                .arg(
+                    Arg::with_name("ledger")
+                        .long("ledger")
+                        .takes_value(true)
+                        .help("Imports into this ledger instead"),
+                )
+                .arg(
+                    Arg::with_name("renumber")
+                        .long("renumber")
+                        .help("Gives the entries the next offsets of the ledger"),
+                )
+                .arg(
+                    Arg::with_name("segment-size")
+                        .long("segment-size")
+                        .default_value("1000"),
+                ),
+        )
+        .get_matches();
+
+    let path = PathBuf::from(matches.value_of("path").unwrap());
+    let keyring = match matches.value_of("key-file") {
+        Some(key_file) => Some(Arc::new(Keyring::load(&PathBuf::from(key_file))?)),
+        None => None,
+    };
+    match matches.subcommand() {
+        ("ledgers", _) => ledgers(&path),
+        ("segments", Some(args)) => segments(&path.join(ledger_id(args)), keyring).await,
+        ("dump", Some(args)) => {
+            let (location, id) = (path.join(ledger_id(args)), segment_id(args)?);
+            dump(&location, id, keyring, args.value_of("format").unwrap()).await
+        }
+        ("index", Some(args)) => print_index(&path.join(ledger_id(args)), segment_id(args)?).await,
+        ("verify", Some(args)) => match args.value_of("ledger") {
+            Some(id) => verify(&path.join(id), keyring.clone()),
+            None => {
+                for id in files::list_dirs(&path)? {
+                    verify(&path.join(id), keyring.clone())?;
+                }
+                Ok(())
+            }
+        },
+        ("rebuild-index", Some(args)) => {
+            rebuild_index(&path.join(ledger_id(args)), segment_id(args)?)
+        }
+        ("snapshots", _) => {
+            for name in snapshot::list(&path)? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        ("restore-snapshot", Some(args)) => {
+            let name = args.value_of("snapshot").unwrap();
+            let snapshot = snapshot::dir(&path, name)?;
+            let target = PathBuf::from(args.value_of("target").unwrap());
+            for id in snapshot::restore(&snapshot, &target)? {
+                println!("restored ledger {}", id);
+            }
+            Ok(())
+        }
+        ("export", Some(args)) => export(&path, args, keyring).await,
+        ("import", Some(args)) => import(&path, args, keyring).await,
+        _ => Ok(()),
+    }
+}
+
+fn ledger_id<'a>(args: &'a ArgMatches) -> &'a str {
+    args.value_of("ledger").unwrap()
+}
+
+fn segment_id(args: &ArgMatches) -> Result<u64> {
+    Ok(args.value_of("segment").unwrap().parse()?)
+}
+
+fn open(location: &Path, keyring: Option<Arc<Keyring>>) -> Result<Segments> {
+    if !location.exists() {
+        return Err(format!("no ledger at {:?}", location).into());
+    }
+    let keys = match keyring {
+        Some(keyring) => LedgerKeys::open(location, keyring)?,
+        None => None,
+    };
+    Ok(Segments::open_read_only(
+        location.to_owned(),
+        Codec::None,
+        keys,
+    )?)
+}
+
+fn ledgers(path: &Path) -> Result<()> {
+    for id in files::list_dirs(path)? {
+        let segments = files::list_files_as_u64(&path.join(&id), "index")?;
+        println!("{}\t{} segments", id, segments.len());
+    }
+    Ok(())
+}
+
+async fn segments(location: &Path, keyring: Option<Arc<Keyring>>) -> Result<()> {
+    let mut segments = open(location, keyring)?;
+    println!("id\tbase offset\tnext offset\tlog bytes");
+    for id in segments.ids() {
+        let segment = segments.get_mut(id).unwrap();
+        if let Some(reason) = segment.quarantined() {
+            let (base_offset, size) = (segment.base_offset, segment.size());
+            println!(
+                "{}\t{}\t-\t{}\tquarantined: {}",
+                id, base_offset, size, reason
+            );
+            continue;
+        }
+        let next_offset = segment.next_offset().await?;
+        let (base_offset, size) = (segment.base_offset, segment.size());
+        println!("{}\t{}\t{}\t{}", id, base_offset, next_offset, size);
+    }
+    Ok(())
+}
+
+async fn dump(location: &Path, id: u64, keyring: Option<Arc<Keyring>>, format: &str) -> Result<()> {
+    let mut segments = open(location, keyring)?;
+    segments
+        .get(id)
+        .ok_or_else(|| format!("no segment {}", id))?;
+    for (offset, entry) in segments.entries(id).await? {
+        match format {
+            "utf8" => println!("{}\t{}", offset, String::from_utf8_lossy(&entry)),
+            "json" => println!(
+                "{{\"offset\":{},\"entry\":\"{}\"}}",
+                offset,
+                json_escape(&String::from_utf8_lossy(&entry))
+            ),
+            _ => println!("{}\t{}", offset, hex(&entry)),
+        }
+    }
+    Ok(())
+}
+
+async fn print_index(location: &Path, id: u64) -> Result<()> {
+    let base_offset = read_base_offset(location, id)?;
+    let mut index = Index::open_read_only(&location.to_owned(), id, base_offset).await?;
+    println!("last offset\tlog position");
+    for (offset, position) in index.read_entries().await? {
+        println!("{}\t{}", offset, position);
+    }
+    Ok(())
+}
+
+/// Checks every segment of the ledger, reporting the corrupt ones without stopping.
+fn verify(location: &Path, keyring: Option<Arc<Keyring>>) -> Result<()> {
+    let segments = open(location, keyring)?;
+    for id in segments.ids() {
+        match segments.get(id).unwrap().scrubber(Duration::from_secs(0))() {
+            Ok(bytes) => println!("{:?} segment {}: ok, {} bytes", location, id, bytes),
+            Err(e) => println!("{:?} segment {}: {}", location, id, e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the index of a segment anew from its log, which is cut off at the first
+/// torn, corrupt or out of order batch.
+fn rebuild_index(location: &Path, id: u64) -> Result<()> {
+    let path = location.join(id.to_string());
+    let log = fs::read(path.with_extension("log"))?;
+    let (index, end, next_offset) = index_log(&log, read_base_offset(location, id)?);
+    files::write_atomic(&path.with_extension("index"), &index)?;
+    if end < log.len() {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(path.with_extension("log"))?;
+        file.set_len(end as u64)?;
+        file.sync_all()?;
+        println!("cut off {} bytes of the log", log.len() - end);
+    }
+    let indexed = index.len() / index::ENTRY_SIZE;
+    println!("indexed {} batches, next offset {}", indexed, next_offset);
+    Ok(())
+}
+
+/// The index entries of the whole batches at the start of `log`, as the last
+/// offset of each batch and its position, with where the last batch ends and the
+/// offset after it.
+fn index_log(log: &[u8], base_offset: u64) -> (Vec<u8>, usize, u64) {
+    let (mut index, mut position, mut next_offset) = (Vec::new(), 0, base_offset);
+    while position + batch::HEADER_SIZE <= log.len() {
+        let size = BigEndian::read_u32(&log[position + 8..]) as usize;
+        let end = position + batch::HEADER_SIZE + size;
+        let batch = match log.get(position..end).map(Batch::decode) {
+            Some(Ok(mut batches)) if batches.len() == 1 => batches.remove(0),
+            _ => break,
+        };
+        if batch.count == 0 || batch.base_offset < next_offset {
+            break;
+        }
+        index.extend(batch.last_offset().to_be_bytes().iter());
+        index.extend((position as u64).to_be_bytes().iter());
+        position = end;
+        next_offset = batch.last_offset() + 1;
+    }
+    (index, position, next_offset)
+}
+
+async fn export(path: &Path, args: &ArgMatches<'_>, keyring: Option<Arc<Keyring>>) -> Result<()> {
+    let id = ledger_id(args).to_owned();
+    let config = LedgerConfig::new(1000);
+    let ledger = Ledger::open(path, id.clone(), config, keyring).await?;
+    let mut ledger = ledger.ok_or_else(|| format!("no ledger {}", id))?;
+    let offsets = ledger.offsets().await?;
+    let from = match args.value_of("from") {
+        Some(from) => from.parse::<u64>()?.max(offsets.start),
+        None => offsets.start,
+    };
+    let to = match args.value_of("to") {
+        Some(to) => to.parse::<u64>()?.min(offsets.end),
+        None => offsets.end,
+    };
+    let manifest = Manifest {
+        ledger_id: id,
+        offsets: from..to.max(from),
+    };
+    let file = BufWriter::new(fs::File::create(args.value_of("archive").unwrap())?);
+    let mut archive = ArchiveWriter::new(file, &manifest)?;
+    let exported = ledger
+        .export(manifest.offsets.clone(), &mut archive)
+        .await?;
+    archive.finish()?;
+    println!(
+        "exported {} entries, offsets {:?}",
+        exported, manifest.offsets
+    );
+    Ok(())
+}
+
+/// Imports into an existing ledger, or creates it under the id of the archive. A
+/// new ledger is built apart and moved into the data directory once the whole
+/// archive is in, where a running server picks it up.
+async fn import(path: &Path, args: &ArgMatches<'_>, keyring: Option<Arc<Keyring>>) -> Result<()> {
+    let archive = PathBuf::from(args.value_of("archive").unwrap());
+    let input = || fs::File::open(&archive).map(BufReader::new);
+    let id = match args.value_of("ledger") {
+        Some(id) => id.to_owned(),
+        None => ArchiveReader::open(input()?)?.manifest.ledger_id,
+    };
+    let config = LedgerConfig::new(args.value_of("segment-size").unwrap().parse()?);
+    let renumber = args.is_present("renumber");
+    let offsets = match Ledger::open(path, id.clone(), config.clone(), keyring.clone()).await? {
+        Some(mut ledger) => ledger.import(input()?, renumber).await?,
+        None => {
+            let staging = path.join(IMPORT_STAGING);
+            if staging.join(&id).exists() {
+                files::remove_dir(&staging.join(&id))?;
+            }
+            let mut ledger = Ledger::create(&staging, id.clone(), config, keyring).await?;
+            let imported = ledger.import(input()?, renumber).await;
+            drop(ledger);
+            if imported.is_err() {
+                files::remove_dir(&staging.join(&id))?;
+            }
+            let offsets = imported?;
+            files::rename(&staging.join(&id), &path.join(&id))?;
+            files::sync_dir(path)?;
+            offsets
+        }
+    };
+    println!("imported offsets {:?} into ledger {}", offsets, id);
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_log_up_to_bad_batch() {
+        let batch = |base_offset, count| Batch {
+            base_offset,
+            attributes: 0,
+            count,
+            producer: None,
+            payload: vec![0; 8],
+        };
+        let (first, second) = (batch(10, 2), batch(12, 1));
+        let mut log = first.encode();
+        log.extend(second.encode());
+        let end = log.len();
+        log.extend(batch(11, 1).encode());
+        let mut corrupt = batch(13, 1).encode();
+        corrupt[20] ^= 1;
+
+        let (index, indexed, next_offset) = index_log(&log, 10);
+        assert_eq!((indexed, next_offset), (end, 13));
+        assert_eq!(BigEndian::read_u64(&index[16..]), 12);
+        assert_eq!(BigEndian::read_u64(&index[24..]), first.size() as u64);
+        log.truncate(end);
+        log.extend(corrupt);
+        assert_eq!(index_log(&log, 10).1, end);
+        assert_eq!(index_log(&log[..end - 1], 10).1, first.size());
+    }
+}
@@ -0,0 +1,437 @@
+use crate::files;
+use crate::ledger::LedgerRepository;
+use crate::types::{Error, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The entry of a control batch. A transaction begins in every segment it writes
+/// to, before its first batch there, and ends once per ledger in the segment that is
+/// active when it is settled, so it may end in a later segment than it began.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Marker {
+    Begin,
+    Commit,
+    Abort,
+}
+
+impl Marker {
+    pub fn entry(self) -> Vec<u8> {
+        match self {
+            Marker::Begin => vec![0],
+            Marker::Commit => vec![1],
+            Marker::Abort => vec![2],
+        }
+    }
+
+    pub fn parse(entry: &[u8]) -> Option<Marker> {
+        match entry {
+            [0] => Some(Marker::Begin),
+            [1] => Some(Marker::Commit),
+            [2] => Some(Marker::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// The transaction markers of a segment, or of a run of segments in offset order,
+/// by producer. A transactional batch belongs to the transaction that its producer
+/// ends with the first end marker after it.
+#[derive(Clone, Default)]
+pub struct TransactionIndex {
+    markers: HashMap<u64, Vec<(u64, Marker)>>,
+}
+
+impl TransactionIndex {
+    pub fn add(&mut self, producer_id: u64, marker: Marker, offset: u64) {
+        let markers = self.markers.entry(producer_id).or_default();
+        markers.push((offset, marker));
+    }
+
+    /// Adds the markers of the segment following the ones indexed so far.
+    pub fn extend(&mut self, following: &TransactionIndex) {
+        for (producer_id, markers) in &following.markers {
+            let indexed = self.markers.entry(*producer_id).or_default();
+            indexed.extend(markers.iter().cloned());
+        }
+    }
+
+    /// How the transaction of the producer holding `offset` ended, none while it is open.
+    pub fn outcome(&self, producer_id: u64, offset: u64) -> Option<Marker> {
+        let markers = self.markers.get(&producer_id)?.iter();
+        markers
+            .filter(|(at, marker)| *at > offset && *marker != Marker::Begin)
+            .map(|(_, marker)| *marker)
+            .next()
+    }
+
+    /// The end of the transaction marked at `offset`: the marker there if it ends the
+    /// transaction, or else the first end after it.
+    pub fn end(&self, producer_id: u64, offset: u64) -> Option<Marker> {
+        let markers = self.markers.get(&producer_id)?.iter();
+        markers
+            .filter(|(at, marker)| *at >= offset && *marker != Marker::Begin)
+            .map(|(_, marker)| *marker)
+            .next()
+    }
+
+    /// The first offset of the oldest open transaction, read committed readers stop there.
+    pub fn stable_offset(&self) -> Option<u64> {
+        let begins = self.markers.iter().flat_map(|(producer_id, markers)| {
+            let begins = markers
+                .iter()
+                .filter(|(_, marker)| *marker == Marker::Begin);
+            begins.map(move |(offset, _)| (*producer_id, *offset))
+        });
+        begins
+            .filter(|(producer_id, offset)| self.outcome(*producer_id, *offset).is_none())
+            .map(|(_, offset)| offset)
+            .min()
+    }
+
+    pub fn is_aborted(&self, producer_id: u64, offset: u64) -> bool {
+        self.outcome(producer_id, offset) == Some(Marker::Abort)
+    }
+}
+
+const LOG_LIMIT: u64 = 1 << 20;
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Transaction {
+    participants: BTreeSet<(String, u64)>,
+    decision: Option<Marker>,
+    touched: Instant,
+}
+
+/// Tracks the open transactions, one per producer, and the segments they wrote to.
+/// Once a transaction is committed or aborted the decision stands, a failed commit
+/// or abort only has to be retried to write the remaining markers. With a log,
+/// every step is synced there before the markers that follow from it are written.
+#[derive(Default)]
+pub struct Coordinator {
+    transactions: HashMap<u64, Transaction>,
+    log: Option<TransactionLog>,
+}
+
+impl Coordinator {
+    /// Opens the coordinator log at `path`, along with the transactions it left pending.
+    pub fn open(path: &Path) -> Result<Coordinator> {
+        let text = match files::read(path) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let transactions = replay(&text)?;
+        let mut log = TransactionLog::open(path)?;
+        log.rewrite(&transactions)?;
+        Ok(Coordinator {
+            transactions,
+            log: Some(log),
+        })
+    }
+
+    pub fn begin(&mut self, producer_id: u64) -> Result<()> {
+        if self.transactions.contains_key(&producer_id) {
+            return Err(Error::TransactionInProgress(producer_id));
+        }
+        let transaction = Transaction {
+            participants: BTreeSet::new(),
+            decision: None,
+            touched: Instant::now(),
+        };
+        self.transactions.insert(producer_id, transaction);
+        Ok(())
+    }
+
+    /// Adds a segment to the open transaction of the producer, telling whether it is new to it.
+    pub fn join(&mut self, producer_id: u64, ledger_id: &str, segment_id: u64) -> Result<bool> {
+        match self.transactions.get_mut(&producer_id) {
+            Some(t) if t.decision.is_none() => {
+                t.touched = Instant::now();
+                let participant = (ledger_id.to_owned(), segment_id);
+                if t.participants.contains(&participant) {
+                    return Ok(false);
+                }
+                if let Some(log) = self.log.as_mut() {
+                    log.append(&join(producer_id, &participant))?;
+                }
+                Ok(t.participants.insert(participant))
+            }
+            Some(_) => Err(Error::TransactionInProgress(producer_id)),
+            None => Err(Error::NoTransaction(producer_id)),
+        }
+    }
+
+    /// Settles the transaction with `marker` and returns the segments still missing it.
+    pub fn decide(&mut self, producer_id: u64, marker: Marker) -> Result<Vec<(String, u64)>> {
+        let transaction = self
+            .transactions
+            .get_mut(&producer_id)
+            .ok_or(Error::NoTransaction(producer_id))?;
+        match transaction.decision {
+            Some(decision) if decision != marker => Err(Error::TransactionInProgress(producer_id)),
+            _ if transaction.participants.is_empty() => {
+                self.transactions.remove(&producer_id);
+                Ok(vec![])
+            }
+            decision => {
+                if let (None, Some(log)) = (decision, self.log.as_mut()) {
+                    log.append(&decide(producer_id, marker))?;
+                }
+                transaction.decision = Some(marker);
+                Ok(transaction.participants.iter().cloned().collect())
+            }
+        }
+    }
+
+    pub fn written(&mut self, producer_id: u64, ledger_id: &str, segment_id: u64) {
+        if let Some(transaction) = self.transactions.get_mut(&producer_id) {
+            let participant = (ledger_id.to_owned(), segment_id);
+            transaction.participants.remove(&participant);
+            if transaction.participants.is_empty() {
+                self.transactions.remove(&producer_id);
+                if let Err(e) = self.done(producer_id) {
+                    println!(
+                        "logging the end of transaction {} failed: {}",
+                        producer_id, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Logs that the transaction ended, starting the log over once it holds nothing
+    /// pending or has grown past `LOG_LIMIT`.
+    fn done(&mut self, producer_id: u64) -> Result<()> {
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        if self.transactions.is_empty() {
+            return log.clear();
+        }
+        log.append(&format!("done {}\n", producer_id))?;
+        if log.size > LOG_LIMIT {
+            log.rewrite(&self.transactions)?;
+        }
+        Ok(())
+    }
+
+    /// The transactions left without a step for `timeout`, with the marker to end
+    /// them with: their decision, or an abort for the undecided ones.
+    pub fn expired(&self, timeout: Duration) -> Vec<(u64, Marker)> {
+        let transactions = self.transactions.iter();
+        transactions
+            .filter(|(_, t)| t.touched.elapsed() >= timeout)
+            .map(|(id, t)| (*id, t.decision.unwrap_or(Marker::Abort)))
+            .collect()
+    }
+
+    /// Every transaction, with the marker to end it with as in `expired`.
+    pub fn pending(&self) -> Vec<(u64, Marker)> {
+        self.expired(Duration::from_secs(0))
+    }
+}
+
+/// The coordinator log, a line per step: `join <producer id> <segment id> <ledger id>`
+/// before the first marker of a transaction in a segment, `commit <producer id>` or
+/// `abort <producer id>` before its end markers, and `done <producer id>` once
+/// they are all written. A line cut short by a crash is the last one and is dropped.
+struct TransactionLog {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+}
+
+impl TransactionLog {
+    fn open(path: &Path) -> Result<TransactionLog> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let size = file.metadata()?.len();
+        Ok(TransactionLog {
+            path: path.to_owned(),
+            file,
+            size,
+        })
+    }
+
+    fn append(&mut self, line: &str) -> Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Replaces the log with the steps of `transactions` alone.
+    fn rewrite(&mut self, transactions: &HashMap<u64, Transaction>) -> Result<()> {
+        let mut lines = String::new();
+        for (id, transaction) in transactions {
+            for participant in &transaction.participants {
+                lines.push_str(&join(*id, participant));
+            }
+            if let Some(marker) = transaction.decision {
+                lines.push_str(&decide(*id, marker));
+            }
+        }
+        files::write_atomic(&self.path, lines.as_bytes())?;
+        *self = TransactionLog::open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn join(producer_id: u64, (ledger_id, segment_id): &(String, u64)) -> String {
+    format!("join {} {} {}\n", producer_id, segment_id, ledger_id)
+}
+
+fn decide(producer_id: u64, marker: Marker) -> String {
+    match marker {
+        Marker::Commit => format!("commit {}\n", producer_id),
+        _ => format!("abort {}\n", producer_id),
+    }
+}
+
+/// The transactions the log leaves pending.
+fn replay(text: &str) -> Result<HashMap<u64, Transaction>> {
+    let mut transactions: HashMap<u64, Transaction> = HashMap::new();
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    lines.pop();
+    for (i, line) in lines.into_iter().enumerate() {
+        let corrupt = || Error::CorruptTransactionLog(i + 1);
+        let fields: Vec<&str> = line.splitn(4, ' ').collect();
+        let producer_id = fields.get(1).and_then(|id| id.parse::<u64>().ok());
+        let producer_id = producer_id.ok_or_else(corrupt)?;
+        let transaction = transactions.entry(producer_id).or_insert(Transaction {
+            participants: BTreeSet::new(),
+            decision: None,
+            touched: Instant::now(),
+        });
+        match fields.as_slice() {
+            ["join", _, segment_id, ledger_id] => {
+                let segment_id = segment_id.parse().map_err(|_| corrupt())?;
+                let participant = ((*ledger_id).to_owned(), segment_id);
+                transaction.participants.insert(participant);
+            }
+            ["commit", _] => transaction.decision = Some(Marker::Commit),
+            ["abort", _] => transaction.decision = Some(Marker::Abort),
+            ["done", _] => {
+                transactions.remove(&producer_id);
+            }
+            _ => return Err(corrupt()),
+        }
+    }
+    Ok(transactions)
+}
+
+/// Aborts the transactions their producers abandoned for `TRANSACTION_TIMEOUT`,
+/// and finishes ending the ones a failed commit or abort left behind.
+pub async fn run(repository: LedgerRepository) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        match repository.expire_transactions(TRANSACTION_TIMEOUT).await {
+            0 => {}
+            ended => println!("ended {} expired transactions", ended),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_transactions() {
+        let mut index = TransactionIndex::default();
+        index.add(1, Marker::Begin, 10);
+        index.add(2, Marker::Begin, 12);
+        index.add(1, Marker::Abort, 14);
+
+        assert_eq!(index.stable_offset(), Some(12));
+        assert!(index.is_aborted(1, 11));
+        assert!(!index.is_aborted(2, 13));
+        let mut following = TransactionIndex::default();
+        following.add(1, Marker::Begin, 20);
+        following.add(2, Marker::Commit, 22);
+        index.extend(&following);
+        assert_eq!(index.stable_offset(), Some(20));
+        assert_eq!(index.outcome(2, 13), Some(Marker::Commit));
+        assert_eq!(index.outcome(1, 21), None);
+    }
+
+    #[test]
+    fn coordinate_transaction() {
+        let mut coordinator = Coordinator::default();
+        coordinator.begin(1).unwrap();
+        assert!(coordinator.begin(1).is_err());
+        assert!(coordinator.join(1, "a", 0).unwrap());
+        assert!(!coordinator.join(1, "a", 0).unwrap());
+        coordinator.join(1, "b", 0).unwrap();
+
+        let participants = coordinator.decide(1, Marker::Commit).unwrap();
+        assert_eq!(participants.len(), 2);
+        assert!(coordinator.join(1, "c", 0).is_err());
+        assert!(coordinator.decide(1, Marker::Abort).is_err());
+        coordinator.written(1, "a", 0);
+        assert_eq!(coordinator.decide(1, Marker::Commit).unwrap().len(), 1);
+        coordinator.written(1, "b", 0);
+        assert!(coordinator.decide(1, Marker::Commit).is_err());
+        coordinator.begin(1).unwrap();
+        assert!(coordinator.decide(1, Marker::Abort).unwrap().is_empty());
+        coordinator.begin(1).unwrap();
+    }
+
+    #[test]
+    fn replay_coordinator_log() {
+        let path = crate::test_util::create_a_test_directory().join("transactions");
+        let mut coordinator = Coordinator::open(&path).unwrap();
+        for producer_id in 1..4 {
+            coordinator.begin(producer_id).unwrap();
+            coordinator.join(producer_id, "a", 0).unwrap();
+        }
+        coordinator.join(1, "b", 2).unwrap();
+        coordinator.decide(1, Marker::Commit).unwrap();
+        coordinator.decide(3, Marker::Abort).unwrap();
+        coordinator.written(3, "a", 0);
+        coordinator.written(1, "a", 0);
+        drop(coordinator);
+        let mut log = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"done 2").unwrap();
+
+        let mut coordinator = Coordinator::open(&path).unwrap();
+        let mut pending = coordinator.pending();
+        pending.sort_by_key(|(id, _)| *id);
+        assert_eq!(pending, vec![(1, Marker::Commit), (2, Marker::Abort)]);
+        assert_eq!(coordinator.decide(1, Marker::Commit).unwrap().len(), 2);
+        coordinator.written(1, "a", 0);
+        coordinator.written(1, "b", 2);
+        coordinator.decide(2, Marker::Abort).unwrap();
+        coordinator.written(2, "a", 0);
+        assert_eq!(files::size(&path).unwrap(), 0);
+        assert!(Coordinator::open(&path).unwrap().pending().is_empty());
+    }
+
+    #[test]
+    fn expire_idle_transactions() {
+        let mut coordinator = Coordinator::default();
+        coordinator.begin(1).unwrap();
+
+        assert!(coordinator.expired(Duration::from_secs(60)).is_empty());
+        assert_eq!(
+            coordinator.expired(Duration::from_secs(0)),
+            vec![(1, Marker::Abort)]
+        );
+    }
+}
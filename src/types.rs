@@ -7,17 +7,108 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     IOError(io::Error),
     SegmentFull(u64),
+    InvalidKeyFile,
+    UnknownMasterKey(u32),
+    EncryptionFailed,
+    KeyringMissing,
+    LedgerNotEncrypted(String),
+    CorruptBatch(u64),
+    EntryTooLarge(usize, u32),
+    BatchTooLarge(usize, u32),
+    LedgerNotFound(String),
+    OutOfOrderSequence(u64, u64),
+    DuplicateSequence(u64, u64),
+    UnknownProducer(u64),
+    NoTransaction(u64),
+    TransactionInProgress(u64),
+    CorruptTransactionLog(usize),
+    SegmentSealed(u64),
+    CorruptSegmentMetadata(u64),
+    OffsetOutOfRange {
+        requested: u64,
+        first: u64,
+        next: u64,
+    },
+    NoDataDirectory,
+    DiskFull(String),
+    CorruptSegment(u64, String),
+    SegmentQuarantined(u64),
+    CorruptArchive(String),
+    InvalidSnapshot(String),
+    SegmentOffloaded(u64),
+    InvalidConfig(String),
+    ClusterUnavailable(String),
 }
 
 impl Error {
     pub fn is_segment_full(&self) -> bool {
+        matches!(self, Error::SegmentFull(_))
+    }
+
+    pub fn is_segment_sealed(&self) -> bool {
+        matches!(self, Error::SegmentSealed(_))
+    }
+
+    pub fn is_segment_offloaded(&self) -> bool {
+        matches!(self, Error::SegmentOffloaded(_))
+    }
+
+    pub fn is_invalid_config(&self) -> bool {
+        matches!(self, Error::InvalidConfig(_))
+    }
+
+    pub fn is_invalid_key_file(&self) -> bool {
+        matches!(self, Error::InvalidKeyFile)
+    }
+
+    pub fn is_keyring_missing(&self) -> bool {
+        matches!(self, Error::KeyringMissing)
+    }
+
+    pub fn is_corrupt_batch(&self) -> bool {
+        matches!(self, Error::CorruptBatch(_))
+    }
+
+    pub fn is_corrupt_segment(&self) -> bool {
+        matches!(self, Error::CorruptSegment(_, _))
+    }
+
+    pub fn is_corrupt_archive(&self) -> bool {
+        matches!(self, Error::CorruptArchive(_))
+    }
+
+    pub fn is_entry_too_large(&self) -> bool {
+        matches!(self, Error::EntryTooLarge(_, _))
+    }
+
+    pub fn is_batch_too_large(&self) -> bool {
+        matches!(self, Error::BatchTooLarge(_, _))
+    }
+
+    /// Whether the error is from a disk that is full, or past its high water mark.
+    pub fn is_disk_full(&self) -> bool {
         match self {
-            Error::SegmentFull(_) => true,
+            Error::DiskFull(_) => true,
+            Error::IOError(e) => e.raw_os_error() == Some(libc::ENOSPC),
             _ => false,
         }
     }
+
+    pub fn is_duplicate_sequence(&self) -> bool {
+        matches!(self, Error::DuplicateSequence(_, _))
+    }
+
+    pub fn is_unknown_producer(&self) -> bool {
+        matches!(self, Error::UnknownProducer(_))
+    }
+
+    pub fn is_offset_out_of_range(&self) -> bool {
+        matches!(self, Error::OffsetOutOfRange { .. })
+    }
 }
 
+impl std::error::Error for Error {}
+
 impl std::convert::From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::IOError(e)
@@ -29,6 +120,61 @@ impl fmt::Display for Error {
         match self {
             Error::IOError(e) => write!(f, "io error {}", e),
             Error::SegmentFull(id) => write!(f, "segment {} is full", id),
+            Error::InvalidKeyFile => write!(f, "invalid key file"),
+            Error::UnknownMasterKey(id) => write!(f, "unknown master key {}", id),
+            Error::EncryptionFailed => write!(f, "encryption failed"),
+            Error::KeyringMissing => write!(f, "ledger is encrypted but no key file is loaded"),
+            Error::LedgerNotEncrypted(id) => write!(f, "ledger {} is not encrypted", id),
+            Error::CorruptBatch(offset) => write!(f, "batch at offset {} is corrupt", offset),
+            Error::EntryTooLarge(size, max) => {
+                write!(f, "entry of {} bytes exceeds the limit of {}", size, max)
+            }
+            Error::BatchTooLarge(size, max) => {
+                write!(f, "batch of {} bytes exceeds the limit of {}", size, max)
+            }
+            Error::LedgerNotFound(id) => write!(f, "ledger {} not found", id),
+            Error::OutOfOrderSequence(id, expected) => {
+                write!(f, "producer {} expected sequence {}", id, expected)
+            }
+            Error::DuplicateSequence(id, sequence) => write!(
+                f,
+                "sequence {} of producer {} is already written",
+                sequence, id
+            ),
+            Error::UnknownProducer(id) => write!(f, "producer {} is not registered", id),
+            Error::NoTransaction(id) => write!(f, "producer {} has no open transaction", id),
+            Error::TransactionInProgress(id) => {
+                write!(f, "producer {} already has a transaction in progress", id)
+            }
+            Error::CorruptTransactionLog(line) => {
+                write!(f, "line {} of the transaction log is corrupt", line)
+            }
+            Error::SegmentSealed(id) => write!(f, "segment {} is sealed", id),
+            Error::CorruptSegmentMetadata(id) => {
+                write!(f, "metadata of segment {} is corrupt", id)
+            }
+            Error::OffsetOutOfRange {
+                requested,
+                first,
+                next,
+            } => write!(
+                f,
+                "offset {} is out of range, valid offsets are {} to {}",
+                requested, first, next
+            ),
+            Error::NoDataDirectory => write!(f, "no data directory is available"),
+            Error::DiskFull(path) => write!(f, "disk of data directory {} is full", path),
+            Error::CorruptSegment(id, reason) => write!(f, "segment {} is corrupt: {}", id, reason),
+            Error::CorruptArchive(reason) => write!(f, "archive is corrupt: {}", reason),
+            Error::InvalidSnapshot(reason) => write!(f, "invalid snapshot: {}", reason),
+            Error::SegmentOffloaded(id) => {
+                write!(f, "segment {} is offloaded and no cold store is set", id)
+            }
+            Error::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            Error::ClusterUnavailable(reason) => write!(f, "cannot reach etcd: {}", reason),
+            Error::SegmentQuarantined(id) => {
+                write!(f, "segment {} is quarantined after failing a scrub", id)
+            }
         }
     }
 }